@@ -0,0 +1,676 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use actix_web::web;
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use chrono::Utc;
+use lazy_static::lazy_static;
+use log::{debug, error, info, warn};
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::config::{AcmeChallenge, AcmeSettings, SharedSettings};
+use crate::error::AppError;
+use crate::tls;
+
+// TLS-ALPN-01 验证使用的 ALPN 协议（RFC 8737）
+pub const ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+
+// 检查证书是否需要续期的间隔，签发失败时也在下次检查时重试
+const CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+// 轮询授权和订单状态的间隔和次数
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: u32 = 60;
+
+// 状态目录中的文件
+const ACCOUNT_KEY_FILE: &str = "account.key";
+const CERTIFICATE_FILE: &str = "certificate.pem";
+const CERTIFICATE_KEY_FILE: &str = "certificate.key";
+
+// DER 编码中使用的 OID
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const OID_EXTENSION_REQUEST: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+const OID_ACME_IDENTIFIER: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f];
+
+lazy_static! {
+    // HTTP-01 验证：token -> key authorization
+    static ref HTTP_CHALLENGES: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+    // TLS-ALPN-01 验证：域名 -> 验证证书
+    static ref ALPN_CHALLENGES: RwLock<HashMap<String, Arc<CertifiedKey>>> = RwLock::new(HashMap::new());
+}
+
+// ACME 签发的证书和私钥路径
+pub fn certificate_paths(acme: &AcmeSettings) -> (String, String) {
+    let state_dir = Path::new(&acme.state_dir);
+    (
+        state_dir.join(CERTIFICATE_FILE).to_string_lossy().into_owned(),
+        state_dir.join(CERTIFICATE_KEY_FILE).to_string_lossy().into_owned(),
+    )
+}
+
+// HTTP-01 验证的响应内容
+pub fn http_challenge(token: &str) -> Option<String> {
+    HTTP_CHALLENGES.read().unwrap().get(token).cloned()
+}
+
+// TLS-ALPN-01 验证使用的证书
+pub fn alpn_certificate(server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+    let server_name = server_name?.trim_end_matches('.').to_ascii_lowercase();
+    ALPN_CHALLENGES.read().unwrap().get(&server_name).cloned()
+}
+
+// 启动时和之后每小时检查一次证书，不存在、域名变化或即将过期时签发新证书
+pub fn spawn(shared: web::Data<SharedSettings>) {
+    if !shared.load().acme.enabled {
+        return;
+    }
+
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(CHECK_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            let settings = shared.load();
+            if !settings.acme.enabled {
+                continue;
+            }
+
+            let Some(reason) = renewal_reason(&settings.acme) else {
+                debug!("ACME 证书无需续期");
+                continue;
+            };
+            info!("申请 ACME 证书（{}）: {}", reason, settings.acme.domains.join(", "));
+
+            match issue(&settings.acme).await {
+                Ok(()) => {
+                    info!("ACME 证书签发成功");
+                    tls::reload(&settings);
+                }
+                Err(e) => error!("ACME 证书签发失败，{} 秒后重试: {}", CHECK_INTERVAL.as_secs(), e),
+            }
+        }
+    });
+}
+
+// 需要签发证书的原因，不需要时返回 None
+fn renewal_reason(acme: &AcmeSettings) -> Option<String> {
+    let (cert_path, _) = certificate_paths(acme);
+    let Some(cert) = File::open(&cert_path).ok()
+        .and_then(|file| rustls_pemfile::certs(&mut BufReader::new(file)).ok())
+        .and_then(|certs| certs.into_iter().next()) else {
+        return Some("证书不存在".to_string());
+    };
+
+    let wanted: BTreeSet<String> = acme.domains.iter().map(|domain| domain.to_ascii_lowercase()).collect();
+    let current: BTreeSet<String> = tls::dns_names(&cert).iter().map(|name| name.to_ascii_lowercase()).collect();
+    if wanted != current {
+        return Some("域名已修改".to_string());
+    }
+
+    match tls::not_after(&cert) {
+        Some(expiry) => {
+            let remaining = expiry.signed_duration_since(Utc::now()).num_days();
+            (remaining < acme.renew_before_days).then(|| format!("剩余 {remaining} 天"))
+        }
+        None => Some("无法解析证书有效期".to_string()),
+    }
+}
+
+// 完整的签发流程：注册账户、创建订单、完成验证、提交 CSR 并下载证书
+async fn issue(acme: &AcmeSettings) -> Result<(), AppError> {
+    std::fs::create_dir_all(&acme.state_dir)
+        .map_err(|e| AppError::Acme(format!("无法创建状态目录 {}: {e}", acme.state_dir)))?;
+
+    let mut client = AcmeClient::new(acme).await?;
+    client.register(&acme.contact).await?;
+
+    let identifiers: Vec<Value> = acme.domains.iter()
+        .map(|domain| json!({"type": "dns", "value": domain}))
+        .collect();
+    let (order, order_url) = client.post(&client.directory.new_order.clone(), Some(&json!({"identifiers": identifiers}))).await?;
+    let order_url = order_url.ok_or_else(|| AppError::Acme("订单响应缺少 Location".to_string()))?;
+
+    let authorizations: Vec<String> = order["authorizations"].as_array()
+        .map(|urls| urls.iter().filter_map(|url| url.as_str().map(String::from)).collect())
+        .unwrap_or_default();
+    for authorization in &authorizations {
+        client.authorize(authorization, &acme.challenge).await?;
+    }
+
+    // 证书私钥每次签发都重新生成
+    let rng = SystemRandom::new();
+    let certificate_key = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+        .map_err(|_| AppError::Acme("无法生成证书私钥".to_string()))?;
+    let csr = certificate_request(&acme.domains, certificate_key.as_ref())?;
+
+    let finalize = order["finalize"].as_str()
+        .ok_or_else(|| AppError::Acme("订单缺少 finalize 地址".to_string()))?
+        .to_string();
+    client.post(&finalize, Some(&json!({"csr": URL_SAFE_NO_PAD.encode(csr)}))).await?;
+
+    let order = client.poll(&order_url, &["pending", "ready", "processing"]).await?;
+    if order["status"] != "valid" {
+        return Err(AppError::Acme(format!("订单状态 {}: {}", order["status"], order["error"])));
+    }
+    let certificate_url = order["certificate"].as_str()
+        .ok_or_else(|| AppError::Acme("订单缺少证书地址".to_string()))?
+        .to_string();
+    let chain = client.download(&certificate_url).await?;
+
+    // 先写私钥再写证书，证书文件的修改会触发重新加载
+    let (cert_path, key_path) = certificate_paths(acme);
+    write_private(&key_path, pem("PRIVATE KEY", certificate_key.as_ref()).as_bytes())?;
+    write_private(&cert_path, chain.as_bytes())?;
+    info!("ACME 证书已保存: {}", cert_path);
+    Ok(())
+}
+
+// ACME 目录中使用的地址
+#[derive(Debug)]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+// ACME 客户端，所有请求都使用账户密钥签名（JWS，ES256）
+struct AcmeClient {
+    http: reqwest::Client,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    rng: SystemRandom,
+    account_url: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    async fn new(acme: &AcmeSettings) -> Result<Self, AppError> {
+        let mut builder = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(Duration::from_secs(60));
+        if let Some(ca_bundle) = &acme.ca_bundle {
+            let pem = std::fs::read(ca_bundle)
+                .map_err(|e| AppError::Acme(format!("无法读取 CA 证书 {ca_bundle}: {e}")))?;
+            for certificate in reqwest::Certificate::from_pem_bundle(&pem)
+                .map_err(|e| AppError::Acme(format!("无效的 CA 证书 {ca_bundle}: {e}")))? {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        let http = builder.build()
+            .map_err(|e| AppError::Acme(format!("无法创建 HTTP 客户端: {e}")))?;
+
+        let directory: Value = http.get(&acme.directory_url).send().await?
+            .error_for_status()?
+            .json().await?;
+        let url = |name: &str| directory[name].as_str()
+            .map(String::from)
+            .ok_or_else(|| AppError::Acme(format!("ACME 目录缺少 {name}")));
+        let directory = Directory {
+            new_nonce: url("newNonce")?,
+            new_account: url("newAccount")?,
+            new_order: url("newOrder")?,
+        };
+        debug!("ACME 目录: {:?}", directory);
+
+        let rng = SystemRandom::new();
+        let key = account_key(acme, &rng)?;
+        Ok(AcmeClient { http, directory, key, rng, account_url: None, nonce: None })
+    }
+
+    // 账户已存在时 ACME 服务器返回现有账户，因此每次签发都可以直接注册
+    async fn register(&mut self, contact: &[String]) -> Result<(), AppError> {
+        let payload = json!({"termsOfServiceAgreed": true, "contact": contact});
+        let (_, account_url) = self.post(&self.directory.new_account.clone(), Some(&payload)).await?;
+        let account_url = account_url.ok_or_else(|| AppError::Acme("账户响应缺少 Location".to_string()))?;
+        debug!("ACME 账户: {}", account_url);
+        self.account_url = Some(account_url);
+        Ok(())
+    }
+
+    // 完成一个授权的验证
+    async fn authorize(&mut self, authorization_url: &str, challenge_type: &AcmeChallenge) -> Result<(), AppError> {
+        let (authorization, _) = self.post(authorization_url, None).await?;
+        let domain = authorization["identifier"]["value"].as_str().unwrap_or_default().to_string();
+        if authorization["status"] == "valid" {
+            debug!("域名 {} 的授权仍然有效", domain);
+            return Ok(());
+        }
+
+        let type_name = match challenge_type {
+            AcmeChallenge::Http01 => "http-01",
+            AcmeChallenge::TlsAlpn01 => "tls-alpn-01",
+        };
+        let challenge = authorization["challenges"].as_array()
+            .and_then(|challenges| challenges.iter().find(|challenge| challenge["type"] == type_name))
+            .ok_or_else(|| AppError::Acme(format!("域名 {domain} 不支持 {type_name} 验证")))?;
+        let token = challenge["token"].as_str().unwrap_or_default().to_string();
+        let challenge_url = challenge["url"].as_str().unwrap_or_default().to_string();
+        let key_authorization = format!("{token}.{}", self.thumbprint());
+
+        match challenge_type {
+            AcmeChallenge::Http01 => {
+                HTTP_CHALLENGES.write().unwrap().insert(token.clone(), key_authorization);
+            }
+            AcmeChallenge::TlsAlpn01 => {
+                let certificate = alpn_challenge_certificate(&domain, &key_authorization)?;
+                ALPN_CHALLENGES.write().unwrap().insert(domain.to_ascii_lowercase(), certificate);
+            }
+        }
+        info!("等待 ACME 服务器验证域名 {} ({})", domain, type_name);
+
+        let result = async {
+            self.post(&challenge_url, Some(&json!({}))).await?;
+            self.poll(authorization_url, &["pending"]).await
+        }.await;
+
+        HTTP_CHALLENGES.write().unwrap().remove(&token);
+        ALPN_CHALLENGES.write().unwrap().remove(&domain.to_ascii_lowercase());
+
+        let authorization = result?;
+        if authorization["status"] != "valid" {
+            let error = authorization["challenges"].as_array()
+                .and_then(|challenges| challenges.iter().find(|challenge| challenge["type"] == type_name))
+                .map(|challenge| challenge["error"].to_string())
+                .unwrap_or_default();
+            return Err(AppError::Acme(format!("域名 {domain} 验证失败 ({}): {error}", authorization["status"])));
+        }
+        info!("域名 {} 验证成功", domain);
+        Ok(())
+    }
+
+    // 轮询资源直到状态不再是 pending_states 之一
+    async fn poll(&mut self, url: &str, pending_states: &[&str]) -> Result<Value, AppError> {
+        for _ in 0..POLL_ATTEMPTS {
+            let (resource, _) = self.post(url, None).await?;
+            let status = resource["status"].as_str().unwrap_or_default();
+            if !pending_states.contains(&status) {
+                return Ok(resource);
+            }
+            actix_web::rt::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(AppError::Acme(format!("等待 {url} 超时")))
+    }
+
+    // 下载 PEM 格式的证书链
+    async fn download(&mut self, url: &str) -> Result<String, AppError> {
+        let response = self.send(url, None, "application/pem-certificate-chain").await?;
+        Ok(response.text().await?)
+    }
+
+    // 发送签名请求，返回响应 JSON 和 Location 头；payload 为 None 时是 POST-as-GET
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<(Value, Option<String>), AppError> {
+        let response = self.send(url, payload, "application/json").await?;
+        let location = response.headers().get("Location")
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let body = response.bytes().await?;
+        let value = if body.is_empty() { Value::Null } else {
+            serde_json::from_slice(&body).map_err(|e| AppError::Acme(format!("无效的 ACME 响应 {url}: {e}")))?
+        };
+        Ok((value, location))
+    }
+
+    // nonce 失效（badNonce）时使用响应中的新 nonce 重试
+    async fn send(&mut self, url: &str, payload: Option<&Value>, accept: &str) -> Result<reqwest::Response, AppError> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let nonce = self.nonce().await?;
+            let body = self.sign(url, &nonce, payload)?;
+            let response = self.http.post(url)
+                .header("Content-Type", "application/jose+json")
+                .header("Accept", accept)
+                .body(body.to_string())
+                .send().await?;
+            self.nonce = response.headers().get("Replay-Nonce")
+                .and_then(|value| value.to_str().ok())
+                .map(String::from);
+
+            if response.status().is_success() {
+                return Ok(response);
+            }
+
+            let status = response.status();
+            let problem: Value = response.json().await.unwrap_or(Value::Null);
+            if problem["type"] == "urn:ietf:params:acme:error:badNonce" && attempt < 3 {
+                warn!("ACME nonce 已失效，重试请求 {}", url);
+                continue;
+            }
+            return Err(AppError::Acme(format!("{url} 返回 {}: {} {}",
+                status.as_u16(),
+                problem["type"].as_str().unwrap_or_default(),
+                problem["detail"].as_str().unwrap_or_default())));
+        }
+    }
+
+    async fn nonce(&mut self) -> Result<String, AppError> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = self.http.head(&self.directory.new_nonce).send().await?;
+        response.headers().get("Replay-Nonce")
+            .and_then(|value| value.to_str().ok())
+            .map(String::from)
+            .ok_or_else(|| AppError::Acme("newNonce 响应缺少 Replay-Nonce".to_string()))
+    }
+
+    // JWS 请求体；注册账户前使用 jwk，之后使用账户地址 kid
+    fn sign(&self, url: &str, nonce: &str, payload: Option<&Value>) -> Result<Value, AppError> {
+        let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+        match &self.account_url {
+            Some(account_url) => protected["kid"] = json!(account_url),
+            None => protected["jwk"] = self.jwk(),
+        }
+        let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+        let payload = payload.map(|payload| URL_SAFE_NO_PAD.encode(payload.to_string())).unwrap_or_default();
+
+        let signature = self.key.sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+            .map_err(|_| AppError::Acme("JWS 签名失败".to_string()))?;
+        Ok(json!({
+            "protected": protected,
+            "payload": payload,
+            "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }))
+    }
+
+    // 账户公钥的 JWK，公钥格式为 0x04 || x || y
+    fn jwk_coordinates(&self) -> (String, String) {
+        let public_key = self.key.public_key().as_ref();
+        (URL_SAFE_NO_PAD.encode(&public_key[1..33]), URL_SAFE_NO_PAD.encode(&public_key[33..65]))
+    }
+
+    fn jwk(&self) -> Value {
+        let (x, y) = self.jwk_coordinates();
+        json!({"crv": "P-256", "kty": "EC", "x": x, "y": y})
+    }
+
+    // JWK 指纹（RFC 7638），成员按字典序排列且不含空白
+    fn thumbprint(&self) -> String {
+        let (x, y) = self.jwk_coordinates();
+        let jwk = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+        URL_SAFE_NO_PAD.encode(Sha256::digest(jwk.as_bytes()))
+    }
+}
+
+// 读取账户密钥，不存在时生成新的 P-256 密钥
+fn account_key(acme: &AcmeSettings, rng: &SystemRandom) -> Result<EcdsaKeyPair, AppError> {
+    let path = Path::new(&acme.state_dir).join(ACCOUNT_KEY_FILE);
+    let pkcs8 = if path.exists() {
+        let file = File::open(&path)?;
+        rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(file))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| AppError::Acme(format!("无法读取账户密钥 {}", path.display())))?
+    } else {
+        info!("生成 ACME 账户密钥: {}", path.display());
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, rng)
+            .map_err(|_| AppError::Acme("无法生成账户密钥".to_string()))?;
+        write_private(&path.to_string_lossy(), pem("PRIVATE KEY", pkcs8.as_ref()).as_bytes())?;
+        pkcs8.as_ref().to_vec()
+    };
+
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, rng)
+        .map_err(|_| AppError::Acme(format!("不支持的账户密钥 {}，需要 P-256 PKCS8 私钥", path.display())))
+}
+
+// 证书签名请求（PKCS#10），域名放在 subjectAltName 扩展中
+fn certificate_request(domains: &[String], pkcs8: &[u8]) -> Result<Vec<u8>, AppError> {
+    let rng = SystemRandom::new();
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8, &rng)
+        .map_err(|_| AppError::Acme("无效的证书私钥".to_string()))?;
+
+    let extension_request = sequence(&[
+        der(0x06, OID_EXTENSION_REQUEST),
+        der(0x31, &sequence(&[subject_alt_name(domains)])),
+    ]);
+    let info = sequence(&[
+        der(0x02, &[0]),
+        name(&domains[0]),
+        public_key_info(key.public_key().as_ref()),
+        der(0xa0, &extension_request),
+    ]);
+    let signature = key.sign(&rng, &info)
+        .map_err(|_| AppError::Acme("CSR 签名失败".to_string()))?;
+
+    Ok(sequence(&[info, sequence(&[der(0x06, OID_ECDSA_WITH_SHA256)]), bit_string(signature.as_ref())]))
+}
+
+// TLS-ALPN-01 验证使用的自签名证书，acmeIdentifier 扩展包含 key authorization 的 SHA-256
+fn alpn_challenge_certificate(domain: &str, key_authorization: &str) -> Result<Arc<CertifiedKey>, AppError> {
+    let rng = SystemRandom::new();
+    let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+        .map_err(|_| AppError::Acme("无法生成验证证书私钥".to_string()))?;
+    let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+        .map_err(|_| AppError::Acme("无法生成验证证书私钥".to_string()))?;
+
+    // 序列号为正整数，首字节固定为 0x01
+    let mut serial = [0u8; 16];
+    rng.fill(&mut serial[1..]).map_err(|_| AppError::Acme("无法生成序列号".to_string()))?;
+    serial[0] = 0x01;
+
+    let now = Utc::now();
+    let validity = sequence(&[
+        utc_time(now - chrono::Duration::days(1)),
+        utc_time(now + chrono::Duration::days(7)),
+    ]);
+    let acme_identifier = sequence(&[
+        der(0x06, OID_ACME_IDENTIFIER),
+        der(0x01, &[0xff]),
+        der(0x04, &der(0x04, &Sha256::digest(key_authorization.as_bytes()))),
+    ]);
+    let tbs = sequence(&[
+        der(0xa0, &der(0x02, &[2])),
+        der(0x02, &serial),
+        sequence(&[der(0x06, OID_ECDSA_WITH_SHA256)]),
+        name(domain),
+        validity,
+        name(domain),
+        public_key_info(key.public_key().as_ref()),
+        der(0xa3, &sequence(&[subject_alt_name(&[domain.to_string()]), acme_identifier])),
+    ]);
+    let signature = key.sign(&rng, &tbs)
+        .map_err(|_| AppError::Acme("验证证书签名失败".to_string()))?;
+    let certificate = sequence(&[tbs, sequence(&[der(0x06, OID_ECDSA_WITH_SHA256)]), bit_string(signature.as_ref())]);
+
+    let signing_key = sign::any_supported_type(&PrivateKey(pkcs8.as_ref().to_vec()))
+        .map_err(|_| AppError::Acme("不支持的验证证书私钥".to_string()))?;
+    Ok(Arc::new(CertifiedKey::new(vec![Certificate(certificate)], signing_key)))
+}
+
+// 编码一个 DER 元素
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![tag];
+    let length = content.len();
+    if length < 0x80 {
+        encoded.push(length as u8);
+    } else {
+        let bytes: Vec<u8> = length.to_be_bytes().into_iter().skip_while(|byte| *byte == 0).collect();
+        encoded.push(0x80 | bytes.len() as u8);
+        encoded.extend(bytes);
+    }
+    encoded.extend_from_slice(content);
+    encoded
+}
+
+fn sequence(elements: &[Vec<u8>]) -> Vec<u8> {
+    der(0x30, &elements.concat())
+}
+
+fn bit_string(content: &[u8]) -> Vec<u8> {
+    der(0x03, &[&[0u8][..], content].concat())
+}
+
+// 只包含 CN 的名称
+fn name(common_name: &str) -> Vec<u8> {
+    sequence(&[der(0x31, &sequence(&[der(0x06, OID_COMMON_NAME), der(0x0c, common_name.as_bytes())]))])
+}
+
+// P-256 公钥的 SubjectPublicKeyInfo
+fn public_key_info(public_key: &[u8]) -> Vec<u8> {
+    sequence(&[
+        sequence(&[der(0x06, OID_EC_PUBLIC_KEY), der(0x06, OID_PRIME256V1)]),
+        bit_string(public_key),
+    ])
+}
+
+// subjectAltName 扩展，每个域名是一个 dNSName
+fn subject_alt_name(domains: &[String]) -> Vec<u8> {
+    let names: Vec<Vec<u8>> = domains.iter().map(|domain| der(0x82, domain.as_bytes())).collect();
+    sequence(&[der(0x06, OID_SUBJECT_ALT_NAME), der(0x04, &sequence(&names))])
+}
+
+fn utc_time(time: chrono::DateTime<Utc>) -> Vec<u8> {
+    der(0x17, time.format("%y%m%d%H%M%SZ").to_string().as_bytes())
+}
+
+fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(std::str::from_utf8(line).unwrap_or_default());
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
+}
+
+// 写入临时文件后重命名，避免证书文件被读到一半；私钥只有所有者可读
+fn write_private(path: &str, content: &[u8]) -> Result<(), AppError> {
+    let temporary = format!("{path}.tmp");
+    std::fs::write(&temporary, content)
+        .map_err(|e| AppError::Acme(format!("无法写入 {path}: {e}")))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&temporary, std::fs::Permissions::from_mode(0o600))?;
+    }
+    std::fs::rename(&temporary, path)
+        .map_err(|e| AppError::Acme(format!("无法写入 {path}: {e}")))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, ECDSA_P256_SHA256_FIXED};
+
+    #[test]
+    fn der_lengths() {
+        assert_eq!(der(0x04, &[0xaa; 3]), [&[0x04, 3][..], &[0xaa; 3]].concat());
+        assert_eq!(der(0x04, &[0; 127])[..2], [0x04, 0x7f]);
+        assert_eq!(der(0x04, &[0; 128])[..3], [0x04, 0x81, 0x80]);
+        assert_eq!(der(0x04, &[0; 300])[..4], [0x04, 0x82, 0x01, 0x2c]);
+
+        // 长格式的长度能被 tls 模块的解析器读回
+        let content = vec![0x5a; 300];
+        let encoded = sequence(&[der(0x04, &content), der(0x05, &[])]);
+        let (tag, inner, rest) = tls::der_element(&encoded).unwrap();
+        assert_eq!((tag, rest.len()), (0x30, 0));
+        let (tag, value, rest) = tls::der_element(inner).unwrap();
+        assert_eq!((tag, value), (0x04, &content[..]));
+        assert_eq!(rest, [0x05, 0x00]);
+    }
+
+    #[test]
+    fn certificate_request_is_signed_by_its_key() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap();
+        // 足够多的域名让各层长度超过 127 字节
+        let domains: Vec<String> = (0..8).map(|i| format!("host{i}.registry.example.com")).collect();
+
+        let csr = certificate_request(&domains, pkcs8.as_ref()).unwrap();
+        let (tag, request, rest) = tls::der_element(&csr).unwrap();
+        assert_eq!((tag, rest.len()), (0x30, 0));
+
+        // CertificationRequestInfo 按原始编码参与签名
+        let (_, _, after_info) = tls::der_element(request).unwrap();
+        let info = &request[..request.len() - after_info.len()];
+        assert!(info.len() > 127);
+        let (_, _, signature) = tls::der_element(after_info).unwrap();
+        let (tag, signature, _) = tls::der_element(signature).unwrap();
+        assert_eq!((tag, signature[0]), (0x03, 0));
+
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, key.public_key().as_ref())
+            .verify(info, &signature[1..])
+            .unwrap();
+        for domain in &domains {
+            assert!(info.windows(domain.len()).any(|window| window == domain.as_bytes()));
+        }
+    }
+
+    #[test]
+    fn alpn_certificate_carries_domain_and_validity() {
+        let certified = alpn_challenge_certificate("registry.example.com", "token.thumbprint").unwrap();
+        let certificate = &certified.cert[0].0;
+
+        assert_eq!(tls::dns_names(certificate), vec!["registry.example.com".to_string()]);
+        assert!(tls::not_after(certificate).unwrap() > Utc::now());
+        let digest = Sha256::digest(b"token.thumbprint");
+        assert!(certificate.windows(digest.len()).any(|window| window == digest.as_slice()));
+    }
+
+    #[test]
+    fn jws_signature_and_key_identifiers() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let mut client = AcmeClient {
+            http: reqwest::Client::new(),
+            directory: Directory {
+                new_nonce: String::new(),
+                new_account: String::new(),
+                new_order: String::new(),
+            },
+            key: EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng).unwrap(),
+            rng,
+            account_url: None,
+            nonce: None,
+        };
+
+        let payload = json!({"termsOfServiceAgreed": true});
+        let jws = client.sign("https://ca.example/new-account", "nonce-1", Some(&payload)).unwrap();
+        let protected: Value = serde_json::from_slice(&decode_field(&jws, "protected")).unwrap();
+        assert_eq!(protected["alg"], "ES256");
+        assert_eq!(protected["nonce"], "nonce-1");
+        assert_eq!(protected["url"], "https://ca.example/new-account");
+        assert_eq!(protected["jwk"], client.jwk());
+        assert!(protected.get("kid").is_none());
+        assert_eq!(serde_json::from_slice::<Value>(&decode_field(&jws, "payload")).unwrap(), payload);
+
+        let signing_input = format!("{}.{}", jws["protected"].as_str().unwrap(), jws["payload"].as_str().unwrap());
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, client.key.public_key().as_ref())
+            .verify(signing_input.as_bytes(), &decode_field(&jws, "signature"))
+            .unwrap();
+
+        // 注册后使用 kid，POST-as-GET 的 payload 为空
+        client.account_url = Some("https://ca.example/acct/1".to_string());
+        let jws = client.sign("https://ca.example/order/1", "nonce-2", None).unwrap();
+        let protected: Value = serde_json::from_slice(&decode_field(&jws, "protected")).unwrap();
+        assert_eq!(protected["kid"], "https://ca.example/acct/1");
+        assert!(protected.get("jwk").is_none());
+        assert_eq!(jws["payload"], "");
+
+        // RFC 7638 指纹是规范 JWK 的 SHA-256，长度为 43 个 base64url 字符
+        let (x, y) = client.jwk_coordinates();
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+        assert_eq!(client.thumbprint(), URL_SAFE_NO_PAD.encode(Sha256::digest(canonical.as_bytes())));
+        assert_eq!(client.thumbprint().len(), 43);
+    }
+
+    fn decode_field(jws: &Value, field: &str) -> Vec<u8> {
+        URL_SAFE_NO_PAD.decode(jws[field].as_str().unwrap()).unwrap()
+    }
+}
@@ -3,7 +3,8 @@ use serde::{Deserialize, Serialize};
 use log::error;
 use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 
-use crate::config::{UserSettings, RegistryCredential, RegistryApiVersion};
+use crate::config::{AuthSettings, UserSettings, RegistryCredential, RegistryApiVersion};
+use crate::upstream;
 
 // JWT token structure returned to Docker clients
 #[derive(Debug, Serialize, Deserialize)]
@@ -54,6 +55,15 @@ pub fn verify_user(username: &str, password: &str, users: &HashMap<String, UserS
     }
 }
 
+// 从 Authorization 头取得已验证的 docxy 用户，未启用认证或验证失败时返回 None
+pub fn authenticated_user(auth: &AuthSettings, authorization: Option<&str>) -> Option<String> {
+    if !auth.enabled {
+        return None;
+    }
+    let (username, password) = parse_basic_auth(authorization?)?;
+    verify_user(&username, &password, &auth.users).then_some(username)
+}
+
 // Get registry credentials for a user and registry
 pub fn get_registry_credentials<'a>(username: &str, registry: &str, users: &'a HashMap<String, UserSettings>) -> Option<&'a RegistryCredential> {
     log::debug!("查找用户 {} 对注册表 {} 的凭据", username, registry);
@@ -205,12 +215,11 @@ pub fn parse_www_authenticate(www_auth: &str) -> Option<AuthChallenge> {
 
 // Get Docker Registry v2 bearer token
 pub async fn get_registry_v2_token(
+    registry_key: &str,
     username: &str,
     password: &str,
     challenge: &AuthChallenge,
 ) -> Result<String, String> {
-    use reqwest;
-    
     log::debug!("获取 Registry v2 Bearer token，realm: {}, service: {}, scope: {:?}", 
         challenge.realm, challenge.service, challenge.scope);
     
@@ -223,8 +232,8 @@ pub async fn get_registry_v2_token(
     // 创建 Basic 认证头
     let auth_header = create_basic_auth(username, password);
     
-    // 发送请求
-    let client = reqwest::Client::new();
+    // 发送请求，使用注册表配置的 HTTP 客户端（代理、CA、超时）
+    let client = upstream::client_for(registry_key);
     let response = client
         .get(&auth_url)
         .header("Authorization", auth_header)
@@ -253,6 +262,7 @@ pub async fn get_registry_v2_token(
 
 // Handle registry authentication based on API version
 pub async fn handle_registry_auth(
+    registry_key: &str,
     registry_cred: &RegistryCredential,
     api_version: &RegistryApiVersion,
     target_url: &str,
@@ -267,7 +277,7 @@ pub async fn handle_registry_auth(
             log::debug!("尝试 Registry v2 token challenge 流程");
             
             // 1. 先发送一个请求获取 WWW-Authenticate 头
-            let client = reqwest::Client::new();
+            let client = upstream::client_for(registry_key);
             let response = client.get(target_url).send().await
                 .map_err(|e| format!("获取认证挑战失败: {}", e))?;
             
@@ -277,6 +287,7 @@ pub async fn handle_registry_auth(
                         if let Some(challenge) = parse_www_authenticate(www_auth_str) {
                             // 2. 获取 Bearer token
                             let bearer_token = get_registry_v2_token(
+                                registry_key,
                                 &registry_cred.username,
                                 &registry_cred.password,
                                 &challenge,
@@ -308,7 +319,7 @@ pub async fn handle_registry_auth(
             log::debug!("尝试 Registry v2 token challenge 流程");
             
             // 1. 先发送一个请求获取 WWW-Authenticate 头
-            let client = reqwest::Client::new();
+            let client = upstream::client_for(registry_key);
             let response = client.get(target_url).send().await
                 .map_err(|e| format!("获取认证挑战失败: {}", e))?;
             
@@ -318,6 +329,7 @@ pub async fn handle_registry_auth(
                         if let Some(challenge) = parse_www_authenticate(www_auth_str) {
                             // 2. 获取 Bearer token
                             match get_registry_v2_token(
+                                registry_key,
                                 &registry_cred.username,
                                 &registry_cred.password,
                                 &challenge,
@@ -360,12 +372,10 @@ pub enum RegistryAuthResult {
 }
 
 // Auto-detect registry API version by probing /v2/ endpoint
-pub async fn detect_registry_api_version(registry_url: &str) -> RegistryApiVersion {
-    use reqwest;
-    
+pub async fn detect_registry_api_version(registry_key: &str, registry_url: &str) -> RegistryApiVersion {
     log::debug!("自动检测注册表 API 版本: {}", registry_url);
     
-    let client = reqwest::Client::new();
+    let client = upstream::client_for(registry_key);
     let v2_url = format!("{}/v2/", registry_url.trim_end_matches('/'));
     
     match client.get(&v2_url).send().await {
@@ -376,13 +386,11 @@ pub async fn detect_registry_api_version(registry_url: &str) -> RegistryApiVersi
             // Check if it has Docker Registry v2 API indicators
             if status == 200 || status == 401 || status == 403 {
                 // Check for v2 API specific headers
-                if let Some(www_auth) = response.headers().get("WWW-Authenticate") {
-                    if let Ok(auth_str) = www_auth.to_str() {
-                        if auth_str.contains("Bearer") {
-                            log::info!("检测到 Docker Registry v2 API (Bearer 认证)");
-                            return RegistryApiVersion::V2;
-                        }
-                    }
+                if let Some(www_auth) = response.headers().get("WWW-Authenticate")
+                    && let Ok(auth_str) = www_auth.to_str()
+                    && auth_str.contains("Bearer") {
+                    log::info!("检测到 Docker Registry v2 API (Bearer 认证)");
+                    return RegistryApiVersion::V2;
                 }
                 
                 // Check for Docker-Distribution-Api-Version header
@@ -418,7 +426,7 @@ pub async fn authenticate_registry(
     api_version: &RegistryApiVersion
 ) -> RegistryAuthResult {
     let effective_version = match api_version {
-        RegistryApiVersion::Auto => detect_registry_api_version(registry_url).await,
+        RegistryApiVersion::Auto => detect_registry_api_version(registry_key, registry_url).await,
         version => version.clone(),
     };
     
@@ -436,6 +444,7 @@ pub async fn authenticate_registry(
             log::debug!("使用 v2 API Bearer token 认证");
             
             match handle_registry_auth(
+                registry_key,
                 &RegistryCredential {
                     username: username.to_string(),
                     password: password.to_string(),
@@ -446,9 +455,9 @@ pub async fn authenticate_registry(
                 Ok(auth_header) => {
                     if auth_header.is_empty() {
                         RegistryAuthResult::NoAuth
-                    } else if auth_header.starts_with("Bearer ") {
-                        let token = auth_header[7..].to_string(); // 移除 "Bearer " 前缀
-                        RegistryAuthResult::BearerToken(token)
+                    } else if let Some(token) = auth_header.strip_prefix("Bearer ") {
+                        // 移除 "Bearer " 前缀
+                        RegistryAuthResult::BearerToken(token.to_string())
                     } else {
                         RegistryAuthResult::BasicAuth(auth_header)
                     }
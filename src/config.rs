@@ -1,67 +1,259 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 
-#[derive(Debug, Deserialize, Clone)]
+fn default_bind_addresses() -> Vec<String> {
+    vec!["0.0.0.0".to_string()]
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct ServerSettings {
     pub http_port: u16,
     pub https_port: u16,
     pub http_enabled: bool,
     pub https_enabled: bool,
     pub behind_proxy: bool,
+    #[serde(default = "default_bind_addresses")]
+    pub bind_addresses: Vec<String>, // HTTP 和 HTTPS 的监听地址，如 0.0.0.0、[::]、127.0.0.1
+    #[serde(default)]
+    pub unix_sockets: Vec<String>,   // 提供完整功能的 Unix 套接字路径，供本机反向代理使用
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>, // 可信反向代理的 CIDR，只接受这些地址发来的 Forwarded / X-Forwarded-* 头
+    #[serde(default)]
+    pub public_url: Option<String>,   // 客户端访问的外部地址，如 https://registry.example.com，用于生成认证地址和重定向
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
 pub enum RegistryApiVersion {
     #[serde(rename = "v1")]
     V1,
     #[serde(rename = "v2")]
     V2,
     #[serde(rename = "auto")]
+    #[default]
     Auto,
 }
 
-impl Default for RegistryApiVersion {
-    fn default() -> Self {
-        RegistryApiVersion::Auto
-    }
+// blob 重定向处理方式
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub enum BlobRedirectMode {
+    #[serde(rename = "follow")]
+    #[default]
+    Follow,       // 服务端跟随重定向并流式传输内容
+    #[serde(rename = "passthrough")]
+    Passthrough,  // 将重定向直接返回给客户端
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct RegistryConfig {
     pub url: String,
     pub api_version: RegistryApiVersion,
     pub auth_url: Option<String>,  // 认证服务URL，如果为空则使用 {url}/token
+    #[serde(default)]
+    pub service: Option<String>,   // 认证服务名称，如果为空则透传客户端提供的 service
+    #[serde(default)]
+    pub mirrors: Vec<String>,      // 按顺序优先尝试的镜像端点，全部失败后回退到 url
+    #[serde(default)]
+    pub client: Option<HttpClientSettings>, // 该注册表专用的 HTTP 客户端配置，为空则使用 [registry.client]
+    #[serde(default)]
+    pub blob_redirect: BlobRedirectMode,    // blob 请求被重定向到 CDN 时的处理方式
+    #[serde(default)]
+    pub hosts: Vec<String>,        // 按请求 Host 路由到该注册表的主机名，如 ghcr.mirror.example.com
+    #[serde(default)]
+    pub rewrites: Vec<PathRewrite>, // 转发前对镜像路径的改写规则
+}
+
+// 镜像路径改写规则，按路径段匹配前缀，from 为空时匹配所有路径
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PathRewrite {
+    pub from: String,
+    pub to: String,
+}
+
+fn default_connect_timeout_secs() -> u64 {
+    10
+}
+
+fn default_read_timeout_secs() -> u64 {
+    60
+}
+
+fn default_pool_idle_timeout_secs() -> u64 {
+    90
+}
+
+fn default_pool_max_idle_per_host() -> usize {
+    10
+}
+
+fn default_max_redirects() -> usize {
+    10
+}
+
+// 上游 HTTP 客户端配置，不设置总超时以免中断大文件的流式传输
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HttpClientSettings {
+    #[serde(default = "default_connect_timeout_secs")]
+    pub connect_timeout_secs: u64,
+    #[serde(default = "default_read_timeout_secs")]
+    pub read_timeout_secs: u64,    // 两次读取之间的最长等待时间
+    #[serde(default = "default_pool_idle_timeout_secs")]
+    pub pool_idle_timeout_secs: u64,
+    #[serde(default = "default_pool_max_idle_per_host")]
+    pub pool_max_idle_per_host: usize,
+    #[serde(default)]
+    pub proxy: Option<String>,     // 出站代理，支持 http://、https://、socks5://、socks5h://
+    #[serde(default)]
+    pub ca_bundle: Option<String>, // 额外信任的 CA 证书（PEM 格式，可包含多个证书）
+    #[serde(default)]
+    pub insecure_skip_verify: bool, // 跳过 TLS 证书校验，仅用于内部注册表
+    #[serde(default = "default_max_redirects")]
+    pub max_redirects: usize,      // 服务端跟随重定向的最大次数
+}
+
+impl Default for HttpClientSettings {
+    fn default() -> Self {
+        HttpClientSettings {
+            connect_timeout_secs: default_connect_timeout_secs(),
+            read_timeout_secs: default_read_timeout_secs(),
+            pool_idle_timeout_secs: default_pool_idle_timeout_secs(),
+            pool_max_idle_per_host: default_pool_max_idle_per_host(),
+            proxy: None,
+            ca_bundle: None,
+            insecure_skip_verify: false,
+            max_redirects: default_max_redirects(),
+        }
+    }
+}
+
+fn default_failure_threshold() -> u32 {
+    3
+}
+
+fn default_cooldown_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct FailoverSettings {
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u32,    // 连续失败多少次后熔断端点
+    #[serde(default = "default_cooldown_secs")]
+    pub cooldown_secs: u64,        // 熔断后的冷却时间（秒）
+}
+
+impl Default for FailoverSettings {
+    fn default() -> Self {
+        FailoverSettings {
+            failure_threshold: default_failure_threshold(),
+            cooldown_secs: default_cooldown_secs(),
+        }
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_initial_backoff_ms() -> u64 {
+    200
+}
+
+fn default_max_backoff_ms() -> u64 {
+    5000
+}
+
+fn default_retryable_status_codes() -> Vec<u16> {
+    vec![429, 502, 503, 504]
+}
+
+fn default_true() -> bool {
+    true
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct RetrySettings {
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,         // 每个端点的最大尝试次数（含首次请求）
+    #[serde(default = "default_initial_backoff_ms")]
+    pub initial_backoff_ms: u64,   // 首次重试前的等待时间，之后按指数增长
+    #[serde(default = "default_max_backoff_ms")]
+    pub max_backoff_ms: u64,       // 单次等待时间上限
+    #[serde(default = "default_true")]
+    pub jitter: bool,              // 是否对等待时间添加随机抖动
+    #[serde(default = "default_retryable_status_codes")]
+    pub retryable_status_codes: Vec<u16>,
+    #[serde(default = "default_true")]
+    pub resume_interrupted_blobs: bool, // blob 传输中断时使用 Range 请求从已发送位置续传
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        RetrySettings {
+            max_attempts: default_max_attempts(),
+            initial_backoff_ms: default_initial_backoff_ms(),
+            max_backoff_ms: default_max_backoff_ms(),
+            jitter: true,
+            retryable_status_codes: default_retryable_status_codes(),
+            resume_interrupted_blobs: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct RegistrySettings {
     pub upstream_registry: String,
     #[serde(default)]
     pub registries: HashMap<String, RegistryConfig>,
+    #[serde(default)]
+    pub failover: FailoverSettings,
+    #[serde(default)]
+    pub retry: RetrySettings,
+    #[serde(default)]
+    pub client: HttpClientSettings,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct TlsSettings {
     #[serde(default)]
     pub cert_path: String,
     #[serde(default)]
     pub key_path: String,
+    #[serde(default)]
+    pub certificates: Vec<TlsCertificate>, // 按 SNI 选择的其他证书，未匹配时使用上面的默认证书
+    #[serde(default = "default_true")]
+    pub watch_files: bool,         // 证书文件修改后自动重新加载，如 acme.sh 续期
+    #[serde(default = "default_tls_interval_secs")]
+    pub interval_secs: u64,        // 检查证书文件的间隔（秒）
+}
+
+// 按 SNI 主机名选择的证书
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct TlsCertificate {
+    pub cert_path: String,
+    pub key_path: String,
+    #[serde(default)]
+    pub hosts: Vec<String>,        // 使用此证书的主机名，支持 *.example.com；为空时使用证书中的 DNS 名称
 }
 
-#[derive(Debug, Deserialize, Clone)]
+fn default_tls_interval_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct RegistryCredential {
     pub username: String,
     pub password: String,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct UserSettings {
     pub password: String,
     #[serde(default)]
     pub registry_credentials: HashMap<String, RegistryCredential>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct AuthSettings {
     #[serde(default)]
     pub enabled: bool,
@@ -69,19 +261,491 @@ pub struct AuthSettings {
     pub users: HashMap<String, UserSettings>,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub enum PolicyAction {
+    #[serde(rename = "allow")]
+    #[default]
+    Allow,
+    #[serde(rename = "deny")]
+    Deny,
+}
+
+// 镜像访问规则，所有已配置的条件都满足时规则生效；列表为空表示不限制
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PolicyRule {
+    pub action: PolicyAction,
+    #[serde(default)]
+    pub registries: Vec<String>,   // 注册表键通配符，如 docker.io、*.pkg.dev
+    #[serde(default)]
+    pub repositories: Vec<String>, // 仓库名通配符（Docker Hub 已规范化），如 library/*
+    #[serde(default)]
+    pub tags: Vec<String>,         // 标签通配符，特殊值 semver 匹配语义化版本标签；设置后规则只作用于按标签拉取的 manifest
+    #[serde(default)]
+    pub clients: Vec<String>,      // 客户端地址 CIDR，如 10.0.0.0/8；经过可信代理时使用转发的客户端地址
+    #[serde(default)]
+    pub description: Option<String>, // 拒绝时返回给客户端的说明
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct PolicySettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub default_action: PolicyAction, // 没有规则匹配时的处理方式
+    #[serde(default)]
+    pub require_digest: bool,      // 只允许按摘要拉取 manifest
+    #[serde(default)]
+    pub rules: Vec<PolicyRule>,    // 按顺序匹配，第一条匹配的规则生效
+}
+
+fn default_manifest_cache_entries() -> usize {
+    1024
+}
+
+fn default_manifest_origin_entries() -> usize {
+    16384
+}
+
+fn default_manifest_origins_file() -> String {
+    "/var/lib/docxy/manifest-origins.json".to_string()
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ManifestSettings {
+    #[serde(default)]
+    pub platforms: Vec<String>,    // 按标签拉取时只保留这些平台，如 linux/amd64；为空不过滤
+    #[serde(default)]
+    pub reject_root: bool,         // 拒绝以 root 用户运行的镜像
+    #[serde(default)]
+    pub required_labels: Vec<String>, // 镜像配置必须包含的标签
+    #[serde(default = "default_manifest_cache_entries")]
+    pub cache_entries: usize,      // 本地生成的 manifest 和镜像配置在内存中保留的数量
+    #[serde(default = "default_manifest_origin_entries")]
+    pub origin_entries: usize,     // 本地生成内容的来源保留的数量，内容被淘汰后据此重新生成
+    #[serde(default = "default_manifest_origins_file")]
+    pub origins_file: String,      // 保存来源的文件，重启后仍能重新生成；为空时只保存在内存中
+}
+
+impl Default for ManifestSettings {
+    fn default() -> Self {
+        ManifestSettings {
+            platforms: Vec::new(),
+            reject_root: false,
+            required_labels: Vec::new(),
+            cache_entries: default_manifest_cache_entries(),
+            origin_entries: default_manifest_origin_entries(),
+            origins_file: default_manifest_origins_file(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct BandwidthSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub global_bytes_per_sec: u64,   // 所有下载共享的带宽，0 表示不限制
+    #[serde(default)]
+    pub per_user_bytes_per_sec: u64, // 每个 docxy 用户的带宽
+    #[serde(default)]
+    pub per_ip_bytes_per_sec: u64,   // 每个客户端 IP 的带宽
+    #[serde(default)]
+    pub users: HashMap<String, u64>, // 按用户名覆盖 per_user_bytes_per_sec
+    #[serde(default)]
+    pub registries: HashMap<String, u64>, // 按注册表键限制带宽
+    #[serde(default = "default_true")]
+    pub exempt_cached: bool,         // 本地缓存返回的内容不受限制
+}
+
+impl Default for BandwidthSettings {
+    fn default() -> Self {
+        BandwidthSettings {
+            enabled: false,
+            global_bytes_per_sec: 0,
+            per_user_bytes_per_sec: 0,
+            per_ip_bytes_per_sec: 0,
+            users: HashMap::new(),
+            registries: HashMap::new(),
+            exempt_cached: true,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub enum QuotaAction {
+    #[serde(rename = "reject")]
+    #[default]
+    Reject,
+    #[serde(rename = "queue")]
+    Queue,
+}
+
+fn default_quota_min_remaining() -> u64 {
+    10
+}
+
+fn default_quota_max_wait_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct UpstreamQuotaSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_quota_min_remaining")]
+    pub min_remaining: u64,        // 上游剩余配额不超过该值时开始保护
+    #[serde(default)]
+    pub action: QuotaAction,       // reject 直接拒绝；queue 按配额恢复速度排队
+    #[serde(default = "default_quota_max_wait_secs")]
+    pub max_wait_secs: u64,        // 排队等待的最长时间，超过时拒绝
+}
+
+impl Default for UpstreamQuotaSettings {
+    fn default() -> Self {
+        UpstreamQuotaSettings {
+            enabled: false,
+            min_remaining: default_quota_min_remaining(),
+            action: QuotaAction::default(),
+            max_wait_secs: default_quota_max_wait_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct RateLimitSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub per_user_requests_per_minute: u32, // 0 表示不限制
+    #[serde(default)]
+    pub per_ip_requests_per_minute: u32,
+    #[serde(default)]
+    pub upstream_quota: UpstreamQuotaSettings, // 根据上游 ratelimit-remaining 保护共享配额
+}
+
+fn default_reload_interval_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ReloadSettings {
+    #[serde(default)]
+    pub watch_files: bool,         // 配置文件修改后自动重新加载，SIGHUP 始终可用
+    #[serde(default = "default_reload_interval_secs")]
+    pub interval_secs: u64,        // 检查配置文件修改的间隔
+}
+
+impl Default for ReloadSettings {
+    fn default() -> Self {
+        ReloadSettings {
+            watch_files: false,
+            interval_secs: default_reload_interval_secs(),
+        }
+    }
+}
+
+// ACME 验证方式
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Default)]
+pub enum AcmeChallenge {
+    #[serde(rename = "http-01")]
+    #[default]
+    Http01,       // 通过 HTTP 端口的 /.well-known/acme-challenge/ 验证
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,    // 通过 HTTPS 端口的 acme-tls/1 握手验证
+}
+
+fn default_acme_directory_url() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_string()
+}
+
+fn default_acme_state_dir() -> String {
+    "/var/lib/docxy/acme".to_string()
+}
+
+fn default_acme_renew_before_days() -> i64 {
+    30
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AcmeSettings {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_acme_directory_url")]
+    pub directory_url: String,     // ACME 目录地址，测试时可使用 Pebble 等本地服务
+    #[serde(default)]
+    pub domains: Vec<String>,      // 证书包含的域名，第一个作为证书主体
+    #[serde(default)]
+    pub contact: Vec<String>,      // 账户联系方式，如 mailto:admin@example.com
+    #[serde(default)]
+    pub challenge: AcmeChallenge,
+    #[serde(default = "default_acme_state_dir")]
+    pub state_dir: String,         // 保存账户密钥、证书和私钥的目录
+    #[serde(default = "default_acme_renew_before_days")]
+    pub renew_before_days: i64,    // 证书剩余有效期少于这个天数时续期
+    #[serde(default)]
+    pub ca_bundle: Option<String>, // 访问 ACME 目录时额外信任的 CA 证书（PEM），如 Pebble 的测试 CA
+}
+
+impl Default for AcmeSettings {
+    fn default() -> Self {
+        AcmeSettings {
+            enabled: false,
+            directory_url: default_acme_directory_url(),
+            domains: Vec::new(),
+            contact: Vec::new(),
+            challenge: AcmeChallenge::default(),
+            state_dir: default_acme_state_dir(),
+            renew_before_days: default_acme_renew_before_days(),
+            ca_bundle: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct Settings {
     pub server: ServerSettings,
     pub registry: RegistrySettings,
     pub tls: TlsSettings,
     pub auth: AuthSettings,
+    #[serde(default)]
+    pub policy: PolicySettings,
+    #[serde(default)]
+    pub manifest: ManifestSettings,
+    #[serde(default)]
+    pub bandwidth: BandwidthSettings,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    #[serde(default)]
+    pub reload: ReloadSettings,
+    #[serde(default)]
+    pub acme: AcmeSettings,
+}
+
+// 可热重载的配置，每个请求开始时取得当前配置的快照，进行中的请求继续使用旧配置
+pub struct SharedSettings(RwLock<Arc<Settings>>);
+
+impl SharedSettings {
+    pub fn new(settings: Settings) -> Self {
+        SharedSettings(RwLock::new(Arc::new(settings)))
+    }
+
+    pub fn load(&self) -> Arc<Settings> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn store(&self, settings: Settings) {
+        *self.0.write().unwrap() = Arc::new(settings);
+    }
+}
+
+// 默认配置文件，local 可选，用于覆盖 default 中的部分配置
+const DEFAULT_CONFIG_FILE: &str = "config/default";
+const LOCAL_CONFIG_FILE: &str = "config/local";
+
+// 环境变量覆盖：DOCXY__SERVER__HTTPS_PORT=8443 对应 server.https_port
+const ENV_PREFIX: &str = "DOCXY";
+const ENV_SEPARATOR: &str = "__";
+const ENV_LIST_SEPARATOR: char = ',';
+
+// 环境变量配置源，按 __ 分段构建嵌套的配置，每一段都作为完整的键
+// 因此包含 . 的注册表键可以直接写在变量名中，如 DOCXY__REGISTRY__REGISTRIES__docker.io__MIRRORS 对应 registry.registries."docker.io".mirrors
+// 配置结构中的数组字段按逗号分隔解析
+#[derive(Debug, Clone)]
+struct EnvironmentSource {
+    vars: Vec<(String, String)>,
+}
+
+impl config::Source for EnvironmentSource {
+    fn clone_into_box(&self) -> Box<dyn config::Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<config::Map<String, config::Value>, config::ConfigError> {
+        let origin = "the environment".to_string();
+        let prefix = format!("{ENV_PREFIX}{ENV_SEPARATOR}").to_lowercase();
+        let list_paths = env_list_paths();
+        let mut root = config::Map::new();
+
+        for (name, value) in &self.vars {
+            let name = name.to_lowercase();
+            let Some(key) = name.strip_prefix(&prefix) else {
+                continue;
+            };
+            // 与 config::Environment 一样忽略空值
+            if value.is_empty() {
+                continue;
+            }
+
+            let segments: Vec<&str> = key.split(ENV_SEPARATOR).collect();
+            if segments.iter().any(|segment| segment.is_empty()) {
+                continue;
+            }
+
+            let kind = if list_paths.iter().any(|path| path_matches(path, &segments)) {
+                let items: Vec<config::Value> = value.split(ENV_LIST_SEPARATOR)
+                    .map(|item| config::Value::new(Some(&origin), item.trim().to_string()))
+                    .collect();
+                config::ValueKind::from(items)
+            } else if let Ok(parsed) = value.to_lowercase().parse::<bool>() {
+                config::ValueKind::Boolean(parsed)
+            } else if let Ok(parsed) = value.parse::<i64>() {
+                config::ValueKind::I64(parsed)
+            } else if let Ok(parsed) = value.parse::<f64>() {
+                config::ValueKind::Float(parsed)
+            } else {
+                config::ValueKind::String(value.clone())
+            };
+            insert_env_value(&mut root, &segments, config::Value::new(Some(&origin), kind));
+        }
+
+        Ok(root)
+    }
+}
+
+fn insert_env_value(table: &mut config::Map<String, config::Value>, segments: &[&str], value: config::Value) {
+    let Some((first, rest)) = segments.split_first() else {
+        return;
+    };
+    if rest.is_empty() {
+        table.insert(first.to_string(), value);
+        return;
+    }
+
+    let entry = table.entry(first.to_string())
+        .or_insert_with(|| config::Value::new(None, config::Map::<String, config::Value>::new()));
+    if !matches!(entry.kind, config::ValueKind::Table(_)) {
+        entry.kind = config::ValueKind::Table(config::Map::new());
+    }
+    if let config::ValueKind::Table(child) = &mut entry.kind {
+        insert_env_value(child, rest, value);
+    }
+}
+
+// 路径中的 * 匹配映射中任意的键
+fn path_matches(pattern: &[String], segments: &[&str]) -> bool {
+    pattern.len() == segments.len()
+        && pattern.iter().zip(segments).all(|(expected, segment)| expected == "*" || expected == segment)
+}
+
+// 从配置结构中找出所有数组字段的路径，如 server.bind_addresses、registry.registries.*.mirrors
+// 样例只填写必需的字段，每个映射放入一个键为 * 的条目，其余字段由反序列化补全默认值
+// 包含所有配置项的有效配置样例，映射表用 * 作为示例键，结构体列表包含一个示例元素
+pub fn schema_sample() -> serde_json::Value {
+    let skeleton = serde_json::json!({
+        "server": {"http_port": 0, "https_port": 0, "http_enabled": false, "https_enabled": false, "behind_proxy": false},
+        "registry": {
+            "upstream_registry": "",
+            "registries": {"*": {
+                "url": "", "api_version": "auto", "auth_url": null, "client": {},
+                "rewrites": [{"from": "", "to": ""}],
+            }},
+        },
+        "tls": {"certificates": [{"cert_path": "", "key_path": ""}]},
+        "auth": {"users": {"*": {"password": "", "registry_credentials": {"*": {"username": "", "password": ""}}}}},
+        "policy": {"rules": [{"action": "allow"}]},
+        "bandwidth": {"users": {"*": 0}, "registries": {"*": 0}},
+    });
+    serde_json::from_value::<Settings>(skeleton)
+        .and_then(serde_json::to_value)
+        .expect("配置结构样例无效")
+}
+
+fn env_list_paths() -> Vec<Vec<String>> {
+    let mut paths = Vec::new();
+    collect_list_paths(&schema_sample(), &mut Vec::new(), &mut paths);
+    paths
+}
+
+fn collect_list_paths(value: &serde_json::Value, path: &mut Vec<String>, paths: &mut Vec<Vec<String>>) {
+    match value {
+        serde_json::Value::Array(_) => paths.push(path.clone()),
+        serde_json::Value::Object(map) => {
+            for (key, child) in map {
+                path.push(key.clone());
+                collect_list_paths(child, path, paths);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
 }
 
 impl Settings {
-    pub fn new() -> Result<Self, config::ConfigError> {
-        let builder = config::Config::builder()
-            .add_source(config::File::with_name("config/default"));
+    // 按顺序加载配置文件，后面的文件覆盖前面的配置，环境变量优先级最高
+    // 没有指定配置文件时使用 config/default 和可选的 config/local
+    pub fn config_source(config_files: &[String]) -> Result<config::Config, config::ConfigError> {
+        let mut builder = config::Config::builder();
+
+        if config_files.is_empty() {
+            builder = builder
+                .add_source(config::File::with_name(DEFAULT_CONFIG_FILE))
+                .add_source(config::File::with_name(LOCAL_CONFIG_FILE).required(false));
+        } else {
+            for config_file in config_files {
+                builder = builder.add_source(config::File::with_name(config_file));
+            }
+        }
+
+        builder.add_source(EnvironmentSource { vars: std::env::vars().collect() }).build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        builder.build()?.try_deserialize()
+    fn collect(vars: &[(&str, &str)]) -> serde_json::Value {
+        let source = EnvironmentSource {
+            vars: vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        };
+        config::Config::builder()
+            .add_source(source)
+            .build()
+            .and_then(|config| config.try_deserialize())
+            .unwrap()
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn list_paths_come_from_the_schema() {
+        let paths: Vec<String> = env_list_paths().iter().map(|path| path.join(".")).collect();
+        for expected in [
+            "server.bind_addresses",
+            "server.unix_sockets",
+            "server.trusted_proxies",
+            "registry.registries.*.mirrors",
+            "registry.registries.*.hosts",
+            "registry.retry.retryable_status_codes",
+            "manifest.platforms",
+            "acme.domains",
+        ] {
+            assert!(paths.iter().any(|path| path == expected), "{expected}: {paths:?}");
+        }
+        assert!(!paths.iter().any(|path| path == "server.public_url"));
+    }
+
+    #[test]
+    fn environment_values_are_nested_and_typed() {
+        let value = collect(&[
+            ("DOCXY__SERVER__HTTPS_PORT", "8443"),
+            ("DOCXY__SERVER__BEHIND_PROXY", "true"),
+            ("DOCXY__SERVER__TRUSTED_PROXIES", "10.0.0.0/8, 127.0.0.1/32"),
+            ("DOCXY__SERVER__BIND_ADDRESSES", "[::]"),
+            ("DOCXY__REGISTRY__REGISTRIES__docker.io__MIRRORS", "https://mirror.example.com"),
+            ("DOCXY__REGISTRY__REGISTRIES__GHCR__HOSTS", "ghcr.example.com,ghcr.internal"),
+            ("DOCXY__REGISTRY__RETRY__RETRYABLE_STATUS_CODES", "503"),
+            ("DOCXY__MANIFEST__ORIGINS_FILE", ""),
+            ("OTHER__SERVER__HTTP_PORT", "1"),
+        ]);
+
+        assert_eq!(value["server"]["https_port"], 8443);
+        assert_eq!(value["server"]["behind_proxy"], true);
+        assert_eq!(value["server"]["trusted_proxies"], serde_json::json!(["10.0.0.0/8", "127.0.0.1/32"]));
+        assert_eq!(value["server"]["bind_addresses"], serde_json::json!(["[::]"]));
+        assert!(value["server"].get("http_port").is_none());
+        // 包含 . 的注册表键作为一个完整的键
+        assert_eq!(value["registry"]["registries"]["docker.io"]["mirrors"], serde_json::json!(["https://mirror.example.com"]));
+        assert_eq!(value["registry"]["registries"]["ghcr"]["hosts"], serde_json::json!(["ghcr.example.com", "ghcr.internal"]));
+        assert_eq!(value["registry"]["retry"]["retryable_status_codes"], serde_json::json!(["503"]));
+        assert!(value.get("manifest").is_none());
+    }
+}
@@ -1,4 +1,6 @@
 use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use log::error;
+use serde_json::json;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -6,33 +8,166 @@ pub enum AppError {
     #[error("Upstream registry request failed")]
     UpstreamRequest(#[from] reqwest::Error),
 
+    #[error("Upstream registry unavailable: {0}")]
+    UpstreamUnavailable(String),
+
+    #[error("Configuration error: {0}")]
+    Config(String),
+
     #[error("TLS configuration failed to load: {0}")]
     TlsConfig(String),
 
     #[error("Rustls error")]
     Rustls(#[from] rustls::Error),
 
+    #[error("Upstream HTTP client configuration failed: {0}")]
+    HttpClient(String),
+
     #[error("Invalid client request: {0}")]
     InvalidRequest(String),
 
+    #[error("Authentication required: {0}")]
+    Unauthorized(String),
+
+    #[error("Unknown route: {0}")]
+    UnknownRoute(String),
+
+    #[error("Method {method} not allowed")]
+    MethodNotAllowed { method: String, allow: &'static str },
+
+    #[error("Manifest conversion failed: {0}")]
+    ManifestConversion(String),
+
+    #[error("Invalid upstream manifest: {0}")]
+    ManifestInvalid(String),
+
+    #[error("Access denied by policy: {0}")]
+    Denied(String),
+
+    #[error("Too many requests: {0}")]
+    TooManyRequests(String, u64),
+
+    #[error("ACME certificate issuance failed: {0}")]
+    Acme(String),
+
     #[error("I/O error")]
     Io(#[from] std::io::Error),
 }
 
+impl AppError {
+    // OCI Distribution 规范的错误码、说明和详细信息
+    fn oci_error(&self) -> (&'static str, &'static str, String) {
+        match self {
+            // 完整错误包含上游地址，只记录到日志，返回给客户端的说明不包含地址
+            AppError::UpstreamRequest(e) => {
+                let detail = if e.is_timeout() {
+                    "upstream registry request timed out"
+                } else if e.is_connect() {
+                    "could not connect to upstream registry"
+                } else if e.is_body() || e.is_decode() {
+                    "failed to read upstream registry response"
+                } else {
+                    "upstream registry request failed"
+                };
+                ("UNAVAILABLE", "upstream registry request failed", detail.to_string())
+            }
+            AppError::UpstreamUnavailable(detail) => ("UNAVAILABLE", "upstream registry unavailable", detail.clone()),
+            AppError::InvalidRequest(detail) => ("UNSUPPORTED", "invalid request parameters", detail.clone()),
+            AppError::Unauthorized(detail) => ("UNAUTHORIZED", "authentication required", detail.clone()),
+            AppError::UnknownRoute(path) => ("UNSUPPORTED", "the operation is unsupported", path.clone()),
+            AppError::MethodNotAllowed { method, .. } => ("UNSUPPORTED", "the operation is unsupported", format!("method {method} not allowed")),
+            AppError::ManifestConversion(detail) => ("MANIFEST_INVALID", "manifest invalid", detail.clone()),
+            AppError::ManifestInvalid(detail) => ("MANIFEST_INVALID", "manifest invalid", detail.clone()),
+            AppError::Denied(detail) => ("DENIED", "requested access to the resource is denied", detail.clone()),
+            AppError::TooManyRequests(detail, _) => ("TOOMANYREQUESTS", "too many requests", detail.clone()),
+            AppError::Config(_) | AppError::TlsConfig(_) | AppError::Rustls(_) | AppError::HttpClient(_) | AppError::Acme(_) | AppError::Io(_) => {
+                ("UNKNOWN", "unknown error", self.to_string())
+            }
+        }
+    }
+}
+
 impl ResponseError for AppError {
     fn status_code(&self) -> StatusCode {
         match self {
-            AppError::UpstreamRequest(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::UpstreamRequest(e) if e.is_timeout() => StatusCode::GATEWAY_TIMEOUT,
+            AppError::UpstreamRequest(_) => StatusCode::BAD_GATEWAY,
+            AppError::UpstreamUnavailable(_) => StatusCode::BAD_GATEWAY,
+            AppError::Config(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::TlsConfig(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::HttpClient(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::UnknownRoute(_) => StatusCode::NOT_FOUND,
+            AppError::MethodNotAllowed { .. } => StatusCode::METHOD_NOT_ALLOWED,
+            AppError::ManifestConversion(_) => StatusCode::BAD_GATEWAY,
+            AppError::ManifestInvalid(_) => StatusCode::BAD_GATEWAY,
+            AppError::Denied(_) => StatusCode::FORBIDDEN,
+            AppError::TooManyRequests(..) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Acme(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
             AppError::Rustls(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
+    // 使用 OCI Distribution 规范的错误格式，Docker 和 containerd 会直接显示给用户
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .content_type("text/plain; charset=utf-8")
-            .body(self.to_string())
+        let (code, message, detail) = self.oci_error();
+        let mut builder = HttpResponse::build(self.status_code());
+        builder.content_type("application/json");
+
+        match self {
+            AppError::UpstreamRequest(e) => {
+                error!("上游请求失败: {e:?}");
+            }
+            AppError::Unauthorized(_) => {
+                builder.insert_header(("WWW-Authenticate", "Basic realm=\"Docker Registry\""));
+            }
+            AppError::MethodNotAllowed { allow, .. } => {
+                builder.insert_header(("Allow", *allow));
+            }
+            AppError::TooManyRequests(_, retry_after) => {
+                builder.insert_header(("Retry-After", retry_after.to_string()));
+            }
+            _ => {}
+        }
+
+        builder.body(json!({
+            "errors": [{
+                "code": code,
+                "message": message,
+                "detail": detail
+            }]
+        }).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body(response: HttpResponse) -> serde_json::Value {
+        let bytes = actix_web::body::to_bytes(response.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn unauthorized_sends_a_basic_challenge() {
+        let response = AppError::Unauthorized("credentials required".to_string()).error_response();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers().get("WWW-Authenticate").unwrap(), "Basic realm=\"Docker Registry\"");
+        assert_eq!(body(response).await["errors"][0]["code"], "UNAUTHORIZED");
+    }
+
+    #[actix_web::test]
+    async fn upstream_errors_do_not_expose_the_upstream_url() {
+        let error = reqwest::Client::new().get("http://127.0.0.1:1/v2/secret-repo/manifests/latest").send().await.unwrap_err();
+        assert!(error.to_string().contains("127.0.0.1"));
+
+        let response = AppError::UpstreamRequest(error).error_response();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        let error = &body(response).await["errors"][0];
+        assert_eq!(error["code"], "UNAVAILABLE");
+        assert_eq!(error["detail"], "could not connect to upstream registry");
     }
 }
@@ -0,0 +1,349 @@
+use std::net::IpAddr;
+use actix_web::HttpRequest;
+use actix_web::http::header;
+
+// 客户端的真实地址、协议和主机名
+// 只有来自可信代理的请求才使用 Forwarded / X-Forwarded-* 头，否则使用连接本身的信息
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    pub ip: Option<IpAddr>,
+    pub scheme: String,
+    pub host: String,              // 客户端请求的主机名，可能包含端口
+}
+
+impl ClientInfo {
+    pub fn from_request(req: &HttpRequest, trusted_proxies: &[String]) -> Self {
+        let peer_ip = req.peer_addr().map(|addr| addr.ip());
+        let mut info = ClientInfo {
+            ip: peer_ip,
+            scheme: if req.app_config().secure() { "https" } else { "http" }.to_string(),
+            host: request_host(req),
+        };
+
+        // Unix 套接字连接没有对端地址，只可能来自本机的反向代理
+        let trusted_peer = match peer_ip {
+            Some(ip) => is_trusted(ip, trusted_proxies),
+            None => true,
+        };
+        if !trusted_peer {
+            return info;
+        }
+
+        // 代理在头的末尾追加自己的记录，前面的内容可能是客户端伪造的，
+        // 只使用最近的可信代理为客户端写入的那一条记录
+        let forwarded: Vec<ForwardedElement> = header_values(req, &header::FORWARDED)
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(parse_forwarded_element)
+            .collect();
+
+        if !forwarded.is_empty() {
+            let chain: Vec<Option<IpAddr>> = forwarded.iter().map(|element| element.for_ip).collect();
+            let element = &forwarded[client_hop(&chain, trusted_proxies)];
+            if let Some(ip) = element.for_ip {
+                info.ip = Some(ip);
+            }
+            if let Some(proto) = &element.proto {
+                info.scheme = proto.clone();
+            }
+            if let Some(host) = &element.host {
+                info.host = host.clone();
+            }
+            return info;
+        }
+
+        let chain: Vec<Option<IpAddr>> = list_values(req, &header::X_FORWARDED_FOR)
+            .iter()
+            .map(|value| parse_ip(value))
+            .collect();
+        // 客户端之后经过的可信代理数，X-Forwarded-Proto / X-Forwarded-Host 中对应倒数第 hops + 1 个值
+        let hops = if chain.is_empty() {
+            0
+        } else {
+            let index = client_hop(&chain, trusted_proxies);
+            if let Some(ip) = chain[index] {
+                info.ip = Some(ip);
+            }
+            chain.len() - 1 - index
+        };
+        if let Some(proto) = nth_from_end(&list_values(req, "x-forwarded-proto"), hops) {
+            info.scheme = proto.to_ascii_lowercase();
+        }
+        if let Some(host) = nth_from_end(&list_values(req, &header::X_FORWARDED_HOST), hops) {
+            info.host = host;
+        }
+
+        info
+    }
+
+    // 生成绝对地址使用的基础 URL，优先使用配置的 public_url，否则根据请求的协议和主机名生成
+    pub fn base_url(&self, public_url: Option<&str>) -> String {
+        match public_url {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => format!("{}://{}", self.scheme, self.host),
+        }
+    }
+
+    pub fn ip_string(&self) -> Option<String> {
+        self.ip.map(|ip| ip.to_string())
+    }
+
+    // 不含端口的主机名
+    pub fn hostname(&self) -> &str {
+        if self.host.starts_with('[') {
+            self.host.split_inclusive(']').next().unwrap_or(&self.host)
+        } else {
+            self.host.split(':').next().unwrap_or(&self.host)
+        }
+    }
+}
+
+// 连接本身的 Host 头，HTTP/2 请求使用 URI 中的 authority
+fn request_host(req: &HttpRequest) -> String {
+    req.headers().get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+        .or_else(|| req.uri().authority().map(|authority| authority.to_string()))
+        .unwrap_or_else(|| req.app_config().host().to_string())
+}
+
+fn header_values<K: header::AsHeaderName>(req: &HttpRequest, name: K) -> Vec<String> {
+    req.headers().get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .map(String::from)
+        .collect()
+}
+
+// 逗号分隔的多个头值，按出现顺序展开
+fn list_values<K: header::AsHeaderName>(req: &HttpRequest, name: K) -> Vec<String> {
+    header_values(req, name)
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().to_string())
+        .collect()
+}
+
+// 倒数第 n + 1 个值
+fn nth_from_end(values: &[String], n: usize) -> Option<String> {
+    values.len().checked_sub(n + 1)
+        .map(|index| values[index].clone())
+        .filter(|value| !value.is_empty())
+}
+
+// Forwarded 头中的一个元素，如 for="[2001:db8::1]:4711";proto=https;host=example.com
+#[derive(Debug, Default)]
+struct ForwardedElement {
+    for_ip: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+fn parse_forwarded_element(element: &str) -> ForwardedElement {
+    let mut parsed = ForwardedElement::default();
+
+    for pair in element.split(';') {
+        let Some((key, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+        match key.trim().to_ascii_lowercase().as_str() {
+            "for" => parsed.for_ip = parse_ip(value),
+            "proto" => parsed.proto = Some(value.to_ascii_lowercase()),
+            "host" => parsed.host = Some(value.to_string()),
+            _ => {}
+        }
+    }
+
+    parsed
+}
+
+// 解析地址，支持 1.2.3.4、1.2.3.4:80、2001:db8::1、[2001:db8::1]:80 等形式
+fn parse_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim();
+    if let Ok(ip) = value.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = value.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    value.rsplit_once(':').and_then(|(ip, _)| ip.parse().ok())
+}
+
+// 客户端所在的记录：从右向左跳过可信代理，第一个不可信或无法解析的地址就是客户端；
+// 全部可信时使用最左边的记录
+fn client_hop(chain: &[Option<IpAddr>], trusted_proxies: &[String]) -> usize {
+    chain.iter()
+        .rposition(|ip| !ip.is_some_and(|ip| is_trusted(ip, trusted_proxies)))
+        .unwrap_or(0)
+}
+
+pub fn is_trusted(ip: IpAddr, trusted_proxies: &[String]) -> bool {
+    trusted_proxies.iter().any(|cidr| cidr_contains(cidr, ip))
+}
+
+// 解析 CIDR，如 10.0.0.0/8、::1/128，不带前缀长度时表示单个地址
+pub fn parse_cidr(cidr: &str) -> Result<(IpAddr, u8), String> {
+    let (address, prefix) = match cidr.split_once('/') {
+        Some((address, prefix)) => (address, Some(prefix)),
+        None => (cidr, None),
+    };
+    let address: IpAddr = address.trim().parse()
+        .map_err(|e| format!("无效的地址 {cidr}: {e}"))?;
+    let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix.trim().parse::<u8>()
+            .ok()
+            .filter(|prefix| *prefix <= max_prefix)
+            .ok_or_else(|| format!("无效的前缀长度 {cidr}"))?,
+        None => max_prefix,
+    };
+    Ok((address, prefix))
+}
+
+pub fn cidr_contains(cidr: &str, ip: IpAddr) -> bool {
+    let Ok((network, prefix)) = parse_cidr(cidr) else {
+        return false;
+    };
+
+    // IPv4 映射的 IPv6 地址（::ffff:1.2.3.4）按 IPv4 处理
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    };
+
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            u32::from(network) & mask == u32::from(ip) & mask
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            u128::from(network) & mask == u128::from(ip) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn client(peer: &str, headers: &[(&str, &str)]) -> ClientInfo {
+        let mut request = TestRequest::default()
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(("Host", "internal:8080"));
+        for header in headers {
+            request = request.append_header(*header);
+        }
+        let trusted = vec!["10.0.0.0/8".to_string()];
+        ClientInfo::from_request(&request.to_http_request(), &trusted)
+    }
+
+    #[test]
+    fn untrusted_peer_ignores_forwarded_headers() {
+        let info = client("203.0.113.7:1234", &[
+            ("X-Forwarded-For", "1.2.3.4"),
+            ("X-Forwarded-Proto", "https"),
+            ("X-Forwarded-Host", "evil.example"),
+            ("Forwarded", "for=1.2.3.4;proto=https;host=evil.example"),
+        ]);
+        assert_eq!(info.ip, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(info.scheme, "http");
+        assert_eq!(info.host, "internal:8080");
+    }
+
+    #[test]
+    fn spoofed_leftmost_forwarded_element_is_ignored() {
+        let info = client("10.0.0.1:1234", &[
+            ("Forwarded", "for=6.6.6.6;proto=http;host=evil.example, for=198.51.100.2;proto=https;host=registry.example.com"),
+        ]);
+        assert_eq!(info.ip, Some("198.51.100.2".parse().unwrap()));
+        assert_eq!(info.scheme, "https");
+        assert_eq!(info.host, "registry.example.com");
+
+        // 可信代理的记录没有 host 时，客户端伪造的 host 不能生效
+        let info = client("10.0.0.1:1234", &[
+            ("Forwarded", "for=6.6.6.6;host=evil.example, for=198.51.100.2"),
+        ]);
+        assert_eq!(info.host, "internal:8080");
+    }
+
+    #[test]
+    fn spoofed_leftmost_x_forwarded_values_are_ignored() {
+        let info = client("10.0.0.1:1234", &[
+            ("X-Forwarded-For", "6.6.6.6, 198.51.100.2"),
+            ("X-Forwarded-Proto", "http, https"),
+            ("X-Forwarded-Host", "evil.example, registry.example.com"),
+        ]);
+        assert_eq!(info.ip, Some("198.51.100.2".parse().unwrap()));
+        assert_eq!(info.scheme, "https");
+        assert_eq!(info.host, "registry.example.com");
+
+        let info = client("10.0.0.1:1234", &[
+            ("X-Forwarded-For", "198.51.100.2"),
+            ("X-Forwarded-Host", "evil.example"),
+            ("X-Forwarded-Host", "registry.example.com"),
+        ]);
+        assert_eq!(info.host, "registry.example.com");
+
+        // 两层可信代理都没有写入 X-Forwarded-Host，唯一的值来自客户端
+        let info = client("10.0.0.1:1234", &[
+            ("X-Forwarded-For", "6.6.6.6, 198.51.100.2, 10.0.0.2"),
+            ("X-Forwarded-Host", "evil.example"),
+        ]);
+        assert_eq!(info.ip, Some("198.51.100.2".parse().unwrap()));
+        assert_eq!(info.host, "internal:8080");
+    }
+
+    #[test]
+    fn multi_hop_chain_uses_nearest_trusted_hop() {
+        // 客户端 -> 10.0.0.2（写入第二条）-> 10.0.0.1（写入第三条）-> docxy
+        let info = client("10.0.0.1:1234", &[
+            ("Forwarded", "for=6.6.6.6;host=evil.example"),
+            ("Forwarded", "for=198.51.100.2;proto=https;host=registry.example.com, for=10.0.0.2;proto=http;host=edge.internal"),
+        ]);
+        assert_eq!(info.ip, Some("198.51.100.2".parse().unwrap()));
+        assert_eq!(info.scheme, "https");
+        assert_eq!(info.host, "registry.example.com");
+
+        let info = client("10.0.0.1:1234", &[
+            ("X-Forwarded-For", "198.51.100.2, 10.0.0.2"),
+            ("X-Forwarded-Proto", "https, http"),
+        ]);
+        assert_eq!(info.ip, Some("198.51.100.2".parse().unwrap()));
+        assert_eq!(info.scheme, "https");
+    }
+
+    #[test]
+    fn parse_cidr_prefixes() {
+        assert_eq!(parse_cidr("10.0.0.0/8"), Ok(("10.0.0.0".parse().unwrap(), 8)));
+        assert_eq!(parse_cidr("2001:db8::/32"), Ok(("2001:db8::".parse().unwrap(), 32)));
+        assert_eq!(parse_cidr("::1"), Ok(("::1".parse().unwrap(), 128)));
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+        assert!(parse_cidr("2001:db8::/129").is_err());
+        assert!(parse_cidr("not-an-ip/8").is_err());
+    }
+
+    #[test]
+    fn cidr_contains_addresses() {
+        assert!(cidr_contains("10.0.0.0/8", "10.255.0.1".parse().unwrap()));
+        assert!(!cidr_contains("10.0.0.0/8", "11.0.0.1".parse().unwrap()));
+        assert!(cidr_contains("0.0.0.0/0", "203.0.113.1".parse().unwrap()));
+        assert!(cidr_contains("2001:db8::/32", "2001:db8:ffff::1".parse().unwrap()));
+        assert!(!cidr_contains("2001:db8::/32", "2001:db9::1".parse().unwrap()));
+        assert!(cidr_contains("fe80::/10", "febf::1".parse().unwrap()));
+        assert!(!cidr_contains("fe80::/10", "fec0::1".parse().unwrap()));
+        // IPv4 映射的 IPv6 地址按 IPv4 匹配
+        assert!(cidr_contains("127.0.0.1/32", "::ffff:127.0.0.1".parse().unwrap()));
+        assert!(!cidr_contains("::/0", "127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_ip_forms() {
+        assert_eq!(parse_ip("1.2.3.4:80"), Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(parse_ip("[2001:db8::1]:4711"), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(parse_ip("2001:db8::1"), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(parse_ip("unknown"), None);
+    }
+}
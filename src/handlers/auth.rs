@@ -4,35 +4,50 @@ use log::{info, debug, error, warn};
 use serde_json::json;
 
 use crate::error::AppError;
-use crate::HTTP_CLIENT;
-use crate::config::{Settings, RegistryConfig};
+use crate::config::{Settings, SharedSettings, RegistryConfig, RegistryCredential, RegistrySettings, RetrySettings};
 use crate::auth_utils;
+use crate::upstream;
+use crate::ratelimit;
+use crate::routing::{get_target_registry, RouteHints};
+use crate::forwarded::ClientInfo;
 
 // 获取 Token 的处理函数
 pub async fn get_token(req: HttpRequest) -> Result<HttpResponse, AppError> {
-    let settings = req.app_data::<web::Data<Settings>>().unwrap();
+    let current_settings = req.app_data::<web::Data<SharedSettings>>().unwrap().load();
+    let settings = &*current_settings;
+    check_rate_limit(&req, settings)?;
     
     // 1. 尝试解析查询参数，失败则返回 400
-    let query_params = match web::Query::<HashMap<String, String>>::from_query(req.query_string()) {
-        Ok(q) => q,
+    let query_pairs = match web::Query::<Vec<(String, String)>>::from_query(req.query_string()) {
+        Ok(q) => q.into_inner(),
         Err(_) => {
             return Err(AppError::InvalidRequest("无效的查询参数".to_string()));
         }
     };
+    let query_params = web::Query(query_pairs.iter().cloned().collect::<HashMap<String, String>>());
 
     // 检查自定义认证是否启用
     if settings.auth.enabled {
-        return handle_custom_auth(req.clone(), &settings, query_params).await;
+        return handle_custom_auth(req.clone(), settings, &query_pairs, query_params).await;
     }
     
     // 如果未启用自定义认证，则使用默认的 Docker Hub 认证转发
-    handle_default_auth(&settings, &query_params, &req).await
+    handle_default_auth(settings, &query_params, &req).await
+}
+
+// 按用户和客户端 IP 限制请求速率，与镜像请求共用计数；认证失败的请求也按 IP 计数
+fn check_rate_limit(req: &HttpRequest, settings: &Settings) -> Result<(), AppError> {
+    let authorization = req.headers().get("Authorization").and_then(|auth| auth.to_str().ok());
+    let user = auth_utils::authenticated_user(&settings.auth, authorization);
+    let client = ClientInfo::from_request(req, &settings.server.trusted_proxies);
+    ratelimit::check_client(&settings.rate_limit, user.as_deref(), client.ip_string().as_deref())
 }
 
 // 处理自定义认证逻辑
 async fn handle_custom_auth(
     req: HttpRequest, 
     settings: &Settings,
+    query_pairs: &[(String, String)],
     query_params: web::Query<HashMap<String, String>>
 ) -> Result<HttpResponse, AppError> {
     // 解析认证头
@@ -49,76 +64,51 @@ async fn handle_custom_auth(
                             Some(user)
                         } else {
                             warn!("用户 {} 认证失败: 密码不正确", user);
-                            return Ok(HttpResponse::Unauthorized()
-                                .append_header(("WWW-Authenticate", "Basic realm=\"Docxy Registry\""))
-                                .body("认证失败: 用户名或密码不正确"));
+                            return Err(AppError::Unauthorized("incorrect username or password".to_string()));
                         }
                     } else {
                         warn!("没有配置用户");
-                        return Ok(HttpResponse::Unauthorized()
-                            .append_header(("WWW-Authenticate", "Basic realm=\"Docxy Registry\""))
-                            .body("认证失败: 没有配置用户"));
+                        return Err(AppError::Unauthorized("no users configured".to_string()));
                     }
                 } else {
                     warn!("无法解析 Basic 认证头");
-                    return Ok(HttpResponse::Unauthorized()
-                        .append_header(("WWW-Authenticate", "Basic realm=\"Docxy Registry\""))
-                        .body("认证失败: 认证格式不正确"));
+                    return Err(AppError::Unauthorized("malformed Basic credentials".to_string()));
                 }
             } else {
                 warn!("无法读取认证头");
-                return Ok(HttpResponse::Unauthorized()
-                    .append_header(("WWW-Authenticate", "Basic realm=\"Docxy Registry\""))
-                    .body("认证失败: 认证头格式不正确"));
+                return Err(AppError::Unauthorized("malformed Authorization header".to_string()));
             }
         },
         None => {
             // 没有认证头，返回 401
-            return Ok(HttpResponse::Unauthorized()
-                .append_header(("WWW-Authenticate", "Basic realm=\"Docxy Registry\""))
-                .body("认证失败: 需要认证"));
+            return Err(AppError::Unauthorized("credentials required".to_string()));
         }
     };
 
     if let Some(user) = username {
-        // 检查是否有 scope 参数，判断需要访问哪个注册表
-        if let Some(scope) = query_params.get("scope") {
-            // 从 scope 中提取注册表信息
-            if let Some(registry_key) = extract_registry_from_scope(scope) {
-                info!("从 scope {} 提取到注册表: {}", scope, registry_key);
-                
-                // 查找用户对此注册表的凭据
-                let users = &settings.auth.users;
-                if !users.is_empty() {
-                    if let Some(registry_cred) = auth_utils::get_registry_credentials(&user, &registry_key, users) {
-                        info!("用户 {} 有 {} 注册表的凭据，尝试获取上游 token", user, registry_key);
-                        
-                        // 获取注册表配置
-                        let registries = &settings.registry.registries;
-                        if !registries.is_empty() {
-                            if let Some(registry_config) = registries.get(&registry_key) {
-                                // 为 v2 注册表获取上游 token
-                                if matches!(registry_config.api_version, crate::config::RegistryApiVersion::V2) {
-                                    return get_upstream_v2_token(
-                                        registry_config,
-                                        registry_cred,
-                                        scope,
-                                        &query_params
-                                    ).await;
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+        // 收集所有 scope 参数（可能重复出现，也可能以空格分隔多个 scope）
+        let scopes: Vec<String> = query_pairs.iter()
+            .filter(|(k, _)| k == "scope")
+            .flat_map(|(_, v)| auth_utils::parse_scope(v))
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        // 按目标注册表对 scope 分组，使用与 handle_request 相同的解析逻辑
+        let client = ClientInfo::from_request(&req, &settings.server.trusted_proxies);
+        let groups = resolve_scopes(&settings.registry, &RouteHints::from_request(&req, &client), &scopes);
+        if let Some(target) = upstream_token_target(settings, &user, &groups)? {
+            info!("用户 {} 有 {} 注册表的凭据，尝试获取上游 token", user, target.registry_key);
+            return get_upstream_v2_token(
+                target.registry_key,
+                &target.registry_config,
+                target.registry_cred,
+                target.scopes,
+                &query_params,
+                &settings.registry.retry,
+            ).await;
         }
-        
-        // 如果没有找到特定的注册表配置，生成本地 token
-        let scopes = match query_params.get("scope") {
-            Some(scope) => auth_utils::parse_scope(scope),
-            None => Vec::new(),
-        };
 
+        // 如果没有找到特定的注册表配置，生成本地 token
         let token_response = auth_utils::generate_docker_token(&user, &scopes);
         return Ok(HttpResponse::Ok()
             .content_type("application/json")
@@ -126,26 +116,38 @@ async fn handle_custom_auth(
     }
 
     // 不应该到达这里
-    Ok(HttpResponse::InternalServerError().body("认证处理错误"))
+    Err(AppError::Unauthorized("credentials required".to_string()))
 }
 
 // 处理默认认证（未启用自定义认证时）
 async fn handle_default_auth(
-    _settings: &Settings,
+    settings: &Settings,
     query_params: &web::Query<HashMap<String, String>>,
     req: &HttpRequest
 ) -> Result<HttpResponse, AppError> {
-    // 构建 Docker Hub 认证服务 URL
-    let mut auth_url = reqwest::Url::parse("https://auth.docker.io/token").unwrap();
+    // 根据请求的 Host 选择认证服务，默认为 Docker Hub
+    let client = ClientInfo::from_request(req, &settings.server.trusted_proxies);
+    let (_, _, registry_key) = get_target_registry(&settings.registry, &RouteHints::from_request(req, &client), "");
+    let registry_config = registry_config_for(settings, &registry_key);
+    let auth_url = registry_config.as_ref()
+        .map(|config| config.auth_url.clone().unwrap_or_else(|| format!("{}/token", config.url)))
+        .unwrap_or_else(|| "https://auth.docker.io/token".to_string());
+    let service = registry_config.as_ref()
+        .and_then(|config| config.service.clone())
+        .or_else(|| query_params.get("service").cloned())
+        .unwrap_or_else(|| "registry.docker.io".to_string());
+
+    // 构建认证服务 URL
+    let mut auth_url = reqwest::Url::parse(&auth_url)
+        .map_err(|e| AppError::InvalidRequest(format!("无效的认证 URL: {}", e)))?;
     {
         let mut query_pairs = auth_url.query_pairs_mut();
-        // service 必须是 registry.docker.io
-        query_pairs.append_pair("service", "registry.docker.io");
+        query_pairs.append_pair("service", &service);
 
         // 透传所有客户端提供的查询参数（包含 account、client_id、offline_token、scope 等）
-        // 避免重复 service
+        // 避免重复 service，ns 只用于本地选择注册表
         for (k, v) in query_params.iter() {
-            if k != "service" {
+            if k != "service" && k != "ns" {
                 query_pairs.append_pair(k, v);
             }
         }
@@ -154,18 +156,17 @@ async fn handle_default_auth(
     info!("转发 token 请求至: {}", auth_url);
 
     // 构造向上游的请求构建器
-    let mut request_builder = HTTP_CLIENT.get(auth_url.clone());
+    let mut request_builder = upstream::client_for(&registry_key).get(auth_url.clone());
 
     // 检查并代理 Authorization 头
-    if let Some(auth_header) = req.headers().get("Authorization") {
-        if let Ok(auth_str) = auth_header.to_str() {
-            info!("代理 Authorization 头: {}", auth_str);
-            request_builder = request_builder.header("Authorization", auth_str);
-        }
+    if let Some(auth_header) = req.headers().get("Authorization")
+        && let Ok(auth_str) = auth_header.to_str() {
+        info!("代理 Authorization 头: {}", auth_str);
+        request_builder = request_builder.header("Authorization", auth_str);
     }
 
-    // 发送请求到 Docker Hub 认证服务
-    let response = match request_builder.send().await {
+    // 发送请求到上游认证服务
+    let response = match upstream::send_with_retry(request_builder, &settings.registry.retry).await {
         Ok(resp) => {
             info!("GET {} {:?} {} {}", 
                 auth_url, 
@@ -176,8 +177,7 @@ async fn handle_default_auth(
         },
         Err(e) => {
             error!("GET {} {:?} 失败: {}", auth_url, req.version(), e);
-            return Ok(HttpResponse::InternalServerError()
-                .body("无法连接到 Docker Hub 认证服务"))
+            return Err(AppError::UpstreamRequest(e));
         }
     };
 
@@ -205,22 +205,23 @@ async fn handle_default_auth(
         },
         Err(e) => {
             error!("读取认证服务响应失败: {}", e);
-            Ok(HttpResponse::InternalServerError().body("无法读取认证服务响应"))
+            Err(AppError::UpstreamRequest(e))
         }
     }
 }
 
 // 为 v2 注册表获取上游 token
 async fn get_upstream_v2_token(
+    registry_key: &str,
     registry_config: &RegistryConfig,
-    registry_cred: &crate::config::RegistryCredential,
-    scope: &str,
-    query_params: &web::Query<HashMap<String, String>>
+    registry_cred: &RegistryCredential,
+    scopes: &[String],
+    query_params: &web::Query<HashMap<String, String>>,
+    retry: &RetrySettings,
 ) -> Result<HttpResponse, AppError> {
     // 确定认证 URL
     let auth_url = registry_config.auth_url
-        .as_ref()
-        .map(|url| url.clone())
+        .clone()
         .unwrap_or_else(|| format!("{}/token", registry_config.url));
     
     info!("向上游注册表请求 token: {}", auth_url);
@@ -232,15 +233,17 @@ async fn get_upstream_v2_token(
     {
         let mut query_pairs = url.query_pairs_mut();
         
-        // 添加基本参数
-        if let Some(service) = query_params.get("service") {
+        // 添加基本参数，优先使用注册表配置的 service
+        if let Some(service) = registry_config.service.as_ref().or(query_params.get("service")) {
             query_pairs.append_pair("service", service);
         }
-        query_pairs.append_pair("scope", scope);
+        for scope in scopes {
+            query_pairs.append_pair("scope", scope);
+        }
         
-        // 透传其他参数
+        // 透传其他参数，ns 只用于本地选择注册表
         for (key, value) in query_params.iter() {
-            if key != "service" && key != "scope" {
+            if key != "service" && key != "scope" && key != "ns" {
                 query_pairs.append_pair(key, value);
             }
         }
@@ -250,20 +253,17 @@ async fn get_upstream_v2_token(
     let auth_header = auth_utils::create_basic_auth(&registry_cred.username, &registry_cred.password);
     
     // 发送请求到上游认证服务
-    let response = match HTTP_CLIENT
+    let request_builder = upstream::client_for(registry_key)
         .get(url.as_str())
-        .header("Authorization", auth_header)
-        .send()
-        .await
-    {
+        .header("Authorization", auth_header);
+    let response = match upstream::send_with_retry(request_builder, retry).await {
         Ok(resp) => {
             info!("上游 token 请求响应: {}", resp.status());
             resp
         },
         Err(e) => {
             error!("上游 token 请求失败: {}", e);
-            return Ok(HttpResponse::InternalServerError()
-                .body("无法连接到上游认证服务"));
+            return Err(AppError::UpstreamRequest(e));
         }
     };
     
@@ -283,45 +283,115 @@ async fn get_upstream_v2_token(
         Ok(bytes) => Ok(builder.body(bytes)),
         Err(e) => {
             error!("读取上游认证响应失败: {}", e);
-            Ok(HttpResponse::InternalServerError().body("无法读取上游认证响应"))
+            Err(AppError::UpstreamRequest(e))
         }
     }
 }
 
-// 从 scope 中提取注册表名称
-fn extract_registry_from_scope(scope: &str) -> Option<String> {
-    // scope 格式通常是: repository:namespace/repo:pull,push
-    // 对于 ghcr.io: repository:user/repo:pull
-    // 对于 docker.io: repository:user/repo:pull
-    
-    if scope.starts_with("repository:") {
-        let repo_part = &scope[11..]; // 跳过 "repository:"
-        
-        // 检查是否包含明确的注册表前缀
-        if repo_part.contains("/") {
-            let parts: Vec<&str> = repo_part.split('/').collect();
-            if parts.len() >= 2 {
-                let first_part = parts[0];
-                
-                // 如果第一部分包含点号，可能是注册表域名
-                if first_part.contains('.') {
-                    return Some(first_part.to_string());
-                }
-                
-                // 检查是否是已知的命名空间模式
-                // GitHub Container Registry 使用用户名/组织名作为命名空间
-                // 但没有明确的注册表前缀，需要通过其他方式判断
+// 将 scope 按目标注册表分组，并把仓库名改写为上游注册表中的名称
+// 返回 (注册表键, 目标注册表地址, scope 列表)
+fn resolve_scopes(registry_settings: &RegistrySettings, hints: &RouteHints, scopes: &[String]) -> Vec<(String, String, Vec<String>)> {
+    let mut groups: Vec<(String, String, Vec<String>)> = Vec::new();
+
+    for scope in scopes {
+        // scope 格式通常是: repository:namespace/repo:pull,push
+        // 仓库名可能包含端口号，因此从右侧拆分 actions
+        let (target_registry, registry_key, resolved) = match scope.strip_prefix("repository:").and_then(|rest| rest.rsplit_once(':')) {
+            Some((name, actions)) => {
+                let (target_registry, remapped_name, registry_key) = get_target_registry(registry_settings, hints, name);
+                (target_registry, registry_key, format!("repository:{remapped_name}:{actions}"))
+            },
+            None => {
+                // 其他类型的 scope（如 registry:catalog:*）归属默认注册表
+                let (target_registry, _, registry_key) = get_target_registry(registry_settings, hints, "");
+                (target_registry, registry_key, scope.clone())
             }
+        };
+
+        match groups.iter_mut().find(|(key, target, _)| *key == registry_key && *target == target_registry) {
+            Some((_, _, group)) => group.push(resolved),
+            None => groups.push((registry_key, target_registry, vec![resolved])),
         }
     }
-    
-    // 默认返回 None，让调用者决定如何处理
+
+    groups
+}
+
+// 获取上游 token 使用的注册表、凭据和 scope
+struct UpstreamTokenTarget<'a> {
+    registry_key: &'a str,
+    registry_config: RegistryConfig,
+    registry_cred: &'a RegistryCredential,
+    scopes: &'a [String],
+}
+
+// 选择获取上游 token 的注册表
+// 上游 token 只对一个注册表有效，需要上游 token 的 scope 涉及多个注册表时拒绝请求，由客户端按注册表分别请求
+fn upstream_token_target<'a>(
+    settings: &'a Settings,
+    user: &str,
+    groups: &'a [(String, String, Vec<String>)],
+) -> Result<Option<UpstreamTokenTarget<'a>>, AppError> {
+    let mut target = None;
+
+    for (registry_key, target_registry, registry_scopes) in groups {
+        info!("scope {:?} 解析到注册表: {}", registry_scopes, registry_key);
+
+        // 查找用户对此注册表的凭据
+        let Some(registry_cred) = auth_utils::get_registry_credentials(user, registry_key, &settings.auth.users) else {
+            debug!("用户 {} 没有 {} 注册表的凭据", user, registry_key);
+            continue;
+        };
+
+        match registry_config_for(settings, registry_key) {
+            Some(mut registry_config) if registry_config.api_version == crate::config::RegistryApiVersion::V2 => {
+                // 通配符注册表使用匹配到的主机地址
+                registry_config.url = target_registry.clone();
+                if target.is_none() {
+                    target = Some(UpstreamTokenTarget { registry_key, registry_config, registry_cred, scopes: registry_scopes });
+                }
+            },
+            Some(_) => debug!("注册表 {} 不是 v2 API，无法获取上游 token", registry_key),
+            None => debug!("注册表 {} 没有配置", registry_key),
+        }
+    }
+
+    if target.is_some() && groups.len() > 1 {
+        let keys: Vec<&str> = groups.iter().map(|(_, target_registry, _)| target_registry.as_str()).collect();
+        warn!("用户 {} 请求的 scope 涉及多个注册表: {:?}", user, keys);
+        return Err(AppError::InvalidRequest(format!(
+            "scopes span multiple registries ({}); request a separate token for each registry",
+            keys.join(", "),
+        )));
+    }
+
+    Ok(target)
+}
+
+// 获取注册表配置，未显式配置的默认注册表使用 Docker Hub 的认证服务
+fn registry_config_for(settings: &Settings, registry_key: &str) -> Option<RegistryConfig> {
+    if let Some(registry_config) = settings.registry.registries.get(registry_key) {
+        return Some(registry_config.clone());
+    }
+
+    if registry_key == "docker.io" {
+        return Some(RegistryConfig {
+            url: settings.registry.upstream_registry.clone(),
+            api_version: crate::config::RegistryApiVersion::V2,
+            auth_url: Some("https://auth.docker.io/token".to_string()),
+            service: Some("registry.docker.io".to_string()),
+            ..Default::default()
+        });
+    }
+
     None
 }
 
 // 处理 /v2/ 路径的认证挑战
 pub async fn proxy_challenge(req: HttpRequest) -> Result<HttpResponse, AppError> {
-    let settings = req.app_data::<web::Data<Settings>>().unwrap();
+    let current_settings = req.app_data::<web::Data<SharedSettings>>().unwrap().load();
+    let settings = &*current_settings;
+    check_rate_limit(&req, settings)?;
 
     // 检查是否启用自定义认证
     if settings.auth.enabled {
@@ -354,50 +424,66 @@ pub async fn proxy_challenge(req: HttpRequest) -> Result<HttpResponse, AppError>
         
         // 认证失败或没有认证头，发送认证挑战
         info!("发送Basic认证挑战");
-        return Ok(HttpResponse::Unauthorized()
-            .append_header(("WWW-Authenticate", "Basic realm=\"Docker Registry\""))
-            .body(json!({
-                "errors": [{
-                    "code": "UNAUTHORIZED",
-                    "message": "authentication required",
-                    "detail": null
-                }]
-            }).to_string()));
+        return Err(AppError::Unauthorized("credentials required".to_string()));
     }
 
     // 如果未启用自定义认证，则使用默认的代理认证挑战
-    let upstream_registry = &settings.registry.upstream_registry;
-    let host = match req.connection_info().host() {
-        host if host.contains(':') => host.to_string(),
-        host => host.to_string()
-    };
-    let request_url = format!("{upstream_registry}/v2/");
-    
-    // 构建请求，检查是否有 Authorization 头
-    let mut request_builder = HTTP_CLIENT.get(&request_url);
-    
-    // 如果客户端提供了 Authorization 头，转发给上游
-    if let Some(auth) = req.headers().get("Authorization") {
-        if let Ok(auth_str) = auth.to_str() {
+    // 认证地址优先使用 public_url，否则使用客户端原始请求的协议和主机名（经过可信代理时）
+    let client = ClientInfo::from_request(&req, &settings.server.trusted_proxies);
+    // 根据请求的 Host 选择上游注册表，按顺序尝试镜像端点，连接失败或 5xx 时切换到下一个端点
+    let hints = RouteHints::from_request(&req, &client);
+    let (upstream_registry, _, registry_key) = get_target_registry(&settings.registry, &hints, "");
+    let endpoints = upstream::available_endpoints(
+        &upstream::registry_endpoints(&settings.registry, &registry_key, &upstream_registry),
+        &settings.registry.failover,
+    );
+    let mut upstream_response = None;
+    let mut last_error = None;
+
+    for (index, endpoint) in endpoints.iter().enumerate() {
+        let request_url = format!("{endpoint}/v2/");
+
+        // 构建请求，检查是否有 Authorization 头
+        let mut request_builder = upstream::client_for(&registry_key).get(&request_url);
+
+        // 如果客户端提供了 Authorization 头，转发给上游
+        if let Some(auth) = req.headers().get("Authorization")
+            && let Ok(auth_str) = auth.to_str() {
             info!("代理 Authorization 头到 /v2/: {}", auth_str);
             request_builder = request_builder.header("Authorization", auth_str);
         }
-    }
 
-    let response = match request_builder.send().await {
-        Ok(resp) => {
-            info!("GET {} {:?} {} {}", 
-                request_url,
-                req.version(), 
-                resp.status().as_u16(), 
-                resp.status().canonical_reason().unwrap_or("Unknown"));
-            resp
-        },
-        Err(e) => {
-            error!("GET {} {:?} 失败: {}", request_url, req.version(), e);
-            return Ok(HttpResponse::InternalServerError()
-                      .body("无法连接到上游 Docker Registry"))
+        match upstream::send_with_retry(request_builder, &settings.registry.retry).await {
+            Ok(resp) => {
+                info!("GET {} {:?} {} {}", 
+                    request_url,
+                    req.version(), 
+                    resp.status().as_u16(), 
+                    resp.status().canonical_reason().unwrap_or("Unknown"));
+
+                if upstream::is_failover_status(resp.status()) {
+                    upstream::record_failure(endpoint, &settings.registry.failover);
+                    if index + 1 < endpoints.len() {
+                        warn!("端点 {} 返回 {}，切换到下一个镜像端点", endpoint, resp.status().as_u16());
+                        continue;
+                    }
+                } else {
+                    upstream::record_success(endpoint);
+                }
+                upstream_response = Some(resp);
+                break;
+            },
+            Err(e) => {
+                error!("GET {} {:?} 失败: {}", request_url, req.version(), e);
+                upstream::record_failure(endpoint, &settings.registry.failover);
+                last_error = Some(e);
+            }
         }
+    }
+
+    let Some(response) = upstream_response else {
+        return Err(last_error.map(AppError::UpstreamRequest)
+            .unwrap_or_else(|| AppError::UpstreamUnavailable("no available upstream endpoint".to_string())));
     };
 
     let status = response.status().as_u16();
@@ -405,11 +491,17 @@ pub async fn proxy_challenge(req: HttpRequest) -> Result<HttpResponse, AppError>
 
     // 只有在返回 401 时才设置 WWW-Authenticate 头
     if status == 401 {
-        let protocol = if settings.server.https_enabled { "https" } else { "http" };
-        let auth_header = format!(
-            "Bearer realm=\"{}://{}/auth/token\",service=\"registry.docker.io\"",
-            protocol, host
-        );
+        let service = registry_config_for(settings, &registry_key)
+            .and_then(|config| config.service)
+            .unwrap_or_else(|| "registry.docker.io".to_string());
+        // containerd 的 ns 参数写入认证地址，token 请求按同一个注册表解析
+        let mut realm = format!("{}/auth/token", client.base_url(settings.server.public_url.as_deref()));
+        if let Some(namespace) = &hints.namespace
+            && let Ok(mut url) = reqwest::Url::parse(&realm) {
+            url.query_pairs_mut().append_pair("ns", namespace);
+            realm = url.to_string();
+        }
+        let auth_header = format!("Bearer realm=\"{realm}\",service=\"{service}\"");
         info!("设置认证头: {}", auth_header);
         
         builder.append_header((
@@ -418,13 +510,10 @@ pub async fn proxy_challenge(req: HttpRequest) -> Result<HttpResponse, AppError>
         ));
     }
 
-    let body = match response.text().await {
-        Ok(text) => text,
-        Err(e) => {
-            error!("读取上游响应内容失败: {}", e);
-            String::from("无法读取上游响应内容")
-        }
-    };
+    let body = response.text().await.map_err(|e| {
+        error!("读取上游响应内容失败: {}", e);
+        AppError::UpstreamRequest(e)
+    })?;
 
     info!("{} {} {:?} {} {}", 
         req.method(), 
@@ -435,3 +524,84 @@ pub async fn proxy_challenge(req: HttpRequest) -> Result<HttpResponse, AppError>
     
     Ok(builder.body(body))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn settings() -> Settings {
+        serde_json::from_value(json!({
+            "server": {"http_port": 80, "https_port": 443, "http_enabled": true, "https_enabled": false, "behind_proxy": false},
+            "registry": {
+                "upstream_registry": "https://registry-1.docker.io",
+                "registries": {"ghcr.io": {"url": "https://ghcr.io", "api_version": "v2", "auth_url": null}},
+            },
+            "tls": {},
+            "auth": {"enabled": true, "users": {
+                "alice": {"password": "secret", "registry_credentials": {"ghcr.io": {"username": "alice", "password": "token"}}},
+                "bob": {"password": "secret"},
+            }},
+        })).unwrap()
+    }
+
+    fn scopes(scopes: &[&str]) -> Vec<String> {
+        scopes.iter().map(|scope| scope.to_string()).collect()
+    }
+
+    #[test]
+    fn scopes_are_grouped_by_registry() {
+        let settings = settings();
+        let groups = resolve_scopes(&settings.registry, &RouteHints::default(), &scopes(&[
+            "repository:nginx:pull",
+            "repository:ghcr.io/org/app:pull",
+            "registry:catalog:*",
+        ]));
+        assert_eq!(groups, [
+            ("docker.io".to_string(), "https://registry-1.docker.io".to_string(), scopes(&["repository:library/nginx:pull", "registry:catalog:*"])),
+            ("ghcr.io".to_string(), "https://ghcr.io".to_string(), scopes(&["repository:org/app:pull"])),
+        ]);
+    }
+
+    #[test]
+    fn upstream_token_covers_a_single_registry() {
+        let settings = settings();
+        let groups = resolve_scopes(&settings.registry, &RouteHints::default(), &scopes(&["repository:ghcr.io/org/app:pull"]));
+        let target = upstream_token_target(&settings, "alice", &groups).unwrap().unwrap();
+        assert_eq!(target.registry_key, "ghcr.io");
+        assert_eq!(target.registry_config.url, "https://ghcr.io");
+        assert_eq!(target.registry_cred.username, "alice");
+        assert_eq!(target.scopes, ["repository:org/app:pull"]);
+    }
+
+    #[test]
+    fn mixed_registries_are_rejected_when_an_upstream_token_is_needed() {
+        let settings = settings();
+        let groups = resolve_scopes(&settings.registry, &RouteHints::default(), &scopes(&[
+            "repository:ghcr.io/org/app:pull",
+            "repository:nginx:pull",
+        ]));
+        assert!(matches!(upstream_token_target(&settings, "alice", &groups), Err(AppError::InvalidRequest(_))));
+
+        // 没有上游凭据时只生成本地 token，不受影响
+        assert!(matches!(upstream_token_target(&settings, "bob", &groups), Ok(None)));
+    }
+
+    #[actix_web::test]
+    async fn token_and_challenge_requests_are_rate_limited() {
+        let mut settings = settings();
+        settings.rate_limit.enabled = true;
+        settings.rate_limit.per_ip_requests_per_minute = 2;
+        let request = || actix_web::test::TestRequest::get()
+            .uri("/auth/token")
+            .peer_addr("192.0.2.40:50000".parse().unwrap())
+            .app_data(web::Data::new(SharedSettings::new(settings.clone())))
+            .to_http_request();
+
+        // 认证失败的请求同样计数
+        assert!(matches!(get_token(request()).await, Err(AppError::Unauthorized(_))));
+        assert!(matches!(proxy_challenge(request()).await, Err(AppError::Unauthorized(_))));
+        assert!(matches!(get_token(request()).await, Err(AppError::TooManyRequests(..))));
+        assert!(matches!(proxy_challenge(request()).await, Err(AppError::TooManyRequests(..))));
+    }
+}
@@ -1,23 +1,51 @@
-use actix_web::{HttpRequest, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
 use log::{info, warn};
 
-// 处理非法请求的函数
-pub async fn handle_invalid_request(req: HttpRequest) -> HttpResponse {
+use crate::acme;
+use crate::config::SharedSettings;
+use crate::error::AppError;
+use crate::forwarded::ClientInfo;
+
+// 请求的客户端信息，经过可信代理时使用转发的地址和主机名
+fn client_info(req: &HttpRequest) -> ClientInfo {
+    let trusted_proxies = req.app_data::<web::Data<SharedSettings>>()
+        .map(|settings| settings.load().server.trusted_proxies.clone())
+        .unwrap_or_default();
+    ClientInfo::from_request(req, &trusted_proxies)
+}
+
+// 处理非法请求的函数：已知路径使用了不支持的方法时返回 405，否则返回 404
+pub async fn handle_invalid_request(req: HttpRequest) -> Result<HttpResponse, AppError> {
     let path = req.uri().path();
-    warn!("拦截非法请求: {} {}", req.method(), path);
-    
-    HttpResponse::Forbidden()
-        .content_type("text/plain; charset=utf-8")
-        .body("非法访问路径")
+    let client = client_info(&req);
+    warn!("拦截非法请求: {} {} {}", client.ip_string().unwrap_or_default(), req.method(), path);
+
+    let allow = match path {
+        "/v2/" | "/auth/token" | "/health" => Some("GET"),
+        // 与 /v2/{image_name}/{path_type}/{reference} 路由相同的路径
+        _ if path.strip_prefix("/v2/").is_some_and(|rest| rest.split('/').filter(|s| !s.is_empty()).count() >= 3) => Some("GET, HEAD"),
+        _ => None,
+    };
+
+    match allow {
+        Some(allow) => Err(AppError::MethodNotAllowed { method: req.method().to_string(), allow }),
+        None => Err(AppError::UnknownRoute(path.to_string())),
+    }
 }
 
 // 新增HTTP到HTTPS的重定向处理函数
 pub async fn redirect_to_https(req: HttpRequest) -> HttpResponse {
-    let host = req.connection_info().host().split(':').next().unwrap_or("").to_string();
+    let client = client_info(&req);
+    let public_url = req.app_data::<web::Data<SharedSettings>>()
+        .and_then(|settings| settings.load().server.public_url.clone())
+        .filter(|url| url.starts_with("https://"));
     let uri = req.uri().to_string();
     
-    // 构建重定向URL (HTTP -> HTTPS)
-    let redirect_url = format!("https://{host}{uri}");
+    // 构建重定向URL (HTTP -> HTTPS)，配置了 HTTPS 的 public_url 时重定向到该地址
+    let redirect_url = match public_url {
+        Some(url) => format!("{}{uri}", url.trim_end_matches('/')),
+        None => format!("https://{}{uri}", client.hostname()),
+    };
     
     info!("接收请求: \"{} {} HTTP/{:?}\" 301 Moved Permanently", 
         req.method(), 
@@ -30,3 +58,16 @@ pub async fn redirect_to_https(req: HttpRequest) -> HttpResponse {
         .append_header(("Location", redirect_url))
         .finish()
 }
+
+// ACME HTTP-01 验证，签发证书期间返回 token 对应的 key authorization
+pub async fn acme_challenge(req: HttpRequest, token: web::Path<String>) -> Result<HttpResponse, AppError> {
+    match acme::http_challenge(&token) {
+        Some(key_authorization) => {
+            info!("响应 ACME HTTP-01 验证: {}", token);
+            Ok(HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .body(key_authorization))
+        }
+        None => Err(AppError::UnknownRoute(req.uri().path().to_string())),
+    }
+}
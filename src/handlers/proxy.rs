@@ -1,24 +1,33 @@
 use actix_web::{web, HttpRequest, HttpResponse, Result};
-use futures::stream::StreamExt;
+use actix_web::web::Bytes;
+use futures::stream::{self, StreamExt};
 use log::{info, error, debug, warn};
 
 use crate::error::AppError;
-use crate::HTTP_CLIENT;
-use crate::config::{Settings, RegistrySettings};
+use crate::config::{Settings, SharedSettings, BlobRedirectMode, RegistryApiVersion};
 use crate::auth_utils;
+use crate::upstream;
+use crate::routing::{self, RouteHints};
+use crate::forwarded::ClientInfo;
+use crate::policy::{self, PolicyDecision};
+use crate::manifest;
+use crate::throttle::{self, Throttle};
+use crate::ratelimit;
 
 pub async fn handle_request(
     req: HttpRequest,
     path: web::Path<(String, String, String)>,
 ) -> Result<HttpResponse, AppError> {
-    let settings = req.app_data::<web::Data<Settings>>().unwrap();
+    let current_settings = req.app_data::<web::Data<SharedSettings>>().unwrap().load();
+    let settings = &*current_settings;
+    let client = ClientInfo::from_request(&req, &settings.server.trusted_proxies);
     // 获取路径参数
     let (mut image_name, path_type, reference) = path.into_inner();
 
     debug!("原始镜像路径: {}", image_name);
     
     // 检查是否需要重新映射注册表
-    let (target_registry, remapped_image_name, registry_key) = get_target_registry(&settings.registry, &image_name);
+    let (target_registry, remapped_image_name, registry_key) = routing::get_target_registry(&settings.registry, &RouteHints::from_request(&req, &client), &image_name);
     debug!("注册表映射结果: 原始镜像={}, 目标注册表={}, 映射后镜像={}, 注册表键={}", 
            image_name, target_registry, remapped_image_name, registry_key);
     if remapped_image_name != image_name {
@@ -28,53 +37,631 @@ pub async fn handle_request(
     
     debug!("目标注册表: {}, 注册表键: {}", target_registry, registry_key);
 
-    // 使用常量构建目标URL
-    let path = format!("/v2/{image_name}/{path_type}/{reference}");
-    
-    // 构建请求，根据原始请求的方法选择 HEAD 或 GET
-    let target_url = format!("{target_registry}{path}");
-    debug!("目标URL: {}", target_url);
-    
-    let mut request_builder = if req.method() == actix_web::http::Method::HEAD {
-        HTTP_CLIENT.head(&target_url)
-    } else {
-        HTTP_CLIENT.get(&target_url)
-    };
+    // 在请求上游之前检查访问策略
+    if let PolicyDecision::Deny(reason) = policy::evaluate(&settings.policy, client.ip, &registry_key, &image_name, &path_type, &reference) {
+        warn!("{} {} {} {:?} 403 Forbidden: {}", client.ip_string().unwrap_or_default(), req.method(), req.uri(), req.version(), reason);
+        return Err(AppError::Denied(reason));
+    }
 
     // 处理认证
     // 首先尝试从请求中获取用户认证信息
-    let mut authenticated_user = None;
+    let authorization = req.headers().get("Authorization").and_then(|auth| auth.to_str().ok());
+    let authenticated_user = auth_utils::authenticated_user(&settings.auth, authorization);
+    if let Some(username) = &authenticated_user {
+        debug!("用户 {} 验证成功", username);
+    }
 
-    if settings.auth.enabled {
-        if let Some(auth) = req.headers().get("Authorization") {
-            if let Ok(auth_str) = auth.to_str() {
-                if let Some((username, password)) = auth_utils::parse_basic_auth(auth_str) {
-                    let users = &settings.auth.users;
-                    if !users.is_empty() {
-                        if auth_utils::verify_user(&username, &password, users) {
-                            debug!("用户 {} 验证成功", username);
-                            authenticated_user = Some(username);
-                        }
+    // 按用户、客户端 IP 和注册表限制下载带宽
+    let client_ip = client.ip_string();
+    let throttle = Throttle::for_request(&settings.bandwidth, authenticated_user.as_deref(), client_ip.as_deref(), &registry_key);
+
+    // 按用户和客户端 IP 限制请求速率
+    ratelimit::check_client(&settings.rate_limit, authenticated_user.as_deref(), client_ip.as_deref())?;
+
+    // 按顺序尝试镜像端点，连接失败或 5xx 时切换到下一个端点
+    let endpoints = upstream::available_endpoints(
+        &upstream::registry_endpoints(&settings.registry, &registry_key, &target_registry),
+        &settings.registry.failover,
+    );
+
+    // 过滤或转换后生成的 manifest 和配置 blob 只存在于本地，按摘要直接返回
+    let local_context = ManifestContext {
+        authenticated_user: authenticated_user.as_deref(),
+        registry_key: &registry_key,
+        endpoint: endpoints.first().unwrap_or(&target_registry),
+        image_name: &image_name,
+        reference: &reference,
+        convert_to_oci: None,
+    };
+    if let Some((media_type, body)) = local_content(&req, settings, &local_context, &path_type).await? {
+        return Ok(local_response(&req, settings, &path_type, &reference, media_type, body, throttle));
+    }
+
+    // V1 注册表返回的 schema1 manifest 在客户端不接受时转换为 Docker v2 / OCI 格式
+    let accept_values: Vec<&str> = req.headers().get_all("Accept")
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    let convert_schema1 = path_type == "manifests"
+        && settings.registry.registries.get(&registry_key)
+            .is_some_and(|config| config.api_version == RegistryApiVersion::V1)
+        && !manifest::accepts_schema1(&accept_values);
+
+    // 需要检查或转换 manifest 内容时，HEAD 请求也需要从上游获取完整内容
+    let inspect_manifest = path_type == "manifests"
+        && (convert_schema1 || manifest::inspection_enabled(&settings.manifest));
+    let upstream_head = req.method() == actix_web::http::Method::HEAD && !inspect_manifest;
+
+    // 拉取 manifest 会消耗上游配额（如 Docker Hub），配额不足时拒绝或排队
+    if path_type == "manifests" && !upstream_head {
+        ratelimit::guard_upstream_quota(&settings.rate_limit, &registry_key).await?;
+    }
+
+    // 使用常量构建目标URL
+    let path = format!("/v2/{image_name}/{path_type}/{reference}");
+    // 透传查询参数（如标签列表的 n / last 分页参数），ns 只用于选择注册表
+    let query = upstream_query(req.query_string());
+
+    // blob 重定向处理方式
+    let blob_redirect = settings.registry.registries
+        .get(&registry_key)
+        .map(|config| config.blob_redirect.clone())
+        .unwrap_or_default();
+    let redirect_passthrough = path_type == "blobs" && blob_redirect == BlobRedirectMode::Passthrough;
+
+    let method = req.method().as_str();
+    let mut last_error = None;
+    let mut upstream_response = None;
+    let mut resume_request = None;
+    let mut selected_endpoint = None;
+
+    for (index, endpoint) in endpoints.iter().enumerate() {
+        // 构建请求，根据原始请求的方法选择 HEAD 或 GET
+        let target_url = format!("{endpoint}{path}{query}");
+        debug!("目标URL: {}", target_url);
+
+        let client = if redirect_passthrough {
+            upstream::no_redirect_client_for(&registry_key)
+        } else {
+            upstream::client_for(&registry_key)
+        };
+        let mut request_builder = if upstream_head {
+            client.head(&target_url)
+        } else {
+            client.get(&target_url)
+        };
+        request_builder = apply_upstream_auth(&req, settings, authenticated_user.as_deref(), &registry_key, endpoint, &target_url, request_builder).await;
+        request_builder = apply_accept_headers(&req, settings, &registry_key, request_builder);
+        if convert_schema1 {
+            request_builder = request_builder.header("Accept", format!("{},{}", manifest::MEDIA_TYPE_SCHEMA1_SIGNED, manifest::MEDIA_TYPE_SCHEMA1));
+        }
+
+        // blob 内容按摘要寻址，可以安全地使用 Range 续传
+        let request_clone = if path_type == "blobs" { request_builder.try_clone() } else { None };
+
+        // 透传客户端的 Range / If-Range 头，支持断点续传和分段下载
+        if path_type == "blobs" && req.method() == actix_web::http::Method::GET {
+            for header in ["Range", "If-Range"] {
+                if let Some(value) = req.headers().get(header)
+                    && let Ok(value_str) = value.to_str() {
+                    debug!("透传 {} 头: {}", header, value_str);
+                    request_builder = request_builder.header(header, value_str);
+                }
+            }
+        }
+
+        // 发送请求到 Docker Registry
+        match upstream::send_with_retry(request_builder, &settings.registry.retry).await {
+            Ok(resp) => {
+                ratelimit::record_upstream_quota(&settings.rate_limit, &registry_key, &resp);
+                info!("{} {} {:?} {} {}", 
+                    method, 
+                    target_url, 
+                    req.version(),
+                    resp.status().as_u16(), 
+                    resp.status().canonical_reason().unwrap_or("Unknown"));
+
+                if upstream::is_failover_status(resp.status()) {
+                    upstream::record_failure(endpoint, &settings.registry.failover);
+                    if index + 1 < endpoints.len() {
+                        warn!("端点 {} 返回 {}，切换到下一个镜像端点", endpoint, resp.status().as_u16());
+                        continue;
                     }
+                } else {
+                    upstream::record_success(endpoint);
+                }
+                upstream_response = Some(resp);
+                resume_request = request_clone;
+                selected_endpoint = Some(endpoint.clone());
+                break;
+            },
+            Err(e) => {
+                error!("{} {} {:?} 失败: {}", method, target_url, req.version(), e);
+                upstream::record_failure(endpoint, &settings.registry.failover);
+                last_error = Some(e);
+            }
+        }
+    }
+
+    let Some(response) = upstream_response else {
+        return Err(last_error.map(AppError::UpstreamRequest)
+            .unwrap_or_else(|| AppError::UpstreamUnavailable("no available upstream endpoint".to_string())));
+    };
+
+    // 获取状态码和响应头
+    let status = response.status();
+    let mut builder = HttpResponse::build(actix_web::http::StatusCode::from_u16(status.as_u16()).unwrap());
+
+    // 复制所有响应头
+    for (name, value) in response.headers() {
+        if let Ok(value_str) = value.to_str() {
+            if name == reqwest::header::LOCATION {
+                // 重定向目标可能是相对路径，需要基于上游地址转换为绝对地址，签名参数保持不变
+                let location = response.url().join(value_str)
+                    .map(|url| url.to_string())
+                    .unwrap_or_else(|_| value_str.to_string());
+                info!("上游重定向至: {}", location);
+                builder.append_header((name.as_str(), location));
+            } else if name == reqwest::header::LINK {
+                // 分页链接指向上游的路径，需要换成客户端请求的路径和对外地址
+                let link = rewrite_pagination_link(value_str, &client.base_url(settings.server.public_url.as_deref()), req.path());
+                debug!("分页链接: {} -> {}", value_str, link);
+                builder.append_header((name.as_str(), link));
+            } else {
+                builder.append_header((name.as_str(), value_str));
+            }
+        }
+    }
+
+    // 服务端跟随了重定向（如 CDN 签名地址），CDN 不会返回镜像仓库的摘要头，需要补充
+    if path_type == "blobs" && response.status().is_success() {
+        if response.url().path() != path.as_str() {
+            debug!("blob 请求已被重定向至: {}", response.url().host_str().unwrap_or("unknown"));
+        }
+        if !response.headers().contains_key("Docker-Content-Digest") && reference.contains(':') {
+            builder.insert_header(("Docker-Content-Digest", reference.as_str()));
+        }
+    }
+
+    // blob 支持 Range 请求，只在返回内容时声明
+    if path_type == "blobs" && matches!(status.as_u16(), 200 | 206)
+        && !response.headers().contains_key(reqwest::header::ACCEPT_RANGES) {
+        builder.insert_header(("Accept-Ranges", "bytes"));
+    }
+
+    // 记录响应日志
+    info!("{} {} {} {:?} {} {}", 
+        client_ip.as_deref().unwrap_or("-"),
+        req.method(), 
+        req.uri(), 
+        req.version(),
+        status.as_u16(), 
+        status.canonical_reason().unwrap_or("Unknown"));
+
+    // 检查 manifest 内容
+    if inspect_manifest && status.is_success() {
+        let context = ManifestContext {
+            authenticated_user: authenticated_user.as_deref(),
+            registry_key: &registry_key,
+            endpoint: selected_endpoint.as_deref().unwrap_or(&target_registry),
+            image_name: &image_name,
+            reference: &reference,
+            convert_to_oci: convert_schema1.then(|| manifest::prefers_oci(&accept_values)),
+        };
+        return inspect_manifest_response(&req, settings, &context, response, builder).await;
+    }
+
+    // 根据请求方法处理响应
+    if req.method() == actix_web::http::Method::HEAD {
+        // HEAD 请求，不需要返回响应体
+        Ok(builder.finish())
+    } else {
+        // GET 请求
+        if status.is_redirection() {
+            // 重定向直接返回给客户端，由客户端从 CDN 下载
+            Ok(builder.finish())
+        } else if !status.is_success() {
+            // 非成功响应，打印响应内容
+            match response.text().await {
+                Ok(body) => {
+                    error!("GET 请求失败 ({}): 响应内容: {}", status.as_u16(), body);
+                    Ok(builder.body(body))
+                },
+                Err(e) => {
+                    error!("读取失败响应内容时出错: {}", e);
+                    Err(AppError::UpstreamRequest(e))
                 }
             }
+        } else {
+            // 成功响应，使用流式传输响应体，传输中断时尝试续传
+            let stream = upstream::resumable_stream(response, resume_request, settings.registry.retry.clone())
+                .map(|result| {
+                    result.map_err(|err| {
+                        error!("流读取错误: {}", err);
+                        actix_web::error::ErrorInternalServerError(err)
+                    })
+                });
+                
+            Ok(builder.streaming(throttle::throttle_stream(stream, throttle)))
+        }
+    }
+}
+
+// manifest 检查所需的上游信息
+struct ManifestContext<'a> {
+    authenticated_user: Option<&'a str>,
+    registry_key: &'a str,
+    endpoint: &'a str,
+    image_name: &'a str,
+    reference: &'a str,
+    // 需要转换 schema1 manifest 时为 Some，值表示是否转换为 OCI 格式
+    convert_to_oci: Option<bool>,
+}
+
+// 缓冲 manifest 内容：schema1 按需转换，按标签拉取的索引按平台过滤并重新计算摘要，镜像 manifest 检查其配置
+async fn inspect_manifest_response(
+    req: &HttpRequest,
+    settings: &Settings,
+    context: &ManifestContext<'_>,
+    response: reqwest::Response,
+    mut builder: actix_web::HttpResponseBuilder,
+) -> Result<HttpResponse, AppError> {
+    let content_type = response.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let mut body = response.bytes().await?.to_vec();
+    let mut media_type = manifest::media_type(content_type.as_deref(), &body);
+
+    if let Some(oci) = context.convert_to_oci
+        && manifest::is_schema1(&media_type) {
+        let (converted_type, converted) = convert_schema1_manifest(req, settings, context, &body, oci).await
+            .map_err(|e| {
+                error!("{}:{} schema1 manifest 转换失败: {}", context.image_name, context.reference, e);
+                AppError::ManifestConversion(e)
+            })?;
+        body = converted;
+        media_type = converted_type;
+        builder.insert_header(("Content-Type", media_type.as_str()));
+        builder.insert_header(("Docker-Content-Digest", manifest::digest(&body)));
+    }
+
+    if manifest::is_index(&media_type) {
+        // 按摘要拉取时内容必须与摘要一致，只在按标签拉取时过滤
+        let by_tag = !context.reference.contains(':');
+        if by_tag && !settings.manifest.platforms.is_empty()
+            && let Some(filtered) = manifest::filter_platforms(&body, &settings.manifest.platforms)? {
+            let source = manifest::digest(&body);
+            body = filtered;
+            let digest = manifest::digest(&body);
+            info!("{}:{} 按平台过滤后的摘要: {}", context.image_name, context.reference, digest);
+            let origin = manifest::LocalOrigin { source: source.clone(), kind: manifest::LocalKind::Filtered };
+            manifest::store_manifest(&settings.manifest, context.registry_key, context.image_name, &digest, &media_type, &body, origin);
+            builder.insert_header(("Docker-Content-Digest", digest));
+        }
+    } else if manifest::is_image_manifest(&media_type) && manifest::needs_config(&settings.manifest)
+        && let Some(config_digest) = manifest::config_digest(&body) {
+        let config_body = fetch_config_blob(req, settings, context, &config_digest).await?;
+        if let Err(e) = manifest::check_config(&settings.manifest, &config_body) {
+            warn!("{} {} {:?} 镜像配置检查未通过: {}", req.method(), req.uri(), req.version(), e);
+            return Err(e);
+        }
+    }
+
+    builder.insert_header(("Content-Length", body.len().to_string()));
+    if req.method() == actix_web::http::Method::HEAD {
+        Ok(builder.finish())
+    } else {
+        Ok(builder.body(body))
+    }
+}
+
+// 查找本地生成的内容：先用客户端的凭据向上游确认其对来源 manifest 的拉取权限，内容已被淘汰或服务重启后从来源重新生成
+// 无权访问或来源已不存在时返回 None，按正常流程请求上游，客户端得到与直接拉取相同的响应
+async fn local_content(
+    req: &HttpRequest,
+    settings: &Settings,
+    context: &ManifestContext<'_>,
+    path_type: &str,
+) -> Result<Option<(String, Vec<u8>)>, AppError> {
+    let lookup = || match path_type {
+        "manifests" => manifest::get_manifest(context.registry_key, context.image_name, context.reference),
+        "blobs" => manifest::get_blob(context.registry_key, context.image_name, context.reference),
+        _ => None,
+    };
+    if !matches!(path_type, "manifests" | "blobs") || !context.reference.contains(':') {
+        return Ok(None);
+    }
+    let Some(origin) = manifest::local_origin(&settings.manifest, context.registry_key, context.image_name, context.reference) else {
+        return Ok(None);
+    };
+
+    if let Some(content) = lookup() {
+        let response = source_manifest_request(req, settings, context, &origin, reqwest::Method::HEAD).await?;
+        if !response.status().is_success() {
+            info!("来源 manifest {} 返回 {}，不使用本地生成的内容", origin.source, response.status().as_u16());
+            return Ok(None);
+        }
+        return Ok(Some(content));
+    }
+
+    info!("本地生成的内容 {}@{} 已不在缓存中，从来源 {} 重新生成", context.image_name, context.reference, origin.source);
+    ratelimit::guard_upstream_quota(&settings.rate_limit, context.registry_key).await?;
+    let response = source_manifest_request(req, settings, context, &origin, reqwest::Method::GET).await?;
+    ratelimit::record_upstream_quota(&settings.rate_limit, context.registry_key, &response);
+    if !response.status().is_success() {
+        info!("来源 manifest {} 返回 {}，无法重新生成", origin.source, response.status().as_u16());
+        return Ok(None);
+    }
+
+    let content_type = response.headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let body = response.bytes().await?.to_vec();
+    match origin.kind {
+        manifest::LocalKind::Filtered => {
+            let media_type = manifest::media_type(content_type.as_deref(), &body);
+            if !settings.manifest.platforms.is_empty()
+                && let Some(filtered) = manifest::filter_platforms(&body, &settings.manifest.platforms)? {
+                let digest = manifest::digest(&filtered);
+                manifest::store_manifest(&settings.manifest, context.registry_key, context.image_name, &digest, &media_type, &filtered, origin.clone());
+            }
+        }
+        manifest::LocalKind::Schema1 { oci } => {
+            convert_schema1_manifest(req, settings, context, &body, oci).await
+                .map_err(AppError::ManifestConversion)?;
+        }
+    }
+
+    let content = lookup();
+    if content.is_none() {
+        warn!("从来源 {} 重新生成的内容与 {} 不一致，配置可能已变化", origin.source, context.reference);
+    }
+    Ok(content)
+}
+
+// 使用客户端的凭据请求本地内容的来源 manifest
+async fn source_manifest_request(
+    req: &HttpRequest,
+    settings: &Settings,
+    context: &ManifestContext<'_>,
+    origin: &manifest::LocalOrigin,
+    method: reqwest::Method,
+) -> Result<reqwest::Response, AppError> {
+    let target_url = format!("{}/v2/{}/manifests/{}", context.endpoint, context.image_name, origin.source);
+    debug!("请求来源 manifest: {} {}", method, target_url);
+
+    let request_builder = upstream::client_for(context.registry_key)
+        .request(method, &target_url)
+        .header("Accept", origin.kind.accept());
+    let request_builder = apply_upstream_auth(
+        req, settings, context.authenticated_user, context.registry_key, context.endpoint, &target_url, request_builder,
+    ).await;
+
+    Ok(upstream::send_with_retry(request_builder, &settings.registry.retry).await?)
+}
+
+// 返回本地生成的内容
+fn local_response(
+    req: &HttpRequest,
+    settings: &Settings,
+    path_type: &str,
+    digest: &str,
+    media_type: String,
+    mut body: Vec<u8>,
+    throttle: Option<Throttle>,
+) -> HttpResponse {
+    // blob 与上游一样支持 Range / If-Range 请求
+    let range = if path_type == "blobs" { requested_range(req, digest, body.len()) } else { None };
+    let mut builder = match range {
+        Some(Err(())) => {
+            info!("{} {} {:?} 416 Range Not Satisfiable (本地生成的内容)", req.method(), req.uri(), req.version());
+            return HttpResponse::RangeNotSatisfiable()
+                .insert_header(("Content-Range", format!("bytes */{}", body.len())))
+                .finish();
+        }
+        Some(Ok((start, end))) => {
+            info!("{} {} {:?} 206 Partial Content (本地生成的内容)", req.method(), req.uri(), req.version());
+            let mut builder = HttpResponse::PartialContent();
+            builder.insert_header(("Content-Range", format!("bytes {start}-{end}/{}", body.len())));
+            body = body[start..=end].to_vec();
+            builder
+        }
+        None => {
+            info!("{} {} {:?} 200 OK (本地生成的内容)", req.method(), req.uri(), req.version());
+            HttpResponse::Ok()
+        }
+    };
+    builder
+        .insert_header(("Content-Type", media_type))
+        .insert_header(("Docker-Content-Digest", digest))
+        .insert_header(("Content-Length", body.len().to_string()));
+    if path_type == "blobs" {
+        builder.insert_header(("Accept-Ranges", "bytes"));
+    }
+    if req.method() == actix_web::http::Method::HEAD {
+        return builder.finish();
+    }
+    if settings.bandwidth.exempt_cached || throttle.is_none() {
+        return builder.body(body);
+    }
+    let stream = stream::once(async move { Ok::<_, actix_web::Error>(Bytes::from(body)) });
+    builder.streaming(throttle::throttle_stream(stream, throttle))
+}
+
+// 解析客户端对本地内容的 Range 请求，返回闭区间 (start, end)
+// 没有 Range 头、格式无效、If-Range 与摘要不一致或为多段请求时返回 None，按完整内容响应；范围无法满足时返回 Some(Err(()))
+fn requested_range(req: &HttpRequest, digest: &str, len: usize) -> Option<Result<(usize, usize), ()>> {
+    let range = req.headers().get("Range")?.to_str().ok()?;
+    if let Some(if_range) = req.headers().get("If-Range")
+        && if_range.to_str().ok()?.trim().trim_matches('"') != digest {
+        return None;
+    }
+
+    let spec = range.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    let range = if start.is_empty() {
+        // 后缀范围，如 bytes=-500 表示最后 500 字节
+        let suffix: usize = end.parse().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        (len.saturating_sub(suffix), len - 1)
+    } else {
+        let start: usize = start.parse().ok()?;
+        let end = if end.is_empty() { usize::MAX } else { end.parse().ok()? };
+        if end < start {
+            return None;
+        }
+        if start >= len {
+            return Some(Err(()));
+        }
+        (start, end.min(len - 1))
+    };
+    Some(Ok(range))
+}
+
+// 去掉 containerd 的 ns 参数后的查询字符串，非空时带上开头的 ?
+fn upstream_query(query_string: &str) -> String {
+    let params: Vec<&str> = query_string.split('&')
+        .filter(|param| !param.is_empty() && param.split('=').next() != Some("ns"))
+        .collect();
+    if params.is_empty() {
+        String::new()
+    } else {
+        format!("?{}", params.join("&"))
+    }
+}
+
+// 改写上游返回的分页 Link 头，如 </v2/library/nginx/tags/list?n=100&last=1.25>; rel="next"
+// 上游的镜像路径可能经过重映射，因此保留查询参数，路径使用客户端请求的路径
+fn rewrite_pagination_link(value: &str, base_url: &str, request_path: &str) -> String {
+    value.split(',')
+        .map(|link| {
+            let link = link.trim();
+            let Some((target, params)) = link.strip_prefix('<').and_then(|rest| rest.split_once('>')) else {
+                return link.to_string();
+            };
+            let query = target.split_once('?').map(|(_, query)| query);
+            match query {
+                Some(query) => format!("<{base_url}{request_path}?{query}>{params}"),
+                None => format!("<{base_url}{request_path}>{params}"),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// 将 schema1 manifest 转换为 Docker v2 / OCI manifest，下载各层计算 diff_id 后在本地生成镜像配置
+async fn convert_schema1_manifest(
+    req: &HttpRequest,
+    settings: &Settings,
+    context: &ManifestContext<'_>,
+    body: &[u8],
+    oci: bool,
+) -> Result<(String, Vec<u8>), String> {
+    let key = manifest::conversion_key(body, oci);
+    let origin = manifest::LocalOrigin {
+        source: manifest::schema1_digest(body),
+        kind: manifest::LocalKind::Schema1 { oci },
+    };
+    if let Some(conversion) = manifest::get_conversion(&key) {
+        debug!("使用已缓存的 schema1 转换结果: {}:{}", context.image_name, context.reference);
+        manifest::store_conversion(&settings.manifest, context.registry_key, context.image_name, &key, &conversion, origin);
+        return Ok((conversion.manifest_type.to_string(), conversion.manifest_body));
+    }
+
+    info!("转换 schema1 manifest: {}:{}", context.image_name, context.reference);
+    let layers = manifest::parse_schema1(body)?;
+
+    let mut digests = Vec::new();
+    for layer in layers.iter().filter(|layer| !layer.throwaway) {
+        let response = upstream_blob_request(req, settings, context, &layer.blob_sum).await
+            .map_err(|e| e.to_string())?;
+        let mut stream = response.bytes_stream();
+        let mut digester = manifest::DiffIdDigester::default();
+        let mut size = 0u64;
+
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| e.to_string())?;
+            size += chunk.len() as u64;
+            digester.update(&chunk)?;
         }
+
+        let compressed = digester.compressed();
+        digests.push(manifest::LayerDigest { diff_id: digester.finish()?, size, compressed });
+    }
+
+    let conversion = manifest::convert_schema1(&layers, &digests, oci)?;
+    manifest::store_conversion(&settings.manifest, context.registry_key, context.image_name, &key, &conversion, origin);
+    Ok((conversion.manifest_type.to_string(), conversion.manifest_body))
+}
+
+// 从上游获取 blob
+async fn upstream_blob_request(
+    req: &HttpRequest,
+    settings: &Settings,
+    context: &ManifestContext<'_>,
+    digest: &str,
+) -> Result<reqwest::Response, AppError> {
+    let target_url = format!("{}/v2/{}/blobs/{}", context.endpoint, context.image_name, digest);
+    debug!("获取 blob: {}", target_url);
+
+    let request_builder = upstream::client_for(context.registry_key).get(&target_url);
+    let request_builder = apply_upstream_auth(
+        req, settings, context.authenticated_user, context.registry_key, context.endpoint, &target_url, request_builder,
+    ).await;
+
+    let response = upstream::send_with_retry(request_builder, &settings.registry.retry).await?;
+    if !response.status().is_success() {
+        return Err(AppError::UpstreamUnavailable(format!("获取 blob {digest} 时上游返回 {}", response.status().as_u16())));
+    }
+
+    Ok(response)
+}
+
+// 获取镜像配置 blob，转换生成的配置只存在于本地
+async fn fetch_config_blob(
+    req: &HttpRequest,
+    settings: &Settings,
+    context: &ManifestContext<'_>,
+    config_digest: &str,
+) -> Result<Vec<u8>, AppError> {
+    if let Some((_, body)) = manifest::get_blob(context.registry_key, context.image_name, config_digest) {
+        return Ok(body);
     }
 
+    let bytes = upstream_blob_request(req, settings, context, config_digest).await?
+        .bytes().await?;
+    Ok(bytes.to_vec())
+}
+
+// 根据已认证用户的注册表凭据或客户端原始认证头设置上游认证
+async fn apply_upstream_auth(
+    req: &HttpRequest,
+    settings: &Settings,
+    authenticated_user: Option<&str>,
+    registry_key: &str,
+    target_registry: &str,
+    target_url: &str,
+    mut request_builder: reqwest::RequestBuilder,
+) -> reqwest::RequestBuilder {
     // 如果启用了认证并找到了已认证用户，使用对应的注册表凭据
-    if settings.auth.enabled && authenticated_user.is_some() {
-        let username = authenticated_user.unwrap();
-        
+    if settings.auth.enabled && let Some(username) = authenticated_user {
         // 查找用户对此注册表的凭据
         let users = &settings.auth.users;
         if !users.is_empty() {
             debug!("尝试获取用户 {} 对注册表 {} 的凭据", username, registry_key);
-            if let Some(registry_cred) = auth_utils::get_registry_credentials(&username, &registry_key, users) {
+            if let Some(registry_cred) = auth_utils::get_registry_credentials(username, registry_key, users) {
                 info!("使用 {} 用户的 {} 注册表凭据", username, registry_key);
                 
                 // 获取注册表 API 版本配置
                 let api_version = settings.registry.registries
-                    .get(&registry_key)
+                    .get(registry_key)
                     .map(|config| config.api_version.clone())
                     .unwrap_or_default(); // 默认为 Auto
                 
@@ -82,9 +669,9 @@ pub async fn handle_request(
                 
                 // 使用通用认证处理
                 match auth_utils::authenticate_registry(
-                    &target_registry,
-                    &registry_key,
-                    &target_url,
+                    target_registry,
+                    registry_key,
+                    target_url,
                     &registry_cred.username,
                     &registry_cred.password,
                     &api_version
@@ -103,10 +690,9 @@ pub async fn handle_request(
                     },
                     auth_utils::RegistryAuthResult::Failed(error) => {
                         warn!("认证失败: {}，使用原始认证头", error);
-                        if let Some(auth) = req.headers().get("Authorization") {
-                            if let Ok(auth_str) = auth.to_str() {
-                                request_builder = request_builder.header("Authorization", auth_str);
-                            }
+                        if let Some(auth) = req.headers().get("Authorization")
+                            && let Ok(auth_str) = auth.to_str() {
+                            request_builder = request_builder.header("Authorization", auth_str);
                         }
                     }
                 }
@@ -114,11 +700,10 @@ pub async fn handle_request(
                 warn!("用户 {} 没有 {} 注册表的凭据", username, registry_key);
                 
                 // 如果用户提供了认证头，但没有对应注册表的凭据，仍使用原始认证头
-                if let Some(auth) = req.headers().get("Authorization") {
-                    if let Ok(auth_str) = auth.to_str() {
-                        info!("使用客户端原始 Authorization 头: {}", auth_str);
-                        request_builder = request_builder.header("Authorization", auth_str);
-                    }
+                if let Some(auth) = req.headers().get("Authorization")
+                    && let Ok(auth_str) = auth.to_str() {
+                    info!("使用客户端原始 Authorization 头: {}", auth_str);
+                    request_builder = request_builder.header("Authorization", auth_str);
                 }
             }
         }
@@ -135,7 +720,16 @@ pub async fn handle_request(
         }
     }
 
-    // 添加所有 Accept 头，对于 v2 API 注册表添加现代格式支持
+    request_builder
+}
+
+// 添加 Accept 头，对于 v2 API 注册表添加现代格式支持
+fn apply_accept_headers(
+    req: &HttpRequest,
+    settings: &Settings,
+    registry_key: &str,
+    mut request_builder: reqwest::RequestBuilder,
+) -> reqwest::RequestBuilder {
     let has_accept = req.headers().contains_key("Accept");
     for accept in req.headers().get_all("Accept") {
         if let Ok(accept_str) = accept.to_str() {
@@ -146,7 +740,7 @@ pub async fn handle_request(
     // 如果没有 Accept 头，根据注册表 API 版本添加默认值
     if !has_accept {
         let api_version = settings.registry.registries
-            .get(&registry_key)
+            .get(registry_key)
             .map(|config| config.api_version.clone())
             .unwrap_or_default();
         
@@ -165,100 +759,75 @@ pub async fn handle_request(
         request_builder = request_builder.header("Accept", default_accept);
     }
 
-    // 发送请求到 Docker Registry
-    let method = req.method().as_str();
-    let response = match request_builder.send().await {
-        Ok(resp) => {
-            info!("{} {} {:?} {} {}", 
-                method, 
-                target_url, 
-                req.version(),
-                resp.status().as_u16(), 
-                resp.status().canonical_reason().unwrap_or("Unknown"));
-            resp
-        },
-        Err(e) => {
-            error!("{} {} {:?} 失败: {}", method, target_url, req.version(), e);
-            return Ok(HttpResponse::InternalServerError()
-                .body(format!("无法连接到 Docker Registry: {e}")))
-        }
-    };
+    request_builder
+}
 
-    // 获取状态码和响应头
-    let status = response.status();
-    let mut builder = HttpResponse::build(actix_web::http::StatusCode::from_u16(status.as_u16()).unwrap());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
 
-    // 复制所有响应头
-    for (name, value) in response.headers() {
-        if let Ok(value_str) = value.to_str() {
-            builder.append_header((name.as_str(), value_str));
+    const DIGEST: &str = "sha256:abc";
+
+    fn range(headers: &[(&str, &str)], len: usize) -> Option<Result<(usize, usize), ()>> {
+        let mut request = TestRequest::default();
+        for header in headers {
+            request = request.append_header(*header);
         }
+        requested_range(&request.to_http_request(), DIGEST, len)
     }
 
-    // 记录响应日志
-    info!("{} {} {:?} {} {}", 
-        req.method(), 
-        req.uri(), 
-        req.version(),
-        status.as_u16(), 
-        status.canonical_reason().unwrap_or("Unknown"));
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(range(&[], 100), None);
+        assert_eq!(range(&[("Range", "bytes=0-9")], 100), Some(Ok((0, 9))));
+        assert_eq!(range(&[("Range", "bytes=90-")], 100), Some(Ok((90, 99))));
+        assert_eq!(range(&[("Range", "bytes=50-500")], 100), Some(Ok((50, 99))));
+        assert_eq!(range(&[("Range", "bytes=-10")], 100), Some(Ok((90, 99))));
+        assert_eq!(range(&[("Range", "bytes=-500")], 100), Some(Ok((0, 99))));
+    }
 
-    // 根据请求方法处理响应
-    if req.method() == actix_web::http::Method::HEAD {
-        // HEAD 请求，不需要返回响应体
-        Ok(builder.finish())
-    } else {
-        // GET 请求
-        if !status.is_success() {
-            // 非成功响应，打印响应内容
-            match response.text().await {
-                Ok(body) => {
-                    error!("GET 请求失败 ({}): 响应内容: {}", status.as_u16(), body);
-                    Ok(builder.body(body))
-                },
-                Err(e) => {
-                    error!("读取失败响应内容时出错: {}", e);
-                    Ok(builder.body(format!("无法读取响应内容: {}", e)))
-                }
-            }
-        } else {
-            // 成功响应，使用流式传输响应体
-            let stream = response
-                .bytes_stream()
-                .map(|result| {
-                    result.map_err(|err| {
-                        error!("流读取错误: {}", err);
-                        actix_web::error::ErrorInternalServerError(err)
-                    })
-                });
-                
-            Ok(builder.streaming(stream))
-        }
+    #[test]
+    fn unsatisfiable_and_ignored_ranges() {
+        assert_eq!(range(&[("Range", "bytes=100-")], 100), Some(Err(())));
+        assert_eq!(range(&[("Range", "bytes=-0")], 100), Some(Err(())));
+        assert_eq!(range(&[("Range", "bytes=9-0")], 100), None);
+        assert_eq!(range(&[("Range", "bytes=0-1,5-6")], 100), None);
+        assert_eq!(range(&[("Range", "items=0-1")], 100), None);
     }
-}
 
-// 根据注册表配置获取目标注册表和修改后的镜像名称
-fn get_target_registry(registry_settings: &RegistrySettings, image_name: &str) -> (String, String, String) {
-    // 默认使用上游注册表
-    let default_registry = registry_settings.upstream_registry.clone();
-    let default_registry_key = "docker.io".to_string();
-    
-    // 如果没有注册表配置，直接返回原始信息
-    if registry_settings.registries.is_empty() {
-        return (default_registry, image_name.to_string(), default_registry_key);
+    #[test]
+    fn if_range_must_match_digest() {
+        assert_eq!(range(&[("Range", "bytes=0-9"), ("If-Range", "\"sha256:abc\"")], 100), Some(Ok((0, 9))));
+        assert_eq!(range(&[("Range", "bytes=0-9"), ("If-Range", "\"sha256:def\"")], 100), None);
     }
-    
-    let registries = &registry_settings.registries;
-    
-    // 检查镜像名称是否包含需要重映射的注册表部分
-    for (registry_key, registry_config) in registries {
-        if image_name.starts_with(&format!("{}/", registry_key)) {
-            // 找到匹配的注册表，提取实际镜像路径
-            let actual_image_path = image_name[registry_key.len() + 1..].to_string();
-            return (registry_config.url.clone(), actual_image_path, registry_key.clone());
-        }
+
+    #[test]
+    fn query_is_forwarded_without_ns() {
+        assert_eq!(upstream_query(""), "");
+        assert_eq!(upstream_query("ns=docker.io"), "");
+        assert_eq!(upstream_query("n=100&last=1.25"), "?n=100&last=1.25");
+        assert_eq!(upstream_query("ns=ghcr.io&n=10"), "?n=10");
+        assert_eq!(upstream_query("n=10&ns=&nsfw=1&&last=a%2Fb"), "?n=10&nsfw=1&last=a%2Fb");
+    }
+
+    #[test]
+    fn pagination_links_point_at_the_proxy() {
+        let base_url = "https://proxy.example.com";
+        let request_path = "/v2/nginx/tags/list";
+        assert_eq!(
+            rewrite_pagination_link("</v2/library/nginx/tags/list?n=100&last=1.25>; rel=\"next\"", base_url, request_path),
+            "<https://proxy.example.com/v2/nginx/tags/list?n=100&last=1.25>; rel=\"next\"",
+        );
+        // 上游返回绝对地址时同样改写，没有查询参数时只替换路径
+        assert_eq!(
+            rewrite_pagination_link("<https://registry-1.docker.io/v2/library/nginx/tags/list>; rel=\"next\"", base_url, request_path),
+            "<https://proxy.example.com/v2/nginx/tags/list>; rel=\"next\"",
+        );
+        assert_eq!(
+            rewrite_pagination_link("</v2/a/tags/list?last=x>; rel=\"next\", </v2/a/tags/list?last=y>; rel=\"prev\"", base_url, request_path),
+            "<https://proxy.example.com/v2/nginx/tags/list?last=x>; rel=\"next\", <https://proxy.example.com/v2/nginx/tags/list?last=y>; rel=\"prev\"",
+        );
+        assert_eq!(rewrite_pagination_link("not a link", base_url, request_path), "not a link");
     }
-    
-    // 没有找到匹配的映射，返回原始信息
-    (default_registry, image_name.to_string(), default_registry_key)
 }
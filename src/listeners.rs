@@ -0,0 +1,165 @@
+use std::net::{IpAddr, SocketAddr, TcpListener};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use log::{info, warn};
+use socket2::{Domain, Protocol, Socket, Type};
+
+use crate::error::AppError;
+
+// systemd 传入的第一个文件描述符
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+// 与 actix-web 默认值一致的监听队列长度
+const LISTEN_BACKLOG: i32 = 1024;
+
+// systemd socket activation 传入的监听套接字
+#[derive(Default)]
+pub struct SystemdListeners {
+    pub http: Vec<TcpListener>,
+    pub https: Vec<TcpListener>, // FileDescriptorName=https 的 TCP 套接字
+    #[cfg(unix)]
+    pub unix: Vec<UnixListener>,
+}
+
+// 解析监听地址，支持 0.0.0.0、::、[::] 等形式
+pub fn socket_addr(address: &str, port: u16) -> Result<SocketAddr, String> {
+    let ip = address.strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .unwrap_or(address);
+    ip.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, port))
+        .map_err(|e| format!("无效的监听地址 {address}: {e}"))
+}
+
+// 绑定 TCP 监听套接字；同时配置了 IPv4 地址时 IPv6 套接字只接受 IPv6 连接，
+// 否则 [::] 会占用 IPv4 端口，导致 0.0.0.0 绑定失败
+pub fn tcp_listener(address: SocketAddr, v6_only: bool) -> Result<TcpListener, AppError> {
+    let bind = || -> std::io::Result<TcpListener> {
+        let socket = Socket::new(Domain::for_address(address), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(true)?;
+        if address.is_ipv6() {
+            socket.set_only_v6(v6_only)?;
+        }
+        socket.bind(&address.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+        Ok(socket.into())
+    };
+    bind().map_err(|e| AppError::Config(format!("无法监听 {address}: {e}")))
+}
+
+// 绑定 Unix 套接字，路径上残留的旧套接字文件会被删除
+#[cfg(unix)]
+pub fn unix_listener(path: &str) -> Result<UnixListener, AppError> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path)
+        && metadata.file_type().is_socket() {
+        std::fs::remove_file(path)?;
+    }
+
+    UnixListener::bind(path)
+        .map_err(|e| AppError::Config(format!("无法绑定 Unix 套接字 {path}: {e}")))
+}
+
+// 读取 systemd socket activation 的 LISTEN_PID / LISTEN_FDS / LISTEN_FDNAMES
+#[cfg(unix)]
+pub fn systemd_listeners() -> SystemdListeners {
+    let mut listeners = SystemdListeners::default();
+
+    let for_this_process = std::env::var("LISTEN_PID").ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count = std::env::var("LISTEN_FDS").ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+    let names: Vec<String> = std::env::var("LISTEN_FDNAMES")
+        .map(|names| names.split(':').map(String::from).collect())
+        .unwrap_or_default();
+
+    // 与 sd_listen_fds(1) 一样读取后清除这些变量，避免子进程把同样的文件描述符当作自己的监听套接字
+    // SAFETY: 只在启动时调用一次，此时还没有其他线程读写环境变量
+    unsafe {
+        std::env::remove_var("LISTEN_PID");
+        std::env::remove_var("LISTEN_FDS");
+        std::env::remove_var("LISTEN_FDNAMES");
+    }
+
+    if !for_this_process || count <= 0 {
+        return listeners;
+    }
+
+    for index in 0..count {
+        let fd = SD_LISTEN_FDS_START + index;
+        let name = names.get(index as usize).map(String::as_str).unwrap_or("");
+
+        // SAFETY: systemd 将这些文件描述符交给本进程，每个只在这里取得一次所有权
+        let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+        match tcp.local_addr() {
+            Ok(addr) if name == "https" => {
+                info!("systemd 套接字 {}: HTTPS {}", fd, addr);
+                listeners.https.push(tcp);
+            }
+            Ok(addr) => {
+                info!("systemd 套接字 {}: HTTP {}", fd, addr);
+                listeners.http.push(tcp);
+            }
+            Err(_) => {
+                // 不是 TCP 套接字，按 Unix 套接字处理
+                // SAFETY: 所有权从 TcpListener 转移，文件描述符不会被重复关闭
+                let unix = unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+                match unix.local_addr() {
+                    Ok(addr) => {
+                        info!("systemd 套接字 {}: Unix {:?}", fd, addr.as_pathname());
+                        listeners.unix.push(unix);
+                    }
+                    Err(e) => warn!("忽略无法识别的 systemd 套接字 {}: {}", fd, e),
+                }
+            }
+        }
+    }
+
+    listeners
+}
+
+// 非 Unix 系统没有 systemd socket activation
+#[cfg(not(unix))]
+pub fn systemd_listeners() -> SystemdListeners {
+    SystemdListeners::default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listen_address_forms() {
+        assert_eq!(socket_addr("0.0.0.0", 80), Ok("0.0.0.0:80".parse().unwrap()));
+        assert_eq!(socket_addr("::", 80), Ok("[::]:80".parse().unwrap()));
+        assert_eq!(socket_addr("[::1]", 80), Ok("[::1]:80".parse().unwrap()));
+        assert!(socket_addr("[::1", 80).is_err());
+        assert!(socket_addr("::1]", 80).is_err());
+        assert!(socket_addr("[127.0.0.1", 80).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn systemd_variables_are_cleared() {
+        // 发给其他进程的变量不会取得文件描述符，但同样被清除
+        // SAFETY: 其他测试不读取这些环境变量
+        unsafe {
+            std::env::set_var("LISTEN_PID", (std::process::id() + 1).to_string());
+            std::env::set_var("LISTEN_FDS", "2");
+            std::env::set_var("LISTEN_FDNAMES", "http:https");
+        }
+
+        let listeners = systemd_listeners();
+        assert!(listeners.http.is_empty() && listeners.https.is_empty() && listeners.unix.is_empty());
+        for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            assert!(std::env::var_os(name).is_none(), "{name}");
+        }
+    }
+}
@@ -1,28 +1,63 @@
 use crate::error::AppError;
 use actix_web::{web, guard, App, HttpServer, Result};
-use rustls::{Certificate, PrivateKey, ServerConfig};
-use std::fs::File;
-use std::io::BufReader;
-use std::time::Duration;
-use lazy_static::lazy_static;
+use std::net::SocketAddr;
 use log::{info, error};
 
 mod config;
 mod error;
 mod handlers;
 mod auth_utils;
+mod upstream;
+mod routing;
+mod policy;
+mod manifest;
+mod throttle;
+mod ratelimit;
+mod reload;
+mod validation;
+mod listeners;
+mod forwarded;
+mod tls;
+mod acme;
 
-
-lazy_static! {
-    pub static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
-        .pool_max_idle_per_host(10)  // 根据负载调整
-        .pool_idle_timeout(Duration::from_secs(90))
-        .timeout(Duration::from_secs(30))
-        .build()
-        .unwrap();
+// 命令行参数
+struct CliArgs {
+    check_config: bool,        // check-config 子命令：只校验配置，不启动服务
+    config_files: Vec<String>, // 按顺序加载的配置文件
 }
 
+// 解析命令行参数
+fn parse_args() -> Result<CliArgs, AppError> {
+    let mut check_config = false;
+    let mut config_files = Vec::new();
+    let mut args = std::env::args().skip(1);
 
+    while let Some(arg) = args.next() {
+        if arg == "check-config" {
+            check_config = true;
+        } else if arg == "--config" || arg == "-c" {
+            let path = args.next()
+                .ok_or_else(|| AppError::Config(format!("{arg} 需要指定配置文件路径")))?;
+            config_files.push(path);
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            config_files.push(path.to_string());
+        } else if arg == "--help" || arg == "-h" {
+            println!("用法: docxy [check-config] [--config <path>]...");
+            println!();
+            println!("  check-config         校验配置并列出所有问题，不启动服务");
+            println!("  -c, --config <path>  配置文件，可重复指定，后面的文件覆盖前面的配置");
+            println!("                       默认加载 config/default 和可选的 config/local");
+            println!();
+            println!("环境变量 DOCXY__<SECTION>__<KEY> 覆盖配置，如 DOCXY__SERVER__HTTPS_PORT=8443");
+            println!("列表配置用逗号分隔；注册表键可包含 .，如 DOCXY__REGISTRY__REGISTRIES__docker.io__MIRRORS");
+            std::process::exit(0);
+        } else {
+            return Err(AppError::Config(format!("未知参数: {arg}")));
+        }
+    }
+
+    Ok(CliArgs { check_config, config_files })
+}
 
 #[actix_web::main]
 async fn main() -> Result<(), AppError> {
@@ -58,7 +93,33 @@ async fn main() -> Result<(), AppError> {
         })
         .init();
     
-    let settings = config::Settings::new().map_err(|e| AppError::TlsConfig(format!("无法加载配置: {}", e)))?;
+    let CliArgs { check_config, config_files } = parse_args()?;
+
+    if check_config {
+        match validation::load(&config_files) {
+            Ok(_) => {
+                println!("配置有效");
+                return Ok(());
+            }
+            Err(problems) => {
+                for problem in &problems {
+                    eprintln!("{problem}");
+                }
+                eprintln!("配置校验失败，共 {} 个问题", problems.len());
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let settings = validation::load(&config_files).map_err(|problems| {
+        for problem in &problems {
+            error!("配置错误: {}", problem);
+        }
+        AppError::Config(format!("配置校验失败，共 {} 个问题", problems.len()))
+    })?;
+    if !config_files.is_empty() {
+        info!("配置文件: {}", config_files.join(", "));
+    }
 
     // 输出配置信息
     info!("服务器配置:");
@@ -73,8 +134,15 @@ async fn main() -> Result<(), AppError> {
     if settings.server.behind_proxy {
         info!("代理模式: 已启用");
     }
+
+    if let Some(public_url) = &settings.server.public_url {
+        info!("对外地址: {}", public_url);
+    }
     
     info!("上游注册表: {}", settings.registry.upstream_registry);
+
+    // 创建上游 HTTP 客户端
+    upstream::init_clients(&settings.registry)?;
     
     // 输出注册表配置
     let registries = &settings.registry.registries;
@@ -82,6 +150,16 @@ async fn main() -> Result<(), AppError> {
         info!("注册表配置:");
         for (registry_key, registry_config) in registries {
             info!("  {} -> {} (API: {:?})", registry_key, registry_config.url, registry_config.api_version);
+            for host in &registry_config.hosts {
+                info!("    主机名路由: {}", host);
+            }
+            for mirror in &registry_config.mirrors {
+                info!("    镜像端点: {}", mirror);
+            }
+            if let Some(client) = &registry_config.client
+                && let Some(proxy) = &client.proxy {
+                info!("    出站代理: {}", proxy);
+            }
             if let Some(auth_url) = &registry_config.auth_url {
                 info!("    认证服务: {}", auth_url);
             }
@@ -112,79 +190,103 @@ async fn main() -> Result<(), AppError> {
         info!("认证系统: 已禁用");
     }
 
-    // 创建应用配置
-    let http_app_data = web::Data::new(settings.clone());
-    
+    // 输出访问策略配置
+    if settings.policy.enabled {
+        info!("访问策略: 已启用 ({} 条规则, 默认: {:?}, 强制摘要: {})",
+            settings.policy.rules.len(), settings.policy.default_action, settings.policy.require_digest);
+    }
 
-    let http_app = move || {
-        App::new()
-            .app_data(http_app_data.clone())
-            .route("/v2/", web::get().to(handlers::proxy_challenge))
-            .route("/auth/token", web::get().to(handlers::get_token))
-            .route("/health", web::get().to(handlers::health_check))
-            .route("/v2/{image_name:.*}/{path_type}/{reference:.+}",
-                   web::route()
-                   .guard(guard::Any(guard::Get()).or(guard::Head()))
-                   .to(handlers::handle_request))
-            .default_service(web::route().to(handlers::handle_invalid_request))  // 添加默认服务处理非法请求
-    };
-    
-    // 创建HTTP重定向应用配置，特殊情况下我们可能仍然希望重定向，而不是拒绝访问
-    let http_redirect_app = move || {
-        App::new()
-            .service(
-                web::scope("/v2")
-                    .route("", web::get().to(handlers::redirect_to_https))
-                    .route("/{tail:.*}", web::route().to(handlers::redirect_to_https))
-            )
-            .route("/auth/token", web::get().to(handlers::redirect_to_https))
-            .route("/health", web::get().to(handlers::redirect_to_https))
-            .default_service(web::route().to(handlers::handle_invalid_request))  // 非法路径直接拒绝
+    if !settings.manifest.platforms.is_empty() {
+        info!("manifest 平台过滤: {}", settings.manifest.platforms.join(", "));
+    }
+    if manifest::needs_config(&settings.manifest) {
+        info!("镜像配置检查: 拒绝 root {}, 必需标签 {:?}", settings.manifest.reject_root, settings.manifest.required_labels);
+    }
+
+    if settings.bandwidth.enabled {
+        info!("带宽限制: 全局 {} B/s, 每用户 {} B/s, 每 IP {} B/s, 注册表 {:?}",
+            settings.bandwidth.global_bytes_per_sec,
+            settings.bandwidth.per_user_bytes_per_sec,
+            settings.bandwidth.per_ip_bytes_per_sec,
+            settings.bandwidth.registries);
+    }
+
+    if settings.rate_limit.enabled {
+        info!("请求速率限制: 每用户 {} 次/分钟, 每 IP {} 次/分钟",
+            settings.rate_limit.per_user_requests_per_minute,
+            settings.rate_limit.per_ip_requests_per_minute);
+    }
+
+    // 创建应用配置，所有 worker 共享同一份可热重载的配置
+    let shared_settings = web::Data::new(config::SharedSettings::new(settings.clone()));
+    reload::spawn(shared_settings.clone(), config_files.clone());
+
+    // 创建应用，routes 决定提供完整功能还是只做 HTTP 到 HTTPS 的重定向
+    let app_factory = |routes: fn(&mut web::ServiceConfig)| {
+        let app_data = shared_settings.clone();
+        move || {
+            App::new()
+                .app_data(app_data.clone())
+                .configure(routes)
+                .default_service(web::route().to(handlers::handle_invalid_request))  // 添加默认服务处理非法请求
+        }
     };
-    
+
+    // 监听地址
+    let bind_addresses = settings.server.bind_addresses.iter()
+        .map(|address| listeners::socket_addr(address, 0))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(AppError::Config)?;
+    let v6_only = bind_addresses.iter().any(SocketAddr::is_ipv4);
+    let systemd = listeners::systemd_listeners();
+
     // 创建服务器实例
     let mut servers = Vec::new();
     
     // 启动HTTP服务器（如果启用）
-    if settings.server.http_enabled {
-        let http_server = if !settings.server.behind_proxy && settings.server.https_enabled {
-            // 如果启用了HTTPS且不在代理后面，HTTP只做重定向
-            HttpServer::new(http_redirect_app)
-                .bind(("0.0.0.0", settings.server.http_port))?
-                .run()
+    if settings.server.http_enabled || !systemd.http.is_empty() {
+        // 如果启用了HTTPS且不在代理后面，HTTP只做重定向，否则HTTP提供完整功能
+        let routes = if !settings.server.behind_proxy && settings.server.https_enabled { redirect_routes } else { proxy_routes };
+        let mut http_server = HttpServer::new(app_factory(routes));
+
+        // systemd 传入套接字时使用它们代替配置的监听地址
+        if systemd.http.is_empty() {
+            for address in &bind_addresses {
+                let address = SocketAddr::new(address.ip(), settings.server.http_port);
+                info!("HTTP 监听: {}", address);
+                http_server = http_server.listen(listeners::tcp_listener(address, v6_only)?)?;
+            }
         } else {
-            // 否则HTTP提供完整功能
-            HttpServer::new(http_app)
-                .bind(("0.0.0.0", settings.server.http_port))?
-                .run()
-        };
+            for listener in systemd.http {
+                http_server = http_server.listen(listener)?;
+            }
+        }
         
-        servers.push(http_server);
+        servers.push(http_server.run());
     }
     
     // 启动HTTPS服务器（如果启用）
-    if settings.server.https_enabled {
+    if settings.server.https_enabled || !systemd.https.is_empty() {
         // 加载TLS配置
-        match load_rustls_config(&settings) {
+        match tls::server_config(&settings) {
             Ok(rustls_config) => {
-                let https_port = settings.server.https_port;
-                let settings_clone = settings.clone();
-                let https_server = HttpServer::new(move || {
-                    App::new()
-                        .app_data(web::Data::new(settings_clone.clone()))
-                        .route("/v2/", web::get().to(handlers::proxy_challenge))
-                        .route("/auth/token", web::get().to(handlers::get_token))
-                        .route("/health", web::get().to(handlers::health_check))
-                        .route("/v2/{image_name:.*}/{path_type}/{reference:.+}",
-                               web::route()
-                               .guard(guard::Any(guard::Get()).or(guard::Head()))
-                               .to(handlers::handle_request))
-                        .default_service(web::route().to(handlers::handle_invalid_request))  // 添加默认服务处理非法请求
-                })
-                    .bind_rustls(("0.0.0.0", https_port), rustls_config)?
-                    .run();
+                let mut https_server = HttpServer::new(app_factory(proxy_routes));
+
+                if systemd.https.is_empty() {
+                    for address in &bind_addresses {
+                        let address = SocketAddr::new(address.ip(), settings.server.https_port);
+                        info!("HTTPS 监听: {}", address);
+                        https_server = https_server.listen_rustls(listeners::tcp_listener(address, v6_only)?, rustls_config.clone())?;
+                    }
+                } else {
+                    for listener in systemd.https {
+                        https_server = https_server.listen_rustls(listener, rustls_config.clone())?;
+                    }
+                }
                 
-                servers.push(https_server);
+                servers.push(https_server.run());
+                tls::spawn(shared_settings.clone());
+                acme::spawn(shared_settings.clone());
             },
             Err(e) => {
                 error!("无法加载TLS配置: {}", e);
@@ -196,11 +298,31 @@ async fn main() -> Result<(), AppError> {
             }
         }
     }
+
+    // Unix 套接字只接受本机连接，始终提供完整功能
+    #[cfg(unix)]
+    if !settings.server.unix_sockets.is_empty() || !systemd.unix.is_empty() {
+        let mut unix_server = HttpServer::new(app_factory(proxy_routes));
+
+        for path in &settings.server.unix_sockets {
+            info!("Unix 套接字监听: {}", path);
+            unix_server = unix_server.listen_uds(listeners::unix_listener(path)?)?;
+        }
+        for listener in systemd.unix {
+            unix_server = unix_server.listen_uds(listener)?;
+        }
+
+        servers.push(unix_server.run());
+    }
+    #[cfg(not(unix))]
+    if !settings.server.unix_sockets.is_empty() {
+        return Err(AppError::Config("当前系统不支持 Unix 套接字".to_string()));
+    }
     
     // 确保至少有一个服务器在运行
     if servers.is_empty() {
         return Err(AppError::TlsConfig(
-            "HTTP和HTTPS服务均已禁用，无法启动服务器".to_string(),
+            "HTTP、HTTPS 和 Unix 套接字均未启用，无法启动服务器".to_string(),
         ));
     }
     
@@ -212,54 +334,26 @@ async fn main() -> Result<(), AppError> {
 
 
 
-// 修改证书加载函数，使用环境变量配置证书路径
-fn load_rustls_config(settings: &config::Settings) -> Result<ServerConfig, AppError> {
-    // 从环境变量获取证书路径，如果未设置则使用默认值
-    let cert_path = &settings.tls.cert_path;
-    let key_path = &settings.tls.key_path;
-    
-    info!("正在加载证书: {}", cert_path);
-    info!("正在加载私钥: {}", key_path);
-    
-    // 读取证书和密钥文件
-    let cert_file = &mut BufReader::new(File::open(&cert_path)
-        .map_err(|e| AppError::TlsConfig(format!("无法打开证书文件 {cert_path}: {e}")))?);
-    
-    let key_file = &mut BufReader::new(File::open(&key_path)
-        .map_err(|e| AppError::TlsConfig(format!("无法打开私钥文件 {key_path}: {e}")))?);
-    
-    // 解析证书
-    let cert_chain = rustls_pemfile::certs(cert_file)?
-        .into_iter()
-        .map(Certificate)
-        .collect();
-    
-    // 尝试解析私钥（支持多种格式）
-    let mut keys = rustls_pemfile::ec_private_keys(key_file)?;
-    
-    // 如果没有找到 ECC 私钥，尝试读取 RSA 私钥
-    if keys.is_empty() {
-        // 需要重新打开文件，因为前面的读取已经消耗了文件内容
-        let key_file = &mut BufReader::new(File::open(&key_path)?);
-        keys = rustls_pemfile::rsa_private_keys(key_file)?;
-    }
-    
-    // 如果仍然没有找到私钥，尝试读取 PKCS8 格式的私钥
-    if keys.is_empty() {
-        let key_file = &mut BufReader::new(File::open(&key_path)?);
-        keys = rustls_pemfile::pkcs8_private_keys(key_file)?;
-    }
-    
-    if keys.is_empty() {
-        return Err(AppError::TlsConfig("无法读取私钥，支持的格式：ECC、RSA 或 PKCS8".into()));
-    }
-    
-    // 构建 TLS 配置
-    let config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(cert_chain, PrivateKey(keys[0].clone()))?;
-    
-    info!("成功加载证书和私钥");
-    Ok(config)
+// 镜像代理的完整路由
+fn proxy_routes(cfg: &mut web::ServiceConfig) {
+    cfg.route("/v2/", web::get().to(handlers::proxy_challenge))
+        .route("/auth/token", web::get().to(handlers::get_token))
+        .route("/health", web::get().to(handlers::health_check))
+        .route("/.well-known/acme-challenge/{token}", web::get().to(handlers::acme_challenge))
+        .route("/v2/{image_name:.*}/{path_type}/{reference:.+}",
+               web::route()
+               .guard(guard::Any(guard::Get()).or(guard::Head()))
+               .to(handlers::handle_request));
+}
+
+// HTTP 重定向路由，特殊情况下我们可能仍然希望重定向，而不是拒绝访问
+fn redirect_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+            web::scope("/v2")
+                .route("", web::get().to(handlers::redirect_to_https))
+                .route("/{tail:.*}", web::route().to(handlers::redirect_to_https))
+        )
+        .route("/auth/token", web::get().to(handlers::redirect_to_https))
+        .route("/health", web::get().to(handlers::redirect_to_https))
+        .route("/.well-known/acme-challenge/{token}", web::get().to(handlers::acme_challenge));
 }
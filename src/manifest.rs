@@ -0,0 +1,802 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::sync::Mutex;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use flate2::write::GzDecoder;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::config::ManifestSettings;
+use crate::error::AppError;
+
+pub const MEDIA_TYPE_OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const MEDIA_TYPE_DOCKER_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+pub const MEDIA_TYPE_OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const MEDIA_TYPE_DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+pub const MEDIA_TYPE_SCHEMA1: &str = "application/vnd.docker.distribution.manifest.v1+json";
+pub const MEDIA_TYPE_SCHEMA1_SIGNED: &str = "application/vnd.docker.distribution.manifest.v1+prettyjws";
+const MEDIA_TYPE_OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+const MEDIA_TYPE_DOCKER_CONFIG: &str = "application/vnd.docker.container.image.v1+json";
+const MEDIA_TYPE_OCI_LAYER: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+const MEDIA_TYPE_DOCKER_LAYER: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+const MEDIA_TYPE_OCI_LAYER_UNCOMPRESSED: &str = "application/vnd.oci.image.layer.v1.tar";
+const MEDIA_TYPE_DOCKER_LAYER_UNCOMPRESSED: &str = "application/vnd.docker.image.rootfs.diff.tar";
+
+// 本地生成的内容（过滤后的 manifest 索引、转换后的 manifest 和配置 blob）及其来源，按键保存
+struct LocalStore<T> {
+    entries: HashMap<String, T>,
+    order: VecDeque<String>,
+}
+
+impl<T: Clone> LocalStore<T> {
+    fn new() -> Self {
+        LocalStore {
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    // 超过容量时淘汰最早的条目，返回是否新增了条目
+    fn insert(&mut self, capacity: usize, key: &str, value: T) -> bool {
+        if self.entries.contains_key(key) {
+            return false;
+        }
+
+        self.entries.insert(key.to_string(), value);
+        self.order.push_back(key.to_string());
+
+        while self.order.len() > capacity.max(1) {
+            if let Some(oldest) = self.order.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    fn get(&self, key: &str) -> Option<T> {
+        self.entries.get(key).cloned()
+    }
+}
+
+// 本地生成内容的来源，用于确认客户端对来源 manifest 的拉取权限，以及内容被淘汰或服务重启后重新生成
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LocalOrigin {
+    pub source: String,            // 上游 manifest 的摘要
+    pub kind: LocalKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LocalKind {
+    Filtered,                      // 按平台过滤的 manifest 索引
+    Schema1 { oci: bool },         // schema1 转换生成的 manifest 和配置 blob
+}
+
+impl LocalKind {
+    // 请求来源 manifest 时的 Accept 头
+    pub fn accept(&self) -> String {
+        match self {
+            LocalKind::Filtered => format!("{MEDIA_TYPE_OCI_INDEX},{MEDIA_TYPE_DOCKER_LIST}"),
+            LocalKind::Schema1 { .. } => format!("{MEDIA_TYPE_SCHEMA1_SIGNED},{MEDIA_TYPE_SCHEMA1}"),
+        }
+    }
+}
+
+lazy_static! {
+    // 按注册表、仓库和摘要保存，供客户端随后按摘要拉取
+    static ref LOCAL_MANIFESTS: Mutex<LocalStore<(String, Vec<u8>)>> = Mutex::new(LocalStore::new());
+    static ref LOCAL_BLOBS: Mutex<LocalStore<(String, Vec<u8>)>> = Mutex::new(LocalStore::new());
+    // schema1 manifest 摘要和目标格式 -> 转换结果
+    static ref SCHEMA1_CONVERSIONS: Mutex<LocalStore<Conversion>> = Mutex::new(LocalStore::new());
+    // 本地内容的来源，首次使用时从 origins_file 加载
+    static ref ORIGINS: Mutex<Option<LocalStore<LocalOrigin>>> = Mutex::new(None);
+}
+
+// 本地内容按注册表和仓库隔离，同一摘要在其他仓库中不可见
+fn local_key(registry_key: &str, repository: &str, digest: &str) -> String {
+    format!("{registry_key}/{repository}@{digest}")
+}
+
+fn load_origins(manifest_settings: &ManifestSettings) -> LocalStore<LocalOrigin> {
+    let mut store = LocalStore::new();
+    if manifest_settings.origins_file.is_empty() {
+        return store;
+    }
+
+    let saved = std::fs::read(&manifest_settings.origins_file).ok()
+        .and_then(|content| match serde_json::from_slice::<Vec<(String, LocalOrigin)>>(&content) {
+            Ok(saved) => Some(saved),
+            Err(e) => {
+                warn!("无法解析本地内容来源文件 {}: {}", manifest_settings.origins_file, e);
+                None
+            }
+        })
+        .unwrap_or_default();
+    debug!("从 {} 加载了 {} 个本地内容来源", manifest_settings.origins_file, saved.len());
+    for (key, origin) in saved {
+        store.insert(manifest_settings.origin_entries, &key, origin);
+    }
+    store
+}
+
+// 写入临时文件后重命名，避免重启时读到写了一半的文件
+fn save_origins(path: &str, store: &LocalStore<LocalOrigin>) {
+    let saved: Vec<(&String, &LocalOrigin)> = store.order.iter()
+        .filter_map(|key| store.entries.get(key).map(|origin| (key, origin)))
+        .collect();
+    let temporary = format!("{path}.tmp");
+    let result = serde_json::to_vec(&saved)
+        .map_err(std::io::Error::other)
+        .and_then(|content| {
+            if let Some(parent) = std::path::Path::new(path).parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(&temporary, content)
+        })
+        .and_then(|_| std::fs::rename(&temporary, path));
+    if let Err(e) = result {
+        warn!("无法保存本地内容来源到 {}: {}", path, e);
+    }
+}
+
+fn record_origin(manifest_settings: &ManifestSettings, key: &str, origin: LocalOrigin) {
+    let mut origins = ORIGINS.lock().unwrap();
+    let store = origins.get_or_insert_with(|| load_origins(manifest_settings));
+    if store.insert(manifest_settings.origin_entries, key, origin) && !manifest_settings.origins_file.is_empty() {
+        save_origins(&manifest_settings.origins_file, store);
+    }
+}
+
+// 查找本地生成内容的来源，内容本身可能已被淘汰
+pub fn local_origin(manifest_settings: &ManifestSettings, registry_key: &str, repository: &str, digest: &str) -> Option<LocalOrigin> {
+    let mut origins = ORIGINS.lock().unwrap();
+    origins.get_or_insert_with(|| load_origins(manifest_settings))
+        .get(&local_key(registry_key, repository, digest))
+}
+
+// 是否需要缓冲 manifest 内容进行检查
+pub fn inspection_enabled(manifest_settings: &ManifestSettings) -> bool {
+    !manifest_settings.platforms.is_empty() || needs_config(manifest_settings)
+}
+
+// 是否需要检查镜像配置
+pub fn needs_config(manifest_settings: &ManifestSettings) -> bool {
+    manifest_settings.reject_root || !manifest_settings.required_labels.is_empty()
+}
+
+// 确定 manifest 类型：优先使用 Content-Type，其次使用内容中的 mediaType 字段
+pub fn media_type(content_type: Option<&str>, body: &[u8]) -> String {
+    let from_header = content_type
+        .map(|value| value.split(';').next().unwrap_or("").trim().to_string())
+        .filter(|value| !value.is_empty() && value != "application/json");
+
+    from_header.unwrap_or_else(|| {
+        let Ok(manifest) = serde_json::from_slice::<Value>(body) else {
+            return String::new();
+        };
+        // schema1 manifest 没有 mediaType 字段
+        if manifest.get("schemaVersion").and_then(Value::as_u64) == Some(1) {
+            return MEDIA_TYPE_SCHEMA1.to_string();
+        }
+        manifest.get("mediaType").and_then(Value::as_str).map(String::from).unwrap_or_default()
+    })
+}
+
+pub fn is_schema1(media_type: &str) -> bool {
+    media_type == MEDIA_TYPE_SCHEMA1 || media_type == MEDIA_TYPE_SCHEMA1_SIGNED
+}
+
+pub fn is_index(media_type: &str) -> bool {
+    media_type == MEDIA_TYPE_OCI_INDEX || media_type == MEDIA_TYPE_DOCKER_LIST
+}
+
+pub fn is_image_manifest(media_type: &str) -> bool {
+    media_type == MEDIA_TYPE_OCI_MANIFEST || media_type == MEDIA_TYPE_DOCKER_MANIFEST
+}
+
+// 计算内容的 sha256 摘要
+pub fn digest(body: &[u8]) -> String {
+    let hash = Sha256::digest(body);
+    format!("sha256:{}", hash.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+// 平台格式为 os/arch 或 os/arch/variant
+fn platform_matches(platform: &Value, wanted: &str) -> bool {
+    let mut parts = wanted.split('/');
+    let (Some(os), Some(arch)) = (parts.next(), parts.next()) else {
+        return false;
+    };
+    let variant = parts.next();
+
+    platform.get("os").and_then(Value::as_str) == Some(os)
+        && platform.get("architecture").and_then(Value::as_str) == Some(arch)
+        && variant.is_none_or(|variant| platform.get("variant").and_then(Value::as_str) == Some(variant))
+}
+
+// 按平台过滤 manifest 索引，内容有变化时返回新的内容，过滤后没有剩余条目时返回错误
+pub fn filter_platforms(body: &[u8], platforms: &[String]) -> Result<Option<Vec<u8>>, AppError> {
+    let mut index: Value = serde_json::from_slice(body)
+        .map_err(|e| AppError::ManifestInvalid(format!("无法解析 manifest 索引: {e}")))?;
+
+    let Some(manifests) = index.get_mut("manifests").and_then(Value::as_array_mut) else {
+        return Ok(None);
+    };
+
+    let original_len = manifests.len();
+    manifests.retain(|entry| {
+        entry.get("platform")
+            .is_some_and(|platform| platforms.iter().any(|wanted| platform_matches(platform, wanted)))
+    });
+
+    if manifests.is_empty() {
+        return Err(AppError::Denied(format!("镜像不包含允许的平台: {}", platforms.join(", "))));
+    }
+    if manifests.len() == original_len {
+        return Ok(None);
+    }
+
+    debug!("manifest 索引按平台过滤: {} -> {} 个条目", original_len, manifests.len());
+    serde_json::to_vec(&index)
+        .map(Some)
+        .map_err(|e| AppError::ManifestInvalid(format!("无法序列化 manifest 索引: {e}")))
+}
+
+// 保存本地生成的 manifest 及其来源
+pub fn store_manifest(
+    manifest_settings: &ManifestSettings,
+    registry_key: &str,
+    repository: &str,
+    digest: &str,
+    media_type: &str,
+    body: &[u8],
+    origin: LocalOrigin,
+) {
+    let key = local_key(registry_key, repository, digest);
+    LOCAL_MANIFESTS.lock().unwrap().insert(manifest_settings.cache_entries, &key, (media_type.to_string(), body.to_vec()));
+    record_origin(manifest_settings, &key, origin);
+}
+
+// 查找本地生成的 manifest，返回 (mediaType, 内容)
+pub fn get_manifest(registry_key: &str, repository: &str, digest: &str) -> Option<(String, Vec<u8>)> {
+    LOCAL_MANIFESTS.lock().unwrap().get(&local_key(registry_key, repository, digest))
+}
+
+// 查找本地生成的 blob，返回 (mediaType, 内容)
+pub fn get_blob(registry_key: &str, repository: &str, digest: &str) -> Option<(String, Vec<u8>)> {
+    LOCAL_BLOBS.lock().unwrap().get(&local_key(registry_key, repository, digest))
+}
+
+// 获取镜像 manifest 引用的配置 blob 摘要
+pub fn config_digest(body: &[u8]) -> Option<String> {
+    serde_json::from_slice::<Value>(body)
+        .ok()?
+        .get("config")?
+        .get("digest")?
+        .as_str()
+        .map(String::from)
+}
+
+// 检查镜像配置：是否以 root 运行、是否包含必需的标签
+pub fn check_config(manifest_settings: &ManifestSettings, config_body: &[u8]) -> Result<(), AppError> {
+    let image_config: Value = serde_json::from_slice(config_body)
+        .map_err(|e| AppError::ManifestInvalid(format!("无法解析镜像配置: {e}")))?;
+    let config = image_config.get("config");
+
+    if manifest_settings.reject_root {
+        let user = config.and_then(|c| c.get("User")).and_then(Value::as_str).unwrap_or("");
+        let name = user.split(':').next().unwrap_or("");
+        if name.is_empty() || name == "root" || name == "0" {
+            info!("镜像以 root 用户运行 (User: {:?})", user);
+            return Err(AppError::Denied("镜像以 root 用户运行，已被策略拒绝".to_string()));
+        }
+    }
+
+    let labels = config.and_then(|c| c.get("Labels")).and_then(Value::as_object);
+    let missing: Vec<&str> = manifest_settings.required_labels.iter()
+        .filter(|label| !labels.is_some_and(|labels| labels.contains_key(label.as_str())))
+        .map(String::as_str)
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(AppError::Denied(format!("镜像缺少必需的标签: {}", missing.join(", "))));
+    }
+
+    Ok(())
+}
+
+// 客户端的 Accept 头是否包含 schema1；没有 Accept 头的旧客户端按接受 schema1 处理
+pub fn accepts_schema1(accept_values: &[&str]) -> bool {
+    accept_values.is_empty()
+        || accept_values.iter()
+            .flat_map(|value| value.split(','))
+            .map(|value| value.split(';').next().unwrap_or("").trim())
+            .any(is_schema1)
+}
+
+// 客户端只接受 OCI 格式时转换为 OCI manifest，否则转换为 Docker v2 manifest
+pub fn prefers_oci(accept_values: &[&str]) -> bool {
+    let types: Vec<&str> = accept_values.iter()
+        .flat_map(|value| value.split(','))
+        .map(|value| value.split(';').next().unwrap_or("").trim())
+        .collect();
+    types.contains(&MEDIA_TYPE_OCI_MANIFEST) && !types.contains(&MEDIA_TYPE_DOCKER_MANIFEST)
+}
+
+// schema1 manifest 中的一层，按从底层到顶层的顺序排列
+pub struct Schema1Layer {
+    pub blob_sum: String,
+    pub v1_compatibility: Value,
+    pub throwaway: bool,
+}
+
+// 解析 schema1 manifest，fsLayers 和 history 中第一个条目是最顶层
+pub fn parse_schema1(body: &[u8]) -> Result<Vec<Schema1Layer>, String> {
+    let manifest: Value = serde_json::from_slice(body)
+        .map_err(|e| format!("无法解析 schema1 manifest: {e}"))?;
+
+    let fs_layers = manifest.get("fsLayers").and_then(Value::as_array)
+        .ok_or("schema1 manifest 缺少 fsLayers")?;
+    let history = manifest.get("history").and_then(Value::as_array)
+        .ok_or("schema1 manifest 缺少 history")?;
+    if fs_layers.is_empty() || fs_layers.len() != history.len() {
+        return Err("schema1 manifest 的 fsLayers 与 history 数量不一致".to_string());
+    }
+
+    fs_layers.iter().zip(history).rev()
+        .map(|(layer, entry)| {
+            let blob_sum = layer.get("blobSum").and_then(Value::as_str)
+                .ok_or("schema1 manifest 缺少 blobSum")?;
+            let v1_compatibility: Value = entry.get("v1Compatibility").and_then(Value::as_str)
+                .ok_or("schema1 manifest 缺少 v1Compatibility")
+                .and_then(|raw| serde_json::from_str(raw).map_err(|_| "无法解析 v1Compatibility"))?;
+            let throwaway = v1_compatibility.get("throwaway").and_then(Value::as_bool).unwrap_or(false);
+
+            Ok(Schema1Layer {
+                blob_sum: blob_sum.to_string(),
+                v1_compatibility,
+                throwaway,
+            })
+        })
+        .collect()
+}
+
+// schema1 manifest 的摘要按去掉签名后的原始内容计算，与注册表返回的 Docker-Content-Digest 一致
+// 签名的 protected 头记录了原始内容的长度 (formatLength) 和签名之后的结尾 (formatTail)
+pub fn schema1_digest(body: &[u8]) -> String {
+    let payload = serde_json::from_slice::<Value>(body).ok()
+        .and_then(|manifest| manifest.pointer("/signatures/0/protected")?.as_str().map(String::from))
+        .and_then(|protected| URL_SAFE_NO_PAD.decode(protected.trim_end_matches('=')).ok())
+        .and_then(|protected| serde_json::from_slice::<Value>(&protected).ok())
+        .and_then(|protected| {
+            let length = usize::try_from(protected.get("formatLength")?.as_u64()?).ok()?;
+            let tail = URL_SAFE_NO_PAD.decode(protected.get("formatTail")?.as_str()?.trim_end_matches('=')).ok()?;
+            let mut payload = body.get(..length)?.to_vec();
+            payload.extend_from_slice(&tail);
+            Some(payload)
+        });
+
+    match payload {
+        Some(payload) => digest(&payload),
+        None => digest(body),
+    }
+}
+
+// 转换结果的缓存键，签名不同但内容相同的 manifest 使用同一个转换结果
+pub fn conversion_key(schema1_body: &[u8], oci: bool) -> String {
+    format!("{}|{}", schema1_digest(schema1_body), if oci { "oci" } else { "docker" })
+}
+
+// 查找已转换的结果
+pub fn get_conversion(key: &str) -> Option<Conversion> {
+    SCHEMA1_CONVERSIONS.lock().unwrap().get(key)
+}
+
+// 写入时计算 sha256 摘要
+struct HashWriter(Sha256);
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+enum DigestState {
+    Pending(Vec<u8>),
+    Gzip(Box<GzDecoder<HashWriter>>),
+    Plain(Sha256),
+}
+
+// 逐块计算层的 diff_id（解压后内容的摘要），未压缩的层直接计算摘要
+pub struct DiffIdDigester {
+    state: DigestState,
+}
+
+impl Default for DiffIdDigester {
+    fn default() -> Self {
+        DiffIdDigester { state: DigestState::Pending(Vec::new()) }
+    }
+}
+
+impl DiffIdDigester {
+    pub fn update(&mut self, chunk: &[u8]) -> Result<(), String> {
+        // 收到至少两个字节后才能根据 gzip 魔数判断层是否压缩
+        if let DigestState::Pending(pending) = &mut self.state {
+            pending.extend_from_slice(chunk);
+            if pending.len() < 2 {
+                return Ok(());
+            }
+            let pending = std::mem::take(pending);
+            self.state = if pending.starts_with(&[0x1f, 0x8b]) {
+                DigestState::Gzip(Box::new(GzDecoder::new(HashWriter(Sha256::new()))))
+            } else {
+                DigestState::Plain(Sha256::new())
+            };
+            return self.update(&pending);
+        }
+
+        match &mut self.state {
+            DigestState::Gzip(decoder) => decoder.write_all(chunk).map_err(|e| format!("无法解压镜像层: {e}")),
+            DigestState::Plain(hasher) => {
+                hasher.update(chunk);
+                Ok(())
+            }
+            DigestState::Pending(_) => Ok(()),
+        }
+    }
+
+    // 层是否经过 gzip 压缩
+    pub fn compressed(&self) -> bool {
+        matches!(self.state, DigestState::Gzip(_))
+    }
+
+    pub fn finish(self) -> Result<String, String> {
+        let hash = match self.state {
+            DigestState::Gzip(decoder) => decoder.finish().map_err(|e| format!("无法解压镜像层: {e}"))?.0.finalize(),
+            DigestState::Plain(hasher) => hasher.finalize(),
+            DigestState::Pending(pending) => Sha256::digest(&pending),
+        };
+        Ok(format!("sha256:{}", hash.iter().map(|b| format!("{:02x}", b)).collect::<String>()))
+    }
+}
+
+// 下载 schema1 的一层后得到的信息
+pub struct LayerDigest {
+    pub diff_id: String,
+    pub size: u64,                 // 压缩后的大小，即 blob 的大小
+    pub compressed: bool,
+}
+
+// schema1 manifest 转换生成的 manifest 和镜像配置
+#[derive(Clone)]
+pub struct Conversion {
+    pub manifest_type: &'static str,
+    pub manifest_body: Vec<u8>,
+    pub config_type: &'static str,
+    pub config_body: Vec<u8>,
+}
+
+// 根据 schema1 的层信息生成镜像配置和新的 manifest，digests 中只包含非 throwaway 的层
+pub fn convert_schema1(layers: &[Schema1Layer], digests: &[LayerDigest], oci: bool) -> Result<Conversion, String> {
+    let top = layers.last().ok_or("schema1 manifest 没有镜像层")?;
+    if layers.iter().filter(|layer| !layer.throwaway).count() != digests.len() {
+        return Err("schema1 镜像层与 diff_id 数量不一致".to_string());
+    }
+
+    // 以最顶层的 v1Compatibility 为基础生成镜像配置
+    let mut config = top.v1_compatibility.clone();
+    let config_object = config.as_object_mut().ok_or("v1Compatibility 格式错误")?;
+    for field in ["id", "parent", "Size", "parent_id", "layer_id", "throwaway"] {
+        config_object.remove(field);
+    }
+
+    let history: Vec<Value> = layers.iter()
+        .map(|layer| {
+            let v1 = &layer.v1_compatibility;
+            let created_by = v1.pointer("/container_config/Cmd")
+                .and_then(Value::as_array)
+                .map(|cmd| cmd.iter().filter_map(Value::as_str).collect::<Vec<_>>().join(" "))
+                .unwrap_or_default();
+
+            let mut entry = json!({ "created_by": created_by });
+            for field in ["created", "author", "comment"] {
+                if let Some(value) = v1.get(field) {
+                    entry[field] = value.clone();
+                }
+            }
+            if layer.throwaway {
+                entry["empty_layer"] = json!(true);
+            }
+            entry
+        })
+        .collect();
+
+    let diff_ids: Vec<&str> = digests.iter().map(|layer| layer.diff_id.as_str()).collect();
+    config_object.insert("rootfs".to_string(), json!({ "type": "layers", "diff_ids": diff_ids }));
+    config_object.insert("history".to_string(), Value::Array(history));
+
+    let config_body = serde_json::to_vec(&config).map_err(|e| format!("无法序列化镜像配置: {e}"))?;
+    let config_digest = digest(&config_body);
+
+    let (manifest_type, config_type) = if oci {
+        (MEDIA_TYPE_OCI_MANIFEST, MEDIA_TYPE_OCI_CONFIG)
+    } else {
+        (MEDIA_TYPE_DOCKER_MANIFEST, MEDIA_TYPE_DOCKER_CONFIG)
+    };
+
+    let layer_descriptors: Vec<Value> = layers.iter()
+        .filter(|layer| !layer.throwaway)
+        .zip(digests)
+        .map(|(layer, digest)| {
+            let layer_type = match (oci, digest.compressed) {
+                (true, true) => MEDIA_TYPE_OCI_LAYER,
+                (true, false) => MEDIA_TYPE_OCI_LAYER_UNCOMPRESSED,
+                (false, true) => MEDIA_TYPE_DOCKER_LAYER,
+                (false, false) => MEDIA_TYPE_DOCKER_LAYER_UNCOMPRESSED,
+            };
+            json!({
+                "mediaType": layer_type,
+                "size": digest.size,
+                "digest": layer.blob_sum,
+            })
+        })
+        .collect();
+
+    let manifest = json!({
+        "schemaVersion": 2,
+        "mediaType": manifest_type,
+        "config": {
+            "mediaType": config_type,
+            "size": config_body.len(),
+            "digest": config_digest,
+        },
+        "layers": layer_descriptors,
+    });
+    let manifest_body = serde_json::to_vec(&manifest).map_err(|e| format!("无法序列化 manifest: {e}"))?;
+
+    debug!("schema1 manifest 已转换: {} (配置 {})", digest(&manifest_body), config_digest);
+    Ok(Conversion { manifest_type, manifest_body, config_type, config_body })
+}
+
+// 缓存转换结果，转换后的 manifest 和配置 blob 保存在请求的仓库下，并记录来源
+pub fn store_conversion(
+    manifest_settings: &ManifestSettings,
+    registry_key: &str,
+    repository: &str,
+    key: &str,
+    conversion: &Conversion,
+    origin: LocalOrigin,
+) {
+    let capacity = manifest_settings.cache_entries;
+    let manifest_key = local_key(registry_key, repository, &digest(&conversion.manifest_body));
+    let config_key = local_key(registry_key, repository, &digest(&conversion.config_body));
+
+    LOCAL_BLOBS.lock().unwrap().insert(capacity, &config_key, (conversion.config_type.to_string(), conversion.config_body.clone()));
+    LOCAL_MANIFESTS.lock().unwrap().insert(capacity, &manifest_key, (conversion.manifest_type.to_string(), conversion.manifest_body.clone()));
+    SCHEMA1_CONVERSIONS.lock().unwrap().insert(capacity, key, conversion.clone());
+    record_origin(manifest_settings, &config_key, origin.clone());
+    record_origin(manifest_settings, &manifest_key, origin);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn index() -> Vec<u8> {
+        json!({
+            "schemaVersion": 2,
+            "mediaType": MEDIA_TYPE_OCI_INDEX,
+            "manifests": [
+                {"digest": "sha256:amd64", "platform": {"os": "linux", "architecture": "amd64"}},
+                {"digest": "sha256:armv7", "platform": {"os": "linux", "architecture": "arm", "variant": "v7"}},
+                {"digest": "sha256:arm64", "platform": {"os": "linux", "architecture": "arm64", "variant": "v8"}},
+                {"digest": "sha256:attestation", "annotations": {"vnd.docker.reference.type": "attestation-manifest"}},
+            ],
+        }).to_string().into_bytes()
+    }
+
+    fn kept(body: &[u8]) -> Vec<String> {
+        let index: Value = serde_json::from_slice(body).unwrap();
+        index["manifests"].as_array().unwrap().iter()
+            .map(|entry| entry["digest"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn settings(reject_root: bool, required_labels: &[&str]) -> ManifestSettings {
+        ManifestSettings {
+            reject_root,
+            required_labels: required_labels.iter().map(|label| label.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn filter_keeps_matching_platforms() {
+        let platforms = vec!["linux/amd64".to_string(), "linux/arm/v7".to_string()];
+        let filtered = filter_platforms(&index(), &platforms).unwrap().unwrap();
+        assert_eq!(kept(&filtered), vec!["sha256:amd64", "sha256:armv7"]);
+
+        // 不指定 variant 时匹配所有 variant
+        let filtered = filter_platforms(&index(), &["linux/arm64".to_string()]).unwrap().unwrap();
+        assert_eq!(kept(&filtered), vec!["sha256:arm64"]);
+        assert!(filter_platforms(&index(), &["linux/arm/v6".to_string()]).is_err());
+    }
+
+    #[test]
+    fn filter_leaves_unchanged_content_alone() {
+        let single = json!({"manifests": [{"digest": "sha256:amd64", "platform": {"os": "linux", "architecture": "amd64"}}]});
+        assert_eq!(filter_platforms(single.to_string().as_bytes(), &["linux/amd64".to_string()]).unwrap(), None);
+        assert_eq!(filter_platforms(b"{}", &["linux/amd64".to_string()]).unwrap(), None);
+        assert!(filter_platforms(b"not json", &["linux/amd64".to_string()]).is_err());
+    }
+
+    #[test]
+    fn config_checks() {
+        let root = json!({"config": {"User": "", "Labels": {"org.opencontainers.image.source": "x"}}}).to_string();
+        let numeric_root = json!({"config": {"User": "0:0"}}).to_string();
+        let app = json!({"config": {"User": "app:app", "Labels": {"org.opencontainers.image.source": "x"}}}).to_string();
+
+        assert!(check_config(&settings(true, &[]), root.as_bytes()).is_err());
+        assert!(check_config(&settings(true, &[]), numeric_root.as_bytes()).is_err());
+        assert!(check_config(&settings(true, &[]), app.as_bytes()).is_ok());
+        assert!(check_config(&settings(false, &[]), root.as_bytes()).is_ok());
+
+        let labels = settings(false, &["org.opencontainers.image.source", "maintainer"]);
+        let missing = check_config(&labels, app.as_bytes()).unwrap_err().to_string();
+        assert!(missing.contains("maintainer") && !missing.contains("source"), "{missing}");
+        assert!(check_config(&settings(false, &[]), b"not json").is_err());
+    }
+
+    #[test]
+    fn media_type_and_config_digest() {
+        assert_eq!(media_type(Some("application/vnd.oci.image.index.v1+json; charset=utf-8"), b""), MEDIA_TYPE_OCI_INDEX);
+        assert_eq!(media_type(Some("application/json"), &index()), MEDIA_TYPE_OCI_INDEX);
+        assert_eq!(media_type(None, b"{}"), "");
+
+        let manifest = json!({"config": {"digest": "sha256:config"}}).to_string();
+        assert_eq!(config_digest(manifest.as_bytes()).as_deref(), Some("sha256:config"));
+        assert_eq!(digest(b""), "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+    }
+
+    #[test]
+    fn local_content_is_scoped_to_repository() {
+        let settings = ManifestSettings { origins_file: String::new(), ..Default::default() };
+        let origin = LocalOrigin { source: "sha256:source".to_string(), kind: LocalKind::Filtered };
+        store_manifest(&settings, "docker.io", "library/scoped", "sha256:scoped", MEDIA_TYPE_OCI_INDEX, b"{}", origin.clone());
+
+        assert!(get_manifest("docker.io", "library/scoped", "sha256:scoped").is_some());
+        assert!(get_manifest("docker.io", "library/other", "sha256:scoped").is_none());
+        assert!(get_manifest("ghcr.io", "library/scoped", "sha256:scoped").is_none());
+        assert_eq!(local_origin(&settings, "docker.io", "library/scoped", "sha256:scoped"), Some(origin));
+        assert_eq!(local_origin(&settings, "docker.io", "library/other", "sha256:scoped"), None);
+    }
+
+    #[test]
+    fn origins_survive_restart() {
+        let path = std::env::temp_dir().join(format!("docxy-origins-{}.json", std::process::id()));
+        let settings = ManifestSettings { origins_file: path.to_string_lossy().to_string(), origin_entries: 2, ..Default::default() };
+
+        let mut store = LocalStore::new();
+        for digest in ["sha256:a", "sha256:b", "sha256:c"] {
+            let origin = LocalOrigin { source: format!("{digest}-source"), kind: LocalKind::Filtered };
+            store.insert(settings.origin_entries, &local_key("docker.io", "library/nginx", digest), origin);
+        }
+        save_origins(&settings.origins_file, &store);
+
+        // 重新加载后保留最新的条目和顺序
+        let loaded = load_origins(&settings);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.order, store.order);
+        assert_eq!(loaded.get(&local_key("docker.io", "library/nginx", "sha256:a")), None);
+        assert_eq!(loaded.get(&local_key("docker.io", "library/nginx", "sha256:c")).unwrap().source, "sha256:c-source");
+    }
+
+    // 注册表返回的带签名 schema1 manifest：最顶层是 throwaway 层，中间一层未压缩，最底层为 gzip 压缩
+    const SCHEMA1_SIGNED: &str = include_str!("../tests/fixtures/schema1_signed.json");
+    // 去掉签名后的原始内容的摘要
+    const SCHEMA1_DIGEST: &str = "sha256:34703f10cb811e665269be93ff080fde4c3bce9acbc6d437352c5465e8b5db6a";
+    const BASE_LAYER: &[u8] = &[
+        31, 139, 8, 0, 0, 0, 0, 0, 2, 255, 75, 74, 44, 78, 85, 200, 73, 172, 76, 45, 178, 82, 208, 79, 45, 73,
+        214, 207, 47, 214, 45, 74, 205, 73, 5, 10, 115, 1, 0, 192, 187, 167, 204, 28, 0, 0, 0,
+    ];
+    const BASE_DIFF_ID: &str = "sha256:785ead495953b20619dbff8edf30de251171047f4474ee960d7f53fcb32629cc";
+    const APP_LAYER: &[u8] = b"uncompressed layer: /app/run.sh\n";
+
+    fn layer_digest(blob: &[u8], chunk_size: usize) -> LayerDigest {
+        let mut digester = DiffIdDigester::default();
+        for chunk in blob.chunks(chunk_size) {
+            digester.update(chunk).unwrap();
+        }
+        let compressed = digester.compressed();
+        LayerDigest { diff_id: digester.finish().unwrap(), size: blob.len() as u64, compressed }
+    }
+
+    #[test]
+    fn parse_schema1_orders_layers_from_base() {
+        let layers = parse_schema1(SCHEMA1_SIGNED.as_bytes()).unwrap();
+        let blob_sums: Vec<&str> = layers.iter().map(|layer| layer.blob_sum.as_str()).collect();
+        assert_eq!(blob_sums, vec![digest(BASE_LAYER), digest(APP_LAYER), "sha256:a3ed95caeb02ffe68cdd9fd84406680ae93d633cb16422d00e8a7c22955b46d4".to_string()]);
+        assert_eq!(layers.iter().map(|layer| layer.throwaway).collect::<Vec<_>>(), vec![false, false, true]);
+        assert_eq!(layers[1].v1_compatibility["id"], "a2");
+
+        let mismatched = json!({"fsLayers": [{"blobSum": "sha256:a"}], "history": []}).to_string();
+        assert!(parse_schema1(mismatched.as_bytes()).is_err());
+        assert!(parse_schema1(b"not json").is_err());
+    }
+
+    #[test]
+    fn schema1_digest_ignores_signatures() {
+        assert_eq!(schema1_digest(SCHEMA1_SIGNED.as_bytes()), SCHEMA1_DIGEST);
+        assert_ne!(digest(SCHEMA1_SIGNED.as_bytes()), SCHEMA1_DIGEST);
+        assert_eq!(conversion_key(SCHEMA1_SIGNED.as_bytes(), true), format!("{SCHEMA1_DIGEST}|oci"));
+
+        // 没有签名时按完整内容计算
+        let unsigned = json!({"schemaVersion": 1, "fsLayers": [], "history": []}).to_string();
+        assert_eq!(schema1_digest(unsigned.as_bytes()), digest(unsigned.as_bytes()));
+    }
+
+    #[test]
+    fn diff_ids_of_compressed_and_uncompressed_layers() {
+        // 逐字节输入也能识别 gzip 魔数
+        for chunk_size in [1, 7, BASE_LAYER.len()] {
+            let base = layer_digest(BASE_LAYER, chunk_size);
+            assert!(base.compressed);
+            assert_eq!(base.diff_id, BASE_DIFF_ID);
+        }
+
+        // 未压缩的层 diff_id 与 blob 摘要相同
+        let app = layer_digest(APP_LAYER, 1);
+        assert!(!app.compressed);
+        assert_eq!(app.diff_id, digest(APP_LAYER));
+
+        assert_eq!(DiffIdDigester::default().finish().unwrap(), digest(b""));
+        let mut truncated = DiffIdDigester::default();
+        truncated.update(&BASE_LAYER[..20]).unwrap();
+        assert!(truncated.finish().is_err());
+    }
+
+    #[test]
+    fn convert_schema1_builds_config_and_layer_types() {
+        let layers = parse_schema1(SCHEMA1_SIGNED.as_bytes()).unwrap();
+        let digests = vec![layer_digest(BASE_LAYER, 16), layer_digest(APP_LAYER, 16)];
+
+        let conversion = convert_schema1(&layers, &digests, false).unwrap();
+        assert_eq!(conversion.manifest_type, MEDIA_TYPE_DOCKER_MANIFEST);
+        let manifest: Value = serde_json::from_slice(&conversion.manifest_body).unwrap();
+        assert_eq!(manifest["config"]["digest"], digest(&conversion.config_body));
+        assert_eq!(manifest["config"]["mediaType"], MEDIA_TYPE_DOCKER_CONFIG);
+        let layer_types: Vec<&str> = manifest["layers"].as_array().unwrap().iter()
+            .map(|layer| layer["mediaType"].as_str().unwrap())
+            .collect();
+        assert_eq!(layer_types, vec![MEDIA_TYPE_DOCKER_LAYER, MEDIA_TYPE_DOCKER_LAYER_UNCOMPRESSED]);
+        assert_eq!(manifest["layers"][0]["digest"], digest(BASE_LAYER));
+        assert_eq!(manifest["layers"][1]["size"], APP_LAYER.len());
+
+        let config: Value = serde_json::from_slice(&conversion.config_body).unwrap();
+        assert_eq!(config["rootfs"]["diff_ids"], json!([BASE_DIFF_ID, digest(APP_LAYER)]));
+        assert_eq!(config["config"]["User"], "app");
+        assert!(config.get("id").is_none() && config.get("parent").is_none() && config.get("throwaway").is_none());
+        let history = config["history"].as_array().unwrap();
+        assert_eq!(history.len(), 3);
+        assert_eq!(history[0]["created_by"], "/bin/sh -c #(nop) ADD file:os-release in /");
+        assert_eq!(history[2]["empty_layer"], true);
+        assert_eq!(history[2]["author"], "ops@example.com");
+
+        let oci = convert_schema1(&layers, &digests, true).unwrap();
+        let manifest: Value = serde_json::from_slice(&oci.manifest_body).unwrap();
+        assert_eq!(manifest["mediaType"], MEDIA_TYPE_OCI_MANIFEST);
+        assert_eq!(manifest["layers"][0]["mediaType"], MEDIA_TYPE_OCI_LAYER);
+        assert_eq!(manifest["layers"][1]["mediaType"], MEDIA_TYPE_OCI_LAYER_UNCOMPRESSED);
+
+        assert!(convert_schema1(&layers, &digests[..1], false).is_err());
+    }
+}
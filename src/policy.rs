@@ -0,0 +1,161 @@
+use std::net::IpAddr;
+use log::debug;
+
+use crate::config::{PolicyAction, PolicyRule, PolicySettings};
+use crate::forwarded;
+
+// 策略评估结果
+#[derive(Debug, PartialEq)]
+pub enum PolicyDecision {
+    Allow,
+    Deny(String),
+}
+
+// 在请求上游之前评估镜像访问策略
+// client_ip 为客户端地址，repository 为路由后的仓库名，path_type 为 manifests 或 blobs，reference 为标签或摘要
+pub fn evaluate(policy: &PolicySettings, client_ip: Option<IpAddr>, registry_key: &str, repository: &str, path_type: &str, reference: &str) -> PolicyDecision {
+    if !policy.enabled {
+        return PolicyDecision::Allow;
+    }
+
+    let is_manifest = path_type == "manifests";
+    let is_digest = reference.contains(':');
+    let tag = if is_manifest && !is_digest { Some(reference) } else { None };
+
+    if policy.require_digest && tag.is_some() {
+        return PolicyDecision::Deny(format!("{registry_key}/{repository}:{reference} 必须按摘要拉取 (image@sha256:...)"));
+    }
+
+    for (index, rule) in policy.rules.iter().enumerate() {
+        if rule_matches(rule, client_ip, registry_key, repository, tag) {
+            debug!("镜像 {}/{}:{} 匹配策略规则 #{}: {:?}", registry_key, repository, reference, index + 1, rule.action);
+            return match rule.action {
+                PolicyAction::Allow => PolicyDecision::Allow,
+                PolicyAction::Deny => PolicyDecision::Deny(rule.description.clone()
+                    .unwrap_or_else(|| format!("{registry_key}/{repository}:{reference} 被策略规则 #{} 拒绝", index + 1))),
+            };
+        }
+    }
+
+    match policy.default_action {
+        PolicyAction::Allow => PolicyDecision::Allow,
+        PolicyAction::Deny => PolicyDecision::Deny(format!("{registry_key}/{repository} 不在允许列表中")),
+    }
+}
+
+fn rule_matches(rule: &PolicyRule, client_ip: Option<IpAddr>, registry_key: &str, repository: &str, tag: Option<&str>) -> bool {
+    let registry_matches = rule.registries.is_empty()
+        || rule.registries.iter().any(|pattern| wildcard_match(pattern, registry_key));
+    let repository_matches = rule.repositories.is_empty()
+        || rule.repositories.iter().any(|pattern| wildcard_match(pattern, repository));
+
+    // 标签条件只作用于按标签拉取的 manifest，blob 和按摘要拉取的请求只按注册表、仓库和客户端匹配
+    let tag_matches = rule.tags.is_empty() || tag.is_none_or(|tag| {
+        rule.tags.iter().any(|pattern| {
+            if pattern == "semver" { is_semver(tag) } else { wildcard_match(pattern, tag) }
+        })
+    });
+
+    let client_matches = rule.clients.is_empty()
+        || client_ip.is_some_and(|ip| rule.clients.iter().any(|cidr| forwarded::cidr_contains(cidr, ip)));
+
+    registry_matches && repository_matches && tag_matches && client_matches
+}
+
+// 通配符匹配：* 匹配任意字符序列（包括 /），? 匹配单个字符
+pub fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p + 1;
+            t = star_t + 1;
+            backtrack = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// 语义化版本标签，例如 1.2.3、v1.2、1.2.3-rc.1
+fn is_semver(tag: &str) -> bool {
+    let version = tag.strip_prefix('v').unwrap_or(tag);
+    // 预发布和构建后缀不能为空，例如 1.2.3- 不是有效版本
+    let (core, suffix) = match version.split_once(['-', '+']) {
+        Some((core, suffix)) => (core, Some(suffix)),
+        None => (version, None),
+    };
+    let parts: Vec<&str> = core.split('.').collect();
+
+    suffix.is_none_or(|suffix| !suffix.is_empty())
+        && (2..=3).contains(&parts.len())
+        && parts.iter().all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(action: PolicyAction, repositories: &[&str], tags: &[&str]) -> PolicyRule {
+        PolicyRule {
+            action,
+            registries: Vec::new(),
+            repositories: repositories.iter().map(|s| s.to_string()).collect(),
+            tags: tags.iter().map(|s| s.to_string()).collect(),
+            clients: Vec::new(),
+            description: None,
+        }
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(wildcard_match("library/*", "library/nginx"));
+        assert!(wildcard_match("*", "a/b/c"));
+        assert!(wildcard_match("*/nginx", "library/nginx"));
+        assert!(wildcard_match("1.2?", "1.25"));
+        assert!(wildcard_match("*.pkg.dev", "europe-docker.pkg.dev"));
+        assert!(wildcard_match("a*b*c", "axxbyyc"));
+        assert!(!wildcard_match("library/*", "myorg/nginx"));
+        assert!(!wildcard_match("1.2?", "1.2"));
+        assert!(!wildcard_match("latest", "latest-alpine"));
+    }
+
+    #[test]
+    fn semver_tags() {
+        for tag in ["1.2", "1.2.3", "v1.2.3", "1.2.3-rc.1", "1.2.3+build.5"] {
+            assert!(is_semver(tag), "{tag}");
+        }
+        for tag in ["latest", "1", "1.2.3.4", "1..2", "v", "1.2-", "1.x.3", "alpine-1.2"] {
+            assert!(!is_semver(tag), "{tag}");
+        }
+    }
+
+    #[test]
+    fn tag_rules_only_apply_to_tagged_manifests() {
+        let policy = PolicySettings {
+            enabled: true,
+            default_action: PolicyAction::Deny,
+            require_digest: false,
+            rules: vec![rule(PolicyAction::Allow, &["library/*"], &["semver"])],
+        };
+
+        assert_eq!(evaluate(&policy, None, "docker.io", "library/nginx", "manifests", "1.25.3"), PolicyDecision::Allow);
+        assert!(matches!(evaluate(&policy, None, "docker.io", "library/nginx", "manifests", "latest"), PolicyDecision::Deny(_)));
+        // 按摘要拉取和 blob 没有标签，忽略 tags 条件，只按仓库匹配
+        assert_eq!(evaluate(&policy, None, "docker.io", "library/nginx", "blobs", "sha256:abc"), PolicyDecision::Allow);
+        assert_eq!(evaluate(&policy, None, "docker.io", "library/nginx", "manifests", "sha256:abc"), PolicyDecision::Allow);
+        assert!(matches!(evaluate(&policy, None, "docker.io", "myorg/app", "blobs", "sha256:abc"), PolicyDecision::Deny(_)));
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+
+use crate::config::{QuotaAction, RateLimitSettings};
+use crate::error::AppError;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+// 超过这个数量时清理已过期的计数窗口
+const MAX_WINDOWS: usize = 4096;
+
+// 固定一分钟窗口内的请求计数
+struct RequestWindow {
+    started: Instant,
+    count: u32,
+}
+
+// 从上游 ratelimit-* 响应头获得的配额状态
+struct QuotaState {
+    remaining: u64,
+    limit: u64,
+    window_secs: u64,
+    updated: Instant,
+    // 配额紧张时下一个允许拉取 manifest 的时间
+    next_slot: Instant,
+}
+
+impl QuotaState {
+    // 配额恢复一次请求所需的时间
+    fn slot_interval(&self) -> Duration {
+        Duration::from_secs_f64(self.window_secs as f64 / self.limit.max(1) as f64)
+    }
+}
+
+lazy_static! {
+    static ref REQUEST_WINDOWS: Mutex<HashMap<String, RequestWindow>> = Mutex::new(HashMap::new());
+    static ref UPSTREAM_QUOTAS: Mutex<HashMap<String, QuotaState>> = Mutex::new(HashMap::new());
+}
+
+// 计数并检查一个键的请求速率，超出时返回需要等待的秒数
+fn hit(key: String, limit: u32) -> Option<u64> {
+    let now = Instant::now();
+    let mut windows = REQUEST_WINDOWS.lock().unwrap();
+
+    if windows.len() > MAX_WINDOWS {
+        windows.retain(|_, window| now.duration_since(window.started) < WINDOW);
+    }
+
+    let window = windows.entry(key).or_insert(RequestWindow { started: now, count: 0 });
+    if now.duration_since(window.started) >= WINDOW {
+        window.started = now;
+        window.count = 0;
+    }
+
+    if window.count >= limit {
+        let remaining = WINDOW.saturating_sub(now.duration_since(window.started));
+        return Some(remaining.as_secs().max(1));
+    }
+
+    window.count += 1;
+    None
+}
+
+// 按用户和客户端 IP 限制每分钟的请求数
+pub fn check_client(rate_limit: &RateLimitSettings, user: Option<&str>, client_ip: Option<&str>) -> Result<(), AppError> {
+    if !rate_limit.enabled {
+        return Ok(());
+    }
+
+    if let Some(user) = user
+        && rate_limit.per_user_requests_per_minute > 0
+        && let Some(retry_after) = hit(format!("user:{user}"), rate_limit.per_user_requests_per_minute) {
+        warn!("用户 {} 请求过于频繁", user);
+        return Err(AppError::TooManyRequests(format!("user {user} exceeded {} requests per minute", rate_limit.per_user_requests_per_minute), retry_after));
+    }
+
+    if let Some(client_ip) = client_ip
+        && rate_limit.per_ip_requests_per_minute > 0
+        && let Some(retry_after) = hit(format!("ip:{client_ip}"), rate_limit.per_ip_requests_per_minute) {
+        warn!("客户端 {} 请求过于频繁", client_ip);
+        return Err(AppError::TooManyRequests(format!("client {client_ip} exceeded {} requests per minute", rate_limit.per_ip_requests_per_minute), retry_after));
+    }
+
+    Ok(())
+}
+
+// 解析 "76;w=21600" 格式的响应头，返回 (数量, 窗口秒数)
+fn parse_ratelimit_header(value: &str) -> Option<(u64, Option<u64>)> {
+    let mut parts = value.split(';');
+    let count = parts.next()?.trim().parse().ok()?;
+    let window = parts
+        .filter_map(|part| part.trim().strip_prefix("w="))
+        .find_map(|window| window.parse().ok());
+    Some((count, window))
+}
+
+// 记录上游响应中的配额信息
+pub fn record_upstream_quota(rate_limit: &RateLimitSettings, registry_key: &str, response: &reqwest::Response) {
+    if !rate_limit.upstream_quota.enabled {
+        return;
+    }
+
+    let header = |name: &str| response.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_ratelimit_header);
+
+    let Some((mut remaining, remaining_window)) = header("ratelimit-remaining") else {
+        return;
+    };
+    if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        remaining = 0;
+    }
+    let (limit, limit_window) = header("ratelimit-limit").unwrap_or((remaining, None));
+    let window_secs = remaining_window.or(limit_window).unwrap_or(21600);
+
+    debug!("注册表 {} 剩余配额: {}/{} (窗口 {} 秒)", registry_key, remaining, limit, window_secs);
+    if remaining <= rate_limit.upstream_quota.min_remaining {
+        info!("注册表 {} 剩余配额不足: {}/{}", registry_key, remaining, limit);
+    }
+
+    let now = Instant::now();
+    let mut quotas = UPSTREAM_QUOTAS.lock().unwrap();
+    let next_slot = quotas.get(registry_key).map(|state| state.next_slot).unwrap_or(now);
+    quotas.insert(registry_key.to_string(), QuotaState {
+        remaining,
+        limit,
+        window_secs,
+        updated: now,
+        next_slot,
+    });
+}
+
+// 上游配额不足时保护 manifest 拉取：拒绝，或按配额恢复速度排队
+pub async fn guard_upstream_quota(rate_limit: &RateLimitSettings, registry_key: &str) -> Result<(), AppError> {
+    let quota = &rate_limit.upstream_quota;
+    if !quota.enabled {
+        return Ok(());
+    }
+
+    let wait = {
+        let now = Instant::now();
+        let mut quotas = UPSTREAM_QUOTAS.lock().unwrap();
+        let Some(state) = quotas.get_mut(registry_key) else {
+            return Ok(());
+        };
+
+        // 配额信息已过期或仍然充足
+        if now.duration_since(state.updated).as_secs() >= state.window_secs || state.remaining > quota.min_remaining {
+            return Ok(());
+        }
+
+        let interval = state.slot_interval();
+        if quota.action == QuotaAction::Reject {
+            warn!("注册表 {} 剩余配额 {} 不足，拒绝拉取 manifest", registry_key, state.remaining);
+            return Err(AppError::TooManyRequests(
+                format!("upstream quota for {registry_key} is nearly exhausted ({} remaining)", state.remaining),
+                interval.as_secs().max(1),
+            ));
+        }
+
+        let slot = state.next_slot.max(now);
+        let wait = slot.duration_since(now);
+        if wait.as_secs() > quota.max_wait_secs {
+            warn!("注册表 {} 配额排队超过 {} 秒，拒绝拉取 manifest", registry_key, quota.max_wait_secs);
+            return Err(AppError::TooManyRequests(
+                format!("upstream quota for {registry_key} is nearly exhausted, queue is full"),
+                wait.as_secs().max(1),
+            ));
+        }
+
+        state.next_slot = slot + interval;
+        wait
+    };
+
+    if !wait.is_zero() {
+        info!("注册表 {} 配额不足，排队等待 {:?}", registry_key, wait);
+        tokio::time::sleep(wait).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UpstreamQuotaSettings;
+
+    fn quota_settings(action: QuotaAction) -> RateLimitSettings {
+        RateLimitSettings {
+            upstream_quota: UpstreamQuotaSettings { enabled: true, action, ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    fn set_quota(registry_key: &str, remaining: u64, limit: u64, window_secs: u64) {
+        let now = Instant::now();
+        UPSTREAM_QUOTAS.lock().unwrap().insert(registry_key.to_string(), QuotaState {
+            remaining,
+            limit,
+            window_secs,
+            updated: now,
+            next_slot: now,
+        });
+    }
+
+    #[test]
+    fn window_rolls_over_after_a_minute() {
+        let key = "test:rollover".to_string();
+        assert_eq!(hit(key.clone(), 2), None);
+        assert_eq!(hit(key.clone(), 2), None);
+        let retry_after = hit(key.clone(), 2).unwrap();
+        assert!((1..=60).contains(&retry_after));
+
+        // 窗口结束后重新计数
+        let mut windows = REQUEST_WINDOWS.lock().unwrap();
+        let window = windows.get_mut(&key).unwrap();
+        window.started = window.started.checked_sub(WINDOW).unwrap();
+        drop(windows);
+        assert_eq!(hit(key.clone(), 2), None);
+        assert_eq!(REQUEST_WINDOWS.lock().unwrap()[&key].count, 1);
+    }
+
+    #[test]
+    fn parses_ratelimit_headers() {
+        assert_eq!(parse_ratelimit_header("76;w=21600"), Some((76, Some(21600))));
+        assert_eq!(parse_ratelimit_header("100"), Some((100, None)));
+        assert_eq!(parse_ratelimit_header("x;w=60"), None);
+    }
+
+    #[actix_web::test]
+    async fn low_quota_is_rejected() {
+        let settings = quota_settings(QuotaAction::Reject);
+        set_quota("reject.example.com", 20, 100, 600);
+        assert!(guard_upstream_quota(&settings, "reject.example.com").await.is_ok());
+
+        set_quota("reject.example.com", 5, 100, 600);
+        match guard_upstream_quota(&settings, "reject.example.com").await {
+            Err(AppError::TooManyRequests(_, retry_after)) => assert_eq!(retry_after, 6),
+            other => panic!("{other:?}"),
+        }
+
+        // 没有记录配额的注册表不受限制
+        assert!(guard_upstream_quota(&settings, "unknown.example.com").await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn low_quota_is_queued_until_the_wait_is_too_long() {
+        let mut settings = quota_settings(QuotaAction::Queue);
+        set_quota("queue.example.com", 0, 1, 100);
+
+        // 第一个请求立即通过并占用下一个时间段，之后的请求需要等待 100 秒，超过 max_wait_secs
+        assert!(guard_upstream_quota(&settings, "queue.example.com").await.is_ok());
+        match guard_upstream_quota(&settings, "queue.example.com").await {
+            Err(AppError::TooManyRequests(_, retry_after)) => assert!((99..=100).contains(&retry_after)),
+            other => panic!("{other:?}"),
+        }
+
+        // 等待时间在 max_wait_secs 以内时排队后通过
+        settings.upstream_quota.max_wait_secs = 1;
+        set_quota("queue.example.com", 0, 1000, 10);
+        let started = Instant::now();
+        for _ in 0..3 {
+            guard_upstream_quota(&settings, "queue.example.com").await.unwrap();
+        }
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+}
@@ -0,0 +1,144 @@
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use actix_web::web;
+use log::{error, info, warn};
+
+use crate::config::{ServerSettings, SharedSettings};
+use crate::tls;
+use crate::upstream;
+use crate::validation;
+
+// 配置文件可能使用的扩展名，与 config::File::with_name 的查找规则一致
+const CONFIG_EXTENSIONS: &[&str] = &["", "toml", "json", "yaml", "yml", "ini", "ron", "json5"];
+
+// 重新加载配置：先完整解析、校验并创建上游客户端，任何一步失败都保留当前配置
+pub fn reload(shared: &SharedSettings, config_files: &[String]) -> bool {
+    let mut new_settings = match validation::load(config_files) {
+        Ok(settings) => settings,
+        Err(problems) => {
+            for problem in &problems {
+                error!("配置错误: {}", problem);
+            }
+            error!("配置重新加载失败，继续使用当前配置");
+            return false;
+        }
+    };
+
+    if let Err(e) = upstream::init_clients(&new_settings.registry) {
+        error!("配置重新加载失败，继续使用当前配置: {}", e);
+        return false;
+    }
+
+    // 监听相关的配置在启动时生效，修改后需要重启；trusted_proxies 和 public_url 立即生效
+    let current = shared.load();
+    let reverted = keep_listener_settings(&mut new_settings.server, &current.server);
+    if !reverted.is_empty() {
+        warn!("server 配置 {} 的修改需要重启后生效", reverted.join(", "));
+    }
+
+    info!("配置已重新加载: {} 个注册表, {} 个用户, 访问策略{}",
+        new_settings.registry.registries.len(),
+        new_settings.auth.users.len(),
+        if new_settings.policy.enabled { "已启用" } else { "已禁用" });
+    shared.store(new_settings);
+
+    // 证书可能已经续期或换了路径，失败时继续使用之前的证书
+    tls::reload(&shared.load());
+    true
+}
+
+// 保留启动时的监听配置，返回被还原的字段名
+fn keep_listener_settings(new: &mut ServerSettings, current: &ServerSettings) -> Vec<&'static str> {
+    let mut reverted = Vec::new();
+    if new.http_port != current.http_port {
+        new.http_port = current.http_port;
+        reverted.push("http_port");
+    }
+    if new.https_port != current.https_port {
+        new.https_port = current.https_port;
+        reverted.push("https_port");
+    }
+    if new.http_enabled != current.http_enabled {
+        new.http_enabled = current.http_enabled;
+        reverted.push("http_enabled");
+    }
+    if new.https_enabled != current.https_enabled {
+        new.https_enabled = current.https_enabled;
+        reverted.push("https_enabled");
+    }
+    if new.bind_addresses != current.bind_addresses {
+        new.bind_addresses = current.bind_addresses.clone();
+        reverted.push("bind_addresses");
+    }
+    if new.unix_sockets != current.unix_sockets {
+        new.unix_sockets = current.unix_sockets.clone();
+        reverted.push("unix_sockets");
+    }
+    if new.behind_proxy != current.behind_proxy {
+        new.behind_proxy = current.behind_proxy;
+        reverted.push("behind_proxy");
+    }
+    reverted
+}
+
+// 配置文件的修改时间，用于检测文件变化
+fn config_fingerprint(config_files: &[String]) -> Vec<Option<SystemTime>> {
+    let names: Vec<&str> = if config_files.is_empty() {
+        vec!["config/default", "config/local"]
+    } else {
+        config_files.iter().map(String::as_str).collect()
+    };
+
+    names.iter()
+        .flat_map(|name| CONFIG_EXTENSIONS.iter().map(move |ext| {
+            if ext.is_empty() { name.to_string() } else { format!("{name}.{ext}") }
+        }))
+        .map(|path| std::fs::metadata(Path::new(&path)).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+// 收到 SIGHUP 或配置文件修改时重新加载配置
+pub fn spawn(shared: web::Data<SharedSettings>, config_files: Vec<String>) {
+    #[cfg(unix)]
+    {
+        let shared = shared.clone();
+        let config_files = config_files.clone();
+        actix_web::rt::spawn(async move {
+            use tokio::signal::unix::{signal, SignalKind};
+
+            let mut hangup = match signal(SignalKind::hangup()) {
+                Ok(hangup) => hangup,
+                Err(e) => {
+                    error!("无法监听 SIGHUP 信号: {}", e);
+                    return;
+                }
+            };
+
+            while hangup.recv().await.is_some() {
+                info!("收到 SIGHUP，重新加载配置");
+                reload(&shared, &config_files);
+            }
+        });
+    }
+
+    let reload_settings = shared.load().reload.clone();
+    if !reload_settings.watch_files {
+        return;
+    }
+
+    info!("监视配置文件修改，检查间隔 {} 秒", reload_settings.interval_secs);
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(reload_settings.interval_secs.max(1)));
+        let mut fingerprint = config_fingerprint(&config_files);
+
+        loop {
+            interval.tick().await;
+            let current = config_fingerprint(&config_files);
+            if current != fingerprint {
+                info!("配置文件已修改，重新加载配置");
+                fingerprint = current;
+                reload(&shared, &config_files);
+            }
+        }
+    });
+}
@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use actix_web::{web, HttpRequest};
+use log::debug;
+
+use crate::config::{PathRewrite, RegistryConfig, RegistrySettings};
+use crate::forwarded::ClientInfo;
+
+// 注册表路由所需的请求信息
+#[derive(Debug, Default, Clone)]
+pub struct RouteHints {
+    pub host: Option<String>,       // 请求的主机名（不含端口，小写）
+    pub namespace: Option<String>,  // containerd 镜像请求携带的 ns 查询参数，标识原始注册表
+}
+
+impl RouteHints {
+    pub fn from_request(req: &HttpRequest, client: &ClientInfo) -> Self {
+        let namespace = web::Query::<HashMap<String, String>>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.get("ns").cloned())
+            .filter(|ns| !ns.is_empty());
+
+        RouteHints {
+            host: Some(strip_port(&client.host)),
+            namespace,
+        }
+    }
+}
+
+// 去掉 Host 头中的端口，支持 IPv6 地址形式 [::1]:443
+fn strip_port(host: &str) -> String {
+    let host = if host.starts_with('[') {
+        host.split_inclusive(']').next().unwrap_or(host)
+    } else {
+        host.split(':').next().unwrap_or(host)
+    };
+    host.to_ascii_lowercase()
+}
+
+// Docker Hub 的默认注册表键及其别名
+pub const DOCKER_HUB_KEY: &str = "docker.io";
+const DOCKER_HUB_ALIASES: [&str; 3] = ["docker.io", "index.docker.io", "registry-1.docker.io"];
+
+// 是否为 Docker Hub 的注册表键或别名
+pub fn is_docker_hub(registry_key: &str) -> bool {
+    DOCKER_HUB_ALIASES.iter().any(|alias| alias.eq_ignore_ascii_case(registry_key))
+}
+
+// Docker Hub 的官方镜像位于 library 命名空间下，例如 nginx -> library/nginx
+pub fn normalize_docker_hub_name(image_name: &str) -> String {
+    if image_name.is_empty() || image_name.contains('/') {
+        image_name.to_string()
+    } else {
+        format!("library/{image_name}")
+    }
+}
+
+// 根据注册表配置获取目标注册表和修改后的镜像名称
+// 路径改写和 Docker Hub 名称规范化在匹配之后进行，日志、凭据查找和访问控制都使用最终的名称
+pub fn get_target_registry(registry_settings: &RegistrySettings, hints: &RouteHints, image_name: &str) -> (String, String, String) {
+    let (target_registry, image_name, registry_key) = resolve_registry(registry_settings, hints, image_name);
+
+    let image_name = match registry_settings.registries.get(&registry_key) {
+        Some(registry_config) => rewrite_path(&registry_config.rewrites, &image_name),
+        None => image_name,
+    };
+
+    if is_docker_hub(&registry_key) {
+        return (target_registry, normalize_docker_hub_name(&image_name), registry_key);
+    }
+
+    (target_registry, image_name, registry_key)
+}
+
+fn resolve_registry(registry_settings: &RegistrySettings, hints: &RouteHints, image_name: &str) -> (String, String, String) {
+    // 默认使用上游注册表
+    let default_registry = registry_settings.upstream_registry.clone();
+    let default_registry_key = DOCKER_HUB_KEY.to_string();
+
+    // 按键排序，保证匹配结果与 HashMap 的遍历顺序无关
+    let mut registries: Vec<(&String, &RegistryConfig)> = registry_settings.registries.iter().collect();
+    registries.sort_by(|a, b| a.0.cmp(b.0));
+
+    // containerd 的 ns 参数与注册表键一致时直接使用该注册表，镜像名称保持不变
+    if let Some(namespace) = &hints.namespace {
+        if let Some((registry_key, registry_config)) = registry_settings.registries.get_key_value(namespace.as_str()) {
+            return (registry_config.url.clone(), image_name.to_string(), registry_key.clone());
+        }
+        if let Some((registry_key, registry_config)) = match_glob(&registries, namespace) {
+            return (expand_url(&registry_config.url, namespace), image_name.to_string(), registry_key.clone());
+        }
+        if is_docker_hub(namespace) {
+            return docker_hub_registry(registry_settings, image_name);
+        }
+    }
+
+    // 按请求的 Host 路由，镜像名称保持不变
+    if let Some(host) = &hints.host {
+        for (registry_key, registry_config) in &registries {
+            if registry_config.hosts.iter().any(|h| h.eq_ignore_ascii_case(host)) {
+                return (registry_config.url.clone(), image_name.to_string(), (*registry_key).clone());
+            }
+        }
+    }
+    
+    // 检查镜像名称是否包含需要重映射的注册表部分，多个键匹配时选择最长的前缀
+    let longest_prefix = registries.iter()
+        .filter(|(registry_key, _)| !registry_key.contains('*'))
+        .filter(|(registry_key, _)| image_name.starts_with(&format!("{}/", registry_key)))
+        .max_by_key(|(registry_key, _)| registry_key.len());
+
+    if let Some((registry_key, registry_config)) = longest_prefix {
+        // 找到匹配的注册表，提取实际镜像路径
+        let actual_image_path = image_name[registry_key.len() + 1..].to_string();
+        return (registry_config.url.clone(), actual_image_path, (*registry_key).clone());
+    }
+
+    // 通配符键匹配镜像名称的第一段（主机名），转发到匹配到的主机
+    if let Some((host, actual_image_path)) = image_name.split_once('/')
+        && let Some((registry_key, registry_config)) = match_glob(&registries, host) {
+        return (expand_url(&registry_config.url, host), actual_image_path.to_string(), registry_key.clone());
+    }
+
+    // 未单独配置的 Docker Hub 别名前缀，如 index.docker.io/nginx
+    for alias in DOCKER_HUB_ALIASES {
+        if let Some(actual_image_path) = image_name.strip_prefix(alias).and_then(|rest| rest.strip_prefix('/')) {
+            return docker_hub_registry(registry_settings, actual_image_path);
+        }
+    }
+    
+    // 没有找到匹配的映射，返回原始信息
+    (default_registry, image_name.to_string(), default_registry_key)
+}
+
+// 查找匹配主机名的通配符键，多个键匹配时选择字面字符最多（最具体）的键
+fn match_glob<'a>(registries: &[(&'a String, &'a RegistryConfig)], host: &str) -> Option<(&'a String, &'a RegistryConfig)> {
+    registries.iter()
+        .filter(|(registry_key, _)| registry_key.contains('*') && glob_match(registry_key, host))
+        .max_by_key(|(registry_key, _)| registry_key.chars().filter(|c| *c != '*').count())
+        .map(|(registry_key, registry_config)| (*registry_key, *registry_config))
+}
+
+// 主机名通配符匹配，* 匹配单个域名标签内的一个或多个字符，例如 *.dkr.ecr.*.amazonaws.com
+pub fn glob_match(pattern: &str, host: &str) -> bool {
+    let pattern_labels: Vec<&str> = pattern.split('.').collect();
+    let host_labels: Vec<&str> = host.split('.').collect();
+
+    pattern_labels.len() == host_labels.len()
+        && pattern_labels.iter().zip(&host_labels).all(|(p, h)| label_match(p.as_bytes(), h.as_bytes()))
+}
+
+fn label_match(pattern: &[u8], label: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => label.is_empty(),
+        // * 至少匹配一个字符
+        Some((b'*', rest)) => (1..=label.len()).any(|i| label_match(rest, &label[i..])),
+        Some((c, rest)) => label.first().is_some_and(|l| l.eq_ignore_ascii_case(c)) && label_match(rest, &label[1..]),
+    }
+}
+
+// 通配符注册表的 url 可以使用 {host} 占位符，替换为匹配到的主机名
+fn expand_url(url: &str, host: &str) -> String {
+    url.replace("{host}", &host.to_ascii_lowercase())
+}
+
+// 按规则改写镜像路径，例如 mirror/foo -> myorg/foo；多条规则匹配时选择最长的 from
+pub fn rewrite_path(rewrites: &[PathRewrite], image_name: &str) -> String {
+    let matched = rewrites.iter()
+        .filter(|rule| {
+            rule.from.is_empty()
+                || image_name == rule.from
+                || image_name.starts_with(&format!("{}/", rule.from))
+        })
+        .max_by_key(|rule| rule.from.len());
+
+    let Some(rule) = matched else {
+        return image_name.to_string();
+    };
+
+    let rest = image_name[rule.from.len()..].trim_start_matches('/');
+    let rewritten = match (rule.to.is_empty(), rest.is_empty()) {
+        (true, _) => rest.to_string(),
+        (false, true) => rule.to.clone(),
+        (false, false) => format!("{}/{}", rule.to, rest),
+    };
+
+    if rewritten != image_name {
+        debug!("改写镜像路径: {} -> {}", image_name, rewritten);
+    }
+    rewritten
+}
+
+// Docker Hub 的目标地址：优先使用 docker.io 注册表配置，否则使用上游注册表
+fn docker_hub_registry(registry_settings: &RegistrySettings, image_name: &str) -> (String, String, String) {
+    let target_registry = registry_settings.registries
+        .get(DOCKER_HUB_KEY)
+        .map(|config| config.url.clone())
+        .unwrap_or_else(|| registry_settings.upstream_registry.clone());
+    (target_registry, image_name.to_string(), DOCKER_HUB_KEY.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RegistryApiVersion;
+    use serde_json::json;
+
+    fn registry_settings() -> RegistrySettings {
+        serde_json::from_value(json!({
+            "upstream_registry": "https://registry-1.docker.io",
+            "registries": {
+                "ghcr.io": {"url": "https://ghcr.io", "api_version": "v2", "hosts": ["ghcr.mirror.example.com"]},
+                "quay.io": {"url": "https://quay.io", "api_version": "v2"},
+            },
+        })).unwrap()
+    }
+
+    fn hints(host: &str) -> RouteHints {
+        RouteHints { host: Some(strip_port(host)), ..Default::default() }
+    }
+
+    #[test]
+    fn strip_port_keeps_ipv6_brackets() {
+        assert_eq!(strip_port("Registry.Example.com:5000"), "registry.example.com");
+        assert_eq!(strip_port("registry.example.com"), "registry.example.com");
+        assert_eq!(strip_port("[::1]:443"), "[::1]");
+        assert_eq!(strip_port("[::1]"), "[::1]");
+    }
+
+    #[test]
+    fn host_header_selects_registry_without_changing_image() {
+        let settings = registry_settings();
+        let (target, image, key) = get_target_registry(&settings, &hints("GHCR.mirror.example.com:443"), "owner/tool");
+        assert_eq!((target.as_str(), image.as_str(), key.as_str()), ("https://ghcr.io", "owner/tool", "ghcr.io"));
+    }
+
+    #[test]
+    fn unknown_host_falls_back_to_image_prefix() {
+        let settings = registry_settings();
+        let (target, image, key) = get_target_registry(&settings, &hints("proxy.example.com"), "quay.io/org/app");
+        assert_eq!((target.as_str(), image.as_str(), key.as_str()), ("https://quay.io", "org/app", "quay.io"));
+
+        let (target, image, key) = get_target_registry(&settings, &hints("proxy.example.com"), "library/nginx");
+        assert_eq!((target.as_str(), image.as_str(), key.as_str()), ("https://registry-1.docker.io", "library/nginx", "docker.io"));
+    }
+
+    #[test]
+    fn containerd_namespace_takes_precedence() {
+        let settings = registry_settings();
+        let request = actix_web::test::TestRequest::with_uri("/v2/org/app/manifests/latest?ns=quay.io")
+            .insert_header(("Host", "ghcr.mirror.example.com"))
+            .to_http_request();
+        let hints = RouteHints::from_request(&request, &ClientInfo::from_request(&request, &[]));
+        assert_eq!(hints.namespace.as_deref(), Some("quay.io"));
+
+        let (target, image, key) = get_target_registry(&settings, &hints, "org/app");
+        assert_eq!((target.as_str(), image.as_str(), key.as_str()), ("https://quay.io", "org/app", "quay.io"));
+    }
+
+    #[test]
+    fn unknown_or_empty_namespace_is_ignored() {
+        let settings = registry_settings();
+        let request = actix_web::test::TestRequest::with_uri("/v2/org/app/manifests/latest?ns=")
+            .insert_header(("Host", "proxy.example.com"))
+            .to_http_request();
+        assert_eq!(RouteHints::from_request(&request, &ClientInfo::from_request(&request, &[])).namespace, None);
+
+        let hints = RouteHints { namespace: Some("registry.example.org".to_string()), ..hints("ghcr.mirror.example.com") };
+        let (_, _, key) = get_target_registry(&settings, &hints, "org/app");
+        assert_eq!(key, "ghcr.io");
+    }
+
+    #[test]
+    fn docker_hub_names_are_normalized() {
+        assert_eq!(normalize_docker_hub_name("nginx"), "library/nginx");
+        assert_eq!(normalize_docker_hub_name("library/nginx"), "library/nginx");
+        assert_eq!(normalize_docker_hub_name("bitnami/redis"), "bitnami/redis");
+        assert_eq!(normalize_docker_hub_name(""), "");
+        assert!(is_docker_hub("Index.Docker.io"));
+        assert!(!is_docker_hub("ghcr.io"));
+    }
+
+    #[test]
+    fn docker_hub_aliases_route_to_docker_hub() {
+        let settings = registry_settings();
+        for image in ["nginx", "docker.io/nginx", "index.docker.io/library/nginx", "registry-1.docker.io/nginx"] {
+            let (target, image, key) = get_target_registry(&settings, &hints("proxy.example.com"), image);
+            assert_eq!((target.as_str(), image.as_str(), key.as_str()), ("https://registry-1.docker.io", "library/nginx", "docker.io"));
+        }
+
+        let hints = RouteHints { namespace: Some("index.docker.io".to_string()), ..hints("proxy.example.com") };
+        let (_, image, key) = get_target_registry(&settings, &hints, "redis");
+        assert_eq!((image.as_str(), key.as_str()), ("library/redis", "docker.io"));
+    }
+
+    fn registry(url: &str, rewrites: &[(&str, &str)]) -> RegistryConfig {
+        RegistryConfig {
+            url: url.to_string(),
+            api_version: RegistryApiVersion::V2,
+            auth_url: None,
+            service: None,
+            mirrors: Vec::new(),
+            client: None,
+            blob_redirect: Default::default(),
+            hosts: Vec::new(),
+            rewrites: rewrites.iter()
+                .map(|(from, to)| PathRewrite { from: from.to_string(), to: to.to_string() })
+                .collect(),
+        }
+    }
+
+    fn rewrites(rules: &[(&str, &str)]) -> Vec<PathRewrite> {
+        registry("", rules).rewrites
+    }
+
+    #[test]
+    fn glob_matches_within_labels() {
+        assert!(glob_match("*.dkr.ecr.*.amazonaws.com", "123456789012.dkr.ecr.us-east-1.amazonaws.com"));
+        assert!(glob_match("*.example.com", "Registry.Example.COM"));
+        assert!(glob_match("registry-*.example.com", "registry-eu.example.com"));
+        // * 只匹配单个标签，不跨越点号
+        assert!(!glob_match("*.example.com", "a.b.example.com"));
+        assert!(!glob_match("*.example.com", "example.com"));
+        // ** 与 * 相同，只是要求至少两个字符
+        assert!(glob_match("**.example.com", "ab.example.com"));
+        assert!(!glob_match("**.example.com", "a.example.com"));
+        assert!(!glob_match("**.example.com", "a.b.example.com"));
+    }
+
+    #[test]
+    fn label_match_requires_at_least_one_character() {
+        assert!(label_match(b"*", b"a"));
+        assert!(!label_match(b"*", b""));
+        assert!(label_match(b"a*c", b"abbc"));
+        assert!(!label_match(b"a*c", b"ac"));
+        assert!(!label_match(b"abc", b"ab"));
+    }
+
+    #[test]
+    fn rewrite_path_uses_longest_matching_prefix() {
+        let rules = rewrites(&[("mirror", "myorg"), ("mirror/special", "other/team"), ("", "")]);
+        assert_eq!(rewrite_path(&rules, "mirror/foo"), "myorg/foo");
+        assert_eq!(rewrite_path(&rules, "mirror/special/foo"), "other/team/foo");
+        assert_eq!(rewrite_path(&rules, "mirror"), "myorg");
+        // 按路径段匹配，mirrored 不匹配 mirror
+        assert_eq!(rewrite_path(&rules, "mirrored/foo"), "mirrored/foo");
+
+        let strip = rewrites(&[("library", "")]);
+        assert_eq!(rewrite_path(&strip, "library/nginx"), "nginx");
+        assert_eq!(rewrite_path(&[], "library/nginx"), "library/nginx");
+    }
+
+    #[test]
+    fn wildcard_registry_expands_host_placeholder() {
+        let mut registries = HashMap::new();
+        registries.insert("*.dkr.ecr.*.amazonaws.com".to_string(), registry("https://{host}", &[]));
+        registries.insert("ghcr.io".to_string(), registry("https://ghcr.io", &[("mirror", "myorg")]));
+        let settings = RegistrySettings {
+            upstream_registry: "https://registry-1.docker.io".to_string(),
+            registries,
+            failover: Default::default(),
+            retry: Default::default(),
+            client: Default::default(),
+        };
+        let hints = RouteHints::default();
+
+        let (target, image, key) = get_target_registry(&settings, &hints, "1234.DKR.ecr.eu-west-1.amazonaws.com/app");
+        assert_eq!(target, "https://1234.dkr.ecr.eu-west-1.amazonaws.com");
+        assert_eq!(image, "app");
+        assert_eq!(key, "*.dkr.ecr.*.amazonaws.com");
+
+        let (target, image, key) = get_target_registry(&settings, &hints, "ghcr.io/mirror/tool");
+        assert_eq!((target.as_str(), image.as_str(), key.as_str()), ("https://ghcr.io", "myorg/tool", "ghcr.io"));
+
+        let (_, image, key) = get_target_registry(&settings, &hints, "nginx");
+        assert_eq!((image.as_str(), key.as_str()), ("library/nginx", "docker.io"));
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use actix_web::web::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+use lazy_static::lazy_static;
+use log::debug;
+
+use crate::config::BandwidthSettings;
+
+// 超过这个数量时清理不再使用的令牌桶
+const MAX_IDLE_BUCKETS: usize = 4096;
+
+// 令牌桶：按速率补充令牌，容量为一秒的流量；令牌不足时允许透支，按透支量等待
+#[derive(Debug)]
+struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    // 取出 n 个令牌，返回需要等待的时间
+    fn take(&mut self, n: usize) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.last_refill = now;
+
+        let rate = self.rate as f64;
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - n as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / rate)
+        }
+    }
+}
+
+lazy_static! {
+    static ref BUCKETS: Mutex<HashMap<String, Arc<Mutex<TokenBucket>>>> = Mutex::new(HashMap::new());
+}
+
+// 获取或创建令牌桶，配置的速率变化时更新
+fn bucket(key: String, rate: u64) -> Arc<Mutex<TokenBucket>> {
+    let mut buckets = BUCKETS.lock().unwrap();
+
+    if buckets.len() > MAX_IDLE_BUCKETS {
+        buckets.retain(|_, bucket| Arc::strong_count(bucket) > 1);
+    }
+
+    let bucket = buckets.entry(key)
+        .or_insert_with(|| Arc::new(Mutex::new(TokenBucket::new(rate))))
+        .clone();
+    bucket.lock().unwrap().rate = rate;
+    bucket
+}
+
+// 一次下载需要经过的所有令牌桶
+pub struct Throttle {
+    buckets: Vec<Arc<Mutex<TokenBucket>>>,
+}
+
+impl Throttle {
+    // 根据用户、客户端 IP 和注册表选择令牌桶，没有任何限制时返回 None
+    pub fn for_request(
+        bandwidth: &BandwidthSettings,
+        user: Option<&str>,
+        client_ip: Option<&str>,
+        registry_key: &str,
+    ) -> Option<Self> {
+        if !bandwidth.enabled {
+            return None;
+        }
+
+        let mut limits = vec![("global".to_string(), bandwidth.global_bytes_per_sec)];
+        if let Some(user) = user {
+            let rate = bandwidth.users.get(user).copied().unwrap_or(bandwidth.per_user_bytes_per_sec);
+            limits.push((format!("user:{user}"), rate));
+        }
+        if let Some(client_ip) = client_ip {
+            limits.push((format!("ip:{client_ip}"), bandwidth.per_ip_bytes_per_sec));
+        }
+        if let Some(rate) = bandwidth.registries.get(registry_key) {
+            limits.push((format!("registry:{registry_key}"), *rate));
+        }
+
+        let buckets: Vec<_> = limits.into_iter()
+            .filter(|(_, rate)| *rate > 0)
+            .map(|(key, rate)| bucket(key, rate))
+            .collect();
+
+        if buckets.is_empty() {
+            None
+        } else {
+            Some(Throttle { buckets })
+        }
+    }
+
+    // 所有令牌桶中最长的等待时间
+    fn delay(&self, n: usize) -> Duration {
+        self.buckets.iter()
+            .map(|bucket| bucket.lock().unwrap().take(n))
+            .max()
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+// 按令牌桶限制响应流的传输速率
+pub fn throttle_stream<S, E>(inner: S, throttle: Option<Throttle>) -> impl Stream<Item = Result<Bytes, E>>
+where
+    S: Stream<Item = Result<Bytes, E>>,
+{
+    stream::unfold((Box::pin(inner), throttle), |(mut inner, throttle)| async move {
+        let item = inner.next().await?;
+
+        if let (Ok(chunk), Some(throttle)) = (&item, &throttle) {
+            let delay = throttle.delay(chunk.len());
+            if !delay.is_zero() {
+                debug!("带宽限制，等待 {:?}", delay);
+                tokio::time::sleep(delay).await;
+            }
+        }
+
+        Some((item, (inner, throttle)))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn bandwidth(value: serde_json::Value) -> BandwidthSettings {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn bucket_starts_full_and_waits_for_overdraft() {
+        let mut bucket = TokenBucket::new(1000);
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+
+        // 透支 500 个令牌，按 1000/s 需要等待约 0.5 秒
+        let delay = bucket.take(500);
+        assert!(delay > Duration::from_millis(490) && delay <= Duration::from_millis(500), "{delay:?}");
+    }
+
+    #[test]
+    fn bucket_refills_up_to_one_second() {
+        let mut bucket = TokenBucket::new(1000);
+        bucket.take(1000);
+
+        // 经过很久也只补充到一秒的容量
+        bucket.last_refill -= Duration::from_secs(10);
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+        assert!(bucket.take(1) > Duration::ZERO);
+    }
+
+    #[test]
+    fn no_throttle_when_disabled_or_unlimited() {
+        let disabled = bandwidth(json!({ "global_bytes_per_sec": 1000 }));
+        assert!(Throttle::for_request(&disabled, Some("alice"), Some("10.0.0.1"), "docker.io").is_none());
+
+        let unlimited = bandwidth(json!({ "enabled": true }));
+        assert!(Throttle::for_request(&unlimited, Some("alice"), Some("10.0.0.1"), "docker.io").is_none());
+    }
+
+    #[test]
+    fn selects_user_ip_and_registry_buckets() {
+        let settings = bandwidth(json!({
+            "enabled": true,
+            "per_user_bytes_per_sec": 1000,
+            "per_ip_bytes_per_sec": 2000,
+            "users": { "throttle-test-unlimited": 0 },
+            "registries": { "throttle-test.io": 3000 },
+        }));
+
+        let throttle = Throttle::for_request(&settings, Some("throttle-test-user"), Some("192.0.2.10"), "throttle-test.io").unwrap();
+        assert_eq!(throttle.buckets.len(), 3);
+
+        // 用户覆盖为 0 时不限制该用户
+        let throttle = Throttle::for_request(&settings, Some("throttle-test-unlimited"), None, "docker.io");
+        assert!(throttle.is_none());
+    }
+
+    #[test]
+    fn delay_is_the_longest_of_all_buckets() {
+        let settings = bandwidth(json!({
+            "enabled": true,
+            "per_user_bytes_per_sec": 1000,
+            "per_ip_bytes_per_sec": 100,
+        }));
+
+        let throttle = Throttle::for_request(&settings, Some("throttle-test-delay"), Some("192.0.2.20"), "docker.io").unwrap();
+        // IP 桶只有 100 个令牌，透支 100 个需要等待约 1 秒
+        let delay = throttle.delay(200);
+        assert!(delay > Duration::from_millis(900), "{delay:?}");
+    }
+}
@@ -0,0 +1,475 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use actix_web::web;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+
+use crate::acme;
+use crate::config::{AcmeChallenge, Settings, SharedSettings};
+use crate::error::AppError;
+
+// 证书剩余有效期少于这个天数时输出警告
+const EXPIRY_WARNING_DAYS: i64 = 14;
+
+// 一个证书及其适用的主机名
+struct NamedCertificate {
+    hosts: Vec<String>,            // 小写主机名，可能以 *. 开头
+    key: Arc<CertifiedKey>,
+}
+
+// 当前使用的证书和证书文件的修改时间
+struct LoadedCertificates {
+    default: Option<Arc<CertifiedKey>>, // 只使用 ACME 且证书尚未签发时为空
+    named: Vec<NamedCertificate>,
+    fingerprint: Vec<Option<SystemTime>>,
+}
+
+impl LoadedCertificates {
+    // 精确匹配优先，其次是通配符证书，都不匹配时使用默认证书
+    fn select(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let Some(server_name) = server_name.map(|name| name.trim_end_matches('.').to_ascii_lowercase()) else {
+            return self.default.clone();
+        };
+
+        let exact = self.named.iter()
+            .find(|certificate| certificate.hosts.contains(&server_name));
+        let wildcard = || self.named.iter()
+            .find(|certificate| certificate.hosts.iter().any(|host| wildcard_matches(host, &server_name)));
+
+        exact.or_else(wildcard)
+            .map(|certificate| certificate.key.clone())
+            .or_else(|| self.default.clone())
+    }
+}
+
+// *.example.com 只匹配一级子域名，如 a.example.com，不匹配 example.com 和 a.b.example.com
+fn wildcard_matches(pattern: &str, server_name: &str) -> bool {
+    let Some(suffix) = pattern.strip_prefix("*.") else {
+        return false;
+    };
+    server_name.strip_suffix(suffix)
+        .and_then(|label| label.strip_suffix('.'))
+        .is_some_and(|label| !label.is_empty() && !label.contains('.'))
+}
+
+lazy_static! {
+    static ref CERTIFICATES: RwLock<Option<LoadedCertificates>> = RwLock::new(None);
+}
+
+// 每次握手时按 SNI 读取当前证书，重新加载证书后新连接立即使用新证书，已建立的连接不受影响
+struct CertificateResolver;
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        // ACME 服务器的 TLS-ALPN-01 验证只协商 acme-tls/1，使用验证专用的证书
+        if client_hello.alpn().is_some_and(|mut protocols| protocols.any(|protocol| protocol == acme::ALPN_PROTOCOL)) {
+            return acme::alpn_certificate(client_hello.server_name());
+        }
+        CERTIFICATES.read().unwrap().as_ref().and_then(|loaded| loaded.select(client_hello.server_name()))
+    }
+}
+
+// 加载证书并创建 HTTPS 服务使用的 TLS 配置
+pub fn server_config(settings: &Settings) -> Result<ServerConfig, AppError> {
+    *CERTIFICATES.write().unwrap() = Some(load_certificates(settings)?);
+
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(CertificateResolver));
+    // actix-web 会在前面加上 h2 和 http/1.1
+    if settings.acme.enabled && settings.acme.challenge == AcmeChallenge::TlsAlpn01 {
+        config.alpn_protocols = vec![acme::ALPN_PROTOCOL.to_vec()];
+    }
+    Ok(config)
+}
+
+// 重新加载所有证书，任何一个失败时继续使用之前的全部证书
+pub fn reload(settings: &Settings) -> bool {
+    if CERTIFICATES.read().unwrap().is_none() {
+        return false;
+    }
+
+    match load_certificates(settings) {
+        Ok(loaded) => {
+            *CERTIFICATES.write().unwrap() = Some(loaded);
+            info!("证书已重新加载");
+            true
+        }
+        Err(e) => {
+            error!("证书重新加载失败，继续使用当前证书: {}", e);
+            false
+        }
+    }
+}
+
+// 定期检查证书文件，修改后（如 acme.sh 续期）自动重新加载
+pub fn spawn(shared: web::Data<SharedSettings>) {
+    let tls = shared.load().tls.clone();
+    if !tls.watch_files || CERTIFICATES.read().unwrap().is_none() {
+        return;
+    }
+
+    info!("监视证书文件修改，检查间隔 {} 秒", tls.interval_secs);
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(Duration::from_secs(tls.interval_secs.max(1)));
+
+        loop {
+            interval.tick().await;
+            // 证书路径可能随配置重新加载而改变
+            let settings = shared.load();
+            let current = fingerprint(&settings);
+            let changed = CERTIFICATES.read().unwrap().as_ref()
+                .is_some_and(|loaded| loaded.fingerprint != current);
+            if changed {
+                info!("证书文件已修改，重新加载证书");
+                if !reload(&settings) {
+                    // 文件可能还没写完，记录本次的修改时间，避免每次检查都输出错误
+                    if let Some(loaded) = CERTIFICATES.write().unwrap().as_mut() {
+                        loaded.fingerprint = current;
+                    }
+                }
+            }
+        }
+    });
+}
+
+// 所有证书和私钥文件的修改时间
+fn fingerprint(settings: &Settings) -> Vec<Option<SystemTime>> {
+    let tls = &settings.tls;
+    let mut paths = vec![tls.cert_path.clone(), tls.key_path.clone()];
+    for certificate in &tls.certificates {
+        paths.push(certificate.cert_path.clone());
+        paths.push(certificate.key_path.clone());
+    }
+    if settings.acme.enabled {
+        let (cert_path, key_path) = acme::certificate_paths(&settings.acme);
+        paths.push(cert_path);
+        paths.push(key_path);
+    }
+
+    paths.iter()
+        .map(|path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+// 加载默认证书、按 SNI 选择的证书和 ACME 签发的证书；未配置默认证书时使用第一个证书
+fn load_certificates(settings: &Settings) -> Result<LoadedCertificates, AppError> {
+    let tls = &settings.tls;
+    let fingerprint = fingerprint(settings);
+    let default = if tls.cert_path.is_empty() && tls.key_path.is_empty() {
+        None
+    } else {
+        Some(load_certified_key(&tls.cert_path, &tls.key_path)?.0)
+    };
+
+    let mut named = Vec::new();
+    for certificate in &tls.certificates {
+        let (key, dns_names) = load_certified_key(&certificate.cert_path, &certificate.key_path)?;
+        let hosts: Vec<String> = if certificate.hosts.is_empty() { dns_names } else { certificate.hosts.clone() }
+            .iter()
+            .map(|host| host.trim_end_matches('.').to_ascii_lowercase())
+            .collect();
+        if hosts.is_empty() {
+            warn!("证书 {} 没有配置主机名，也没有 DNS 名称，只能作为默认证书", certificate.cert_path);
+        } else {
+            info!("证书 {} 用于: {}", certificate.cert_path, hosts.join(", "));
+        }
+        named.push(NamedCertificate { hosts, key });
+    }
+
+    // ACME 证书在签发前不存在，此时只能响应 TLS-ALPN-01 验证
+    let acme = &settings.acme;
+    if acme.enabled {
+        let (cert_path, key_path) = acme::certificate_paths(acme);
+        if Path::new(&cert_path).exists() {
+            let (key, _) = load_certified_key(&cert_path, &key_path)?;
+            let hosts = acme.domains.iter().map(|domain| domain.to_ascii_lowercase()).collect();
+            named.push(NamedCertificate { hosts, key });
+        } else {
+            info!("ACME 证书尚未签发: {}", cert_path);
+        }
+    }
+
+    let default = default.or_else(|| named.first().map(|certificate| certificate.key.clone()));
+    if default.is_none() && !acme.enabled {
+        return Err(AppError::TlsConfig("未配置证书".to_string()));
+    }
+    Ok(LoadedCertificates { default, named, fingerprint })
+}
+
+// 加载一对证书和私钥，同时返回证书中的 DNS 名称
+fn load_certified_key(cert_path: &str, key_path: &str) -> Result<(Arc<CertifiedKey>, Vec<String>), AppError> {
+    info!("正在加载证书: {}", cert_path);
+    info!("正在加载私钥: {}", key_path);
+
+    // 读取证书和密钥文件
+    let cert_file = &mut BufReader::new(File::open(cert_path)
+        .map_err(|e| AppError::TlsConfig(format!("无法打开证书文件 {cert_path}: {e}")))?);
+
+    let key_file = &mut BufReader::new(File::open(key_path)
+        .map_err(|e| AppError::TlsConfig(format!("无法打开私钥文件 {key_path}: {e}")))?);
+
+    // 解析证书
+    let cert_chain: Vec<Certificate> = rustls_pemfile::certs(cert_file)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if cert_chain.is_empty() {
+        return Err(AppError::TlsConfig(format!("证书文件 {cert_path} 中没有证书")));
+    }
+
+    // 尝试解析私钥（支持多种格式）
+    let mut keys = rustls_pemfile::ec_private_keys(key_file)?;
+
+    // 如果没有找到 ECC 私钥，尝试读取 RSA 私钥
+    if keys.is_empty() {
+        // 需要重新打开文件，因为前面的读取已经消耗了文件内容
+        let key_file = &mut BufReader::new(File::open(key_path)?);
+        keys = rustls_pemfile::rsa_private_keys(key_file)?;
+    }
+
+    // 如果仍然没有找到私钥，尝试读取 PKCS8 格式的私钥
+    if keys.is_empty() {
+        let key_file = &mut BufReader::new(File::open(key_path)?);
+        keys = rustls_pemfile::pkcs8_private_keys(key_file)?;
+    }
+
+    if keys.is_empty() {
+        return Err(AppError::TlsConfig("无法读取私钥，支持的格式：ECC、RSA 或 PKCS8".into()));
+    }
+
+    let signing_key = sign::any_supported_type(&PrivateKey(keys[0].clone()))
+        .map_err(|_| AppError::TlsConfig(format!("不支持的私钥类型: {key_path}")))?;
+
+    match not_after(&cert_chain[0].0) {
+        Some(expiry) => {
+            let remaining = expiry.signed_duration_since(Utc::now()).num_days();
+            if remaining < 0 {
+                warn!("证书 {} 已于 {} 过期", cert_path, expiry);
+            } else if remaining < EXPIRY_WARNING_DAYS {
+                warn!("证书 {} 将于 {} 过期，剩余 {} 天", cert_path, expiry, remaining);
+            } else {
+                info!("证书 {} 有效期至 {}，剩余 {} 天", cert_path, expiry, remaining);
+            }
+        }
+        None => warn!("无法解析证书有效期: {}", cert_path),
+    }
+
+    let dns_names = dns_names(&cert_chain[0].0);
+    info!("成功加载证书和私钥");
+    Ok((Arc::new(CertifiedKey::new(cert_chain, signing_key)), dns_names))
+}
+
+// 读取一个 DER 元素，返回标签、内容和剩余数据
+pub(crate) fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (length, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let length = rest[..count].iter().fold(0usize, |length, byte| (length << 8) | *byte as usize);
+        (length, &rest[count..])
+    };
+    if rest.len() < length {
+        return None;
+    }
+    Some((tag, &rest[..length], &rest[length..]))
+}
+
+// TBSCertificate 中的各个字段，缺少版本号 [0] 时补一个空字段，使字段位置固定：
+// 版本、序列号、签名算法、颁发者、有效期、主体、公钥，之后是可选的扩展 [3]
+fn tbs_fields(cert: &[u8]) -> Option<Vec<(u8, &[u8])>> {
+    let (_, certificate, _) = der_element(cert)?;
+    let (_, mut rest, _) = der_element(certificate)?;
+
+    let mut fields = Vec::new();
+    while !rest.is_empty() {
+        let (tag, content, next) = der_element(rest)?;
+        if fields.is_empty() && tag != 0xa0 {
+            fields.push((0xa0, &[][..]));
+        }
+        fields.push((tag, content));
+        rest = next;
+    }
+    Some(fields)
+}
+
+// 证书的过期时间：Validity 中的 notAfter
+pub fn not_after(cert: &[u8]) -> Option<DateTime<Utc>> {
+    let fields = tbs_fields(cert)?;
+    let (_, validity) = fields.get(4)?;
+
+    let (_, _, rest) = der_element(validity)?;
+    let (tag, time, _) = der_element(rest)?;
+    let time = std::str::from_utf8(time).ok()?;
+    let time = match tag {
+        // UTCTime: YYMMDDHHMMSSZ，50 以下表示 20xx 年
+        0x17 => {
+            let year: u32 = time.get(..2)?.parse().ok()?;
+            format!("{}{time}", if year < 50 { "20" } else { "19" })
+        }
+        // GeneralizedTime: YYYYMMDDHHMMSSZ
+        0x18 => time.to_string(),
+        _ => return None,
+    };
+    NaiveDateTime::parse_from_str(&time, "%Y%m%d%H%M%SZ").ok().map(|time| time.and_utc())
+}
+
+// subjectAltName 扩展（2.5.29.17）中的 DNS 名称
+pub fn dns_names(cert: &[u8]) -> Vec<String> {
+    const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+    let mut names = Vec::new();
+    let Some(fields) = tbs_fields(cert) else {
+        return names;
+    };
+    let Some((_, extensions)) = fields.iter().find(|(tag, _)| *tag == 0xa3) else {
+        return names;
+    };
+    let Some((_, mut extensions, _)) = der_element(extensions) else {
+        return names;
+    };
+
+    while let Some((_, extension, next)) = der_element(extensions) {
+        extensions = next;
+        let Some((_, oid, rest)) = der_element(extension) else {
+            continue;
+        };
+        if oid != SUBJECT_ALT_NAME {
+            continue;
+        }
+        // 跳过可选的 critical 标记，扩展值是包含 GeneralNames 的 OCTET STRING
+        let Some((mut tag, mut value, rest)) = der_element(rest) else {
+            continue;
+        };
+        if tag == 0x01 {
+            let Some(element) = der_element(rest) else {
+                continue;
+            };
+            (tag, value, _) = element;
+        }
+        if tag != 0x04 {
+            continue;
+        }
+        let Some((_, mut general_names, _)) = der_element(value) else {
+            continue;
+        };
+        // dNSName 是 [2] 隐式标签的 IA5String
+        while let Some((tag, name, next)) = der_element(general_names) {
+            general_names = next;
+            if tag == 0x82 && let Ok(name) = std::str::from_utf8(name) {
+                names.push(name.to_string());
+            }
+        }
+    }
+
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixture(name: &str) -> String {
+        format!("{}/tests/fixtures/tls/{name}", env!("CARGO_MANIFEST_DIR"))
+    }
+
+    fn certificate_der(name: &str) -> Vec<u8> {
+        let file = &mut BufReader::new(File::open(fixture(&format!("{name}.crt"))).unwrap());
+        rustls_pemfile::certs(file).unwrap().remove(0)
+    }
+
+    fn key(name: &str) -> Arc<CertifiedKey> {
+        load_certified_key(&fixture(&format!("{name}.crt")), &fixture(&format!("{name}.key"))).unwrap().0
+    }
+
+    fn named(name: &str, hosts: &[&str]) -> NamedCertificate {
+        NamedCertificate { hosts: hosts.iter().map(|host| host.to_string()).collect(), key: key(name) }
+    }
+
+    fn settings(default: &str, certificates: &[&str]) -> Settings {
+        let certificates: Vec<serde_json::Value> = certificates.iter()
+            .map(|name| serde_json::json!({"cert_path": fixture(&format!("{name}.crt")), "key_path": fixture(&format!("{name}.key"))}))
+            .collect();
+        serde_json::from_value(serde_json::json!({
+            "server": {"http_port": 80, "https_port": 443, "http_enabled": false, "https_enabled": true, "behind_proxy": false},
+            "registry": {"upstream_registry": "https://registry-1.docker.io"},
+            "tls": {"cert_path": fixture(&format!("{default}.crt")), "key_path": fixture(&format!("{default}.key")), "certificates": certificates},
+            "auth": {},
+        })).unwrap()
+    }
+
+    #[test]
+    fn not_after_reads_utc_and_generalized_time() {
+        // 2050 年之前使用 UTCTime，之后使用 GeneralizedTime
+        assert_eq!(not_after(&certificate_der("exact")).unwrap().to_rfc3339(), "2040-06-27T04:05:46+00:00");
+        assert_eq!(not_after(&certificate_der("wildcard")).unwrap().to_rfc3339(), "2051-06-10T04:05:46+00:00");
+        assert_eq!(not_after(&[0x30, 0x00]), None);
+    }
+
+    #[test]
+    fn dns_names_come_from_subject_alt_name() {
+        assert_eq!(dns_names(&certificate_der("exact")), ["x.a.com", "Y.B.com"]);
+        assert_eq!(dns_names(&certificate_der("wildcard")), ["*.a.com"]);
+        assert!(dns_names(&certificate_der("default")).is_empty());
+    }
+
+    #[test]
+    fn wildcard_matches_a_single_label() {
+        assert!(wildcard_matches("*.a.com", "x.a.com"));
+        assert!(!wildcard_matches("*.a.com", "a.com"));
+        assert!(!wildcard_matches("*.a.com", "x.y.a.com"));
+        assert!(!wildcard_matches("*.a.com", "xa.com"));
+        assert!(!wildcard_matches("x.a.com", "x.a.com"));
+    }
+
+    #[test]
+    fn select_prefers_exact_then_wildcard_then_default() {
+        let default = key("default");
+        // 通配符证书排在前面时精确匹配仍然优先
+        let wildcard = named("wildcard", &["*.a.com"]);
+        let exact = named("exact", &["x.a.com", "y.b.com"]);
+        let loaded = LoadedCertificates {
+            default: Some(default.clone()),
+            named: vec![wildcard, exact],
+            fingerprint: Vec::new(),
+        };
+        let selected = |server_name: Option<&str>| loaded.select(server_name).unwrap();
+        let is = |selected: Arc<CertifiedKey>, expected: &Arc<CertifiedKey>| Arc::ptr_eq(&selected, expected);
+
+        assert!(is(selected(Some("x.a.com")), &loaded.named[1].key));
+        assert!(is(selected(Some("Y.B.com.")), &loaded.named[1].key));
+        assert!(is(selected(Some("z.a.com")), &loaded.named[0].key));
+        assert!(is(selected(Some("a.com")), &default));
+        assert!(is(selected(Some("x.y.a.com")), &default));
+        assert!(is(selected(None), &default));
+    }
+
+    #[test]
+    fn failed_reload_keeps_previous_certificates() {
+        server_config(&settings("default", &["exact"])).unwrap();
+        let current = || CERTIFICATES.read().unwrap().as_ref().map(|loaded| loaded.select(Some("x.a.com")).unwrap()).unwrap();
+        let before = current();
+
+        // 任何一个证书加载失败时继续使用之前的全部证书
+        assert!(!reload(&settings("default", &["exact", "missing"])));
+        assert!(Arc::ptr_eq(&current(), &before));
+        assert_eq!(CERTIFICATES.read().unwrap().as_ref().unwrap().named.len(), 1);
+
+        assert!(reload(&settings("default", &["wildcard", "exact"])));
+        assert!(!Arc::ptr_eq(&current(), &before));
+        assert_eq!(CERTIFICATES.read().unwrap().as_ref().unwrap().named.len(), 2);
+    }
+}
@@ -0,0 +1,476 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use actix_web::web::Bytes;
+use futures::stream::{self, BoxStream, Stream, StreamExt};
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+
+use crate::config::{FailoverSettings, HttpClientSettings, RegistrySettings, RetrySettings};
+use crate::error::AppError;
+
+// 上游端点健康状态
+#[derive(Debug, Default)]
+struct EndpointHealth {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+lazy_static! {
+    static ref ENDPOINT_HEALTH: Mutex<HashMap<String, EndpointHealth>> = Mutex::new(HashMap::new());
+    // 按注册表键缓存的 HTTP 客户端，空字符串键为默认客户端
+    static ref CLIENTS: RwLock<HashMap<String, RegistryClients>> = RwLock::new(HashMap::new());
+}
+
+// 同一注册表的两种客户端：跟随重定向和不跟随重定向
+#[derive(Clone)]
+struct RegistryClients {
+    follow: reqwest::Client,
+    no_redirect: reqwest::Client,
+}
+
+impl RegistryClients {
+    fn build(client_settings: &HttpClientSettings) -> Result<Self, AppError> {
+        let policy = reqwest::redirect::Policy::limited(client_settings.max_redirects);
+        Ok(RegistryClients {
+            follow: build_client(client_settings, policy)?,
+            no_redirect: build_client(client_settings, reqwest::redirect::Policy::none())?,
+        })
+    }
+}
+
+// 根据配置构建 HTTP 客户端
+fn build_client(client_settings: &HttpClientSettings, redirect: reqwest::redirect::Policy) -> Result<reqwest::Client, AppError> {
+    let mut builder = reqwest::Client::builder()
+        .redirect(redirect)
+        .connect_timeout(Duration::from_secs(client_settings.connect_timeout_secs))
+        .read_timeout(Duration::from_secs(client_settings.read_timeout_secs))
+        .pool_idle_timeout(Duration::from_secs(client_settings.pool_idle_timeout_secs))
+        .pool_max_idle_per_host(client_settings.pool_max_idle_per_host);
+
+    if let Some(proxy) = &client_settings.proxy {
+        let proxy = reqwest::Proxy::all(proxy)
+            .map_err(|e| AppError::HttpClient(format!("无效的出站代理 {proxy}: {e}")))?;
+        builder = builder.proxy(proxy);
+    }
+
+    if let Some(ca_bundle) = &client_settings.ca_bundle {
+        let pem = std::fs::read(ca_bundle)
+            .map_err(|e| AppError::HttpClient(format!("无法读取 CA 证书 {ca_bundle}: {e}")))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .map_err(|e| AppError::HttpClient(format!("无法解析 CA 证书 {ca_bundle}: {e}")))?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    if client_settings.insecure_skip_verify {
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+
+    builder.build().map_err(|e| AppError::HttpClient(format!("无法创建 HTTP 客户端: {e}")))
+}
+
+// 为默认配置和每个注册表构建 HTTP 客户端，配置有误时返回错误
+pub fn init_clients(registry_settings: &RegistrySettings) -> Result<(), AppError> {
+    let mut clients = HashMap::new();
+    clients.insert(String::new(), RegistryClients::build(&registry_settings.client)?);
+
+    for (registry_key, registry_config) in &registry_settings.registries {
+        if let Some(client_settings) = &registry_config.client {
+            let client = RegistryClients::build(client_settings)
+                .map_err(|e| AppError::HttpClient(format!("注册表 {registry_key}: {e}")))?;
+            clients.insert(registry_key.clone(), client);
+        }
+    }
+
+    *CLIENTS.write().unwrap() = clients;
+    Ok(())
+}
+
+// 默认客户端在 init_clients 中总会创建，启动时先于任何请求初始化
+fn registry_clients(registry_key: &str) -> RegistryClients {
+    let clients = CLIENTS.read().unwrap();
+    clients.get(registry_key)
+        .or_else(|| clients.get(""))
+        .cloned()
+        .expect("上游 HTTP 客户端尚未初始化")
+}
+
+// 获取注册表对应的 HTTP 客户端，没有专用配置时使用默认客户端
+pub fn client_for(registry_key: &str) -> reqwest::Client {
+    registry_clients(registry_key).follow
+}
+
+// 获取不跟随重定向的 HTTP 客户端，用于将重定向直接返回给客户端
+pub fn no_redirect_client_for(registry_key: &str) -> reqwest::Client {
+    registry_clients(registry_key).no_redirect
+}
+
+// 获取注册表的端点列表：先是配置的镜像端点，最后是源注册表
+pub fn registry_endpoints(registry_settings: &RegistrySettings, registry_key: &str, target_registry: &str) -> Vec<String> {
+    let mirrors = registry_settings.registries
+        .get(registry_key)
+        .map(|config| config.mirrors.as_slice())
+        .unwrap_or_default();
+
+    // 先去掉末尾的 /，再去重，https://mirror/ 与 https://mirror 视为同一个端点
+    let mut endpoints: Vec<String> = Vec::new();
+    for endpoint in mirrors.iter().map(String::as_str).chain([target_registry]) {
+        let endpoint = endpoint.trim_end_matches('/');
+        if !endpoints.iter().any(|existing| existing == endpoint) {
+            endpoints.push(endpoint.to_string());
+        }
+    }
+    endpoints
+}
+
+// 过滤掉处于熔断期的端点，如果全部熔断则按原顺序全部尝试
+pub fn available_endpoints(endpoints: &[String], failover: &FailoverSettings) -> Vec<String> {
+    let now = Instant::now();
+    let health = ENDPOINT_HEALTH.lock().unwrap();
+
+    let available: Vec<String> = endpoints.iter()
+        .filter(|endpoint| {
+            health.get(*endpoint)
+                .and_then(|h| h.open_until)
+                .is_none_or(|until| until <= now)
+        })
+        .cloned()
+        .collect();
+
+    if available.is_empty() {
+        warn!("所有上游端点均处于熔断状态 (冷却 {} 秒)，仍尝试全部端点", failover.cooldown_secs);
+        return endpoints.to_vec();
+    }
+
+    available
+}
+
+// 是否应当切换到下一个端点
+pub fn is_failover_status(status: reqwest::StatusCode) -> bool {
+    status.is_server_error()
+}
+
+// 记录端点请求成功，重置失败计数
+pub fn record_success(endpoint: &str) {
+    let mut health = ENDPOINT_HEALTH.lock().unwrap();
+    if let Some(h) = health.get_mut(endpoint)
+        && (h.consecutive_failures > 0 || h.open_until.is_some()) {
+        info!("上游端点 {} 已恢复", endpoint);
+        *h = EndpointHealth::default();
+    }
+}
+
+// 记录端点请求失败，连续失败达到阈值后熔断该端点
+pub fn record_failure(endpoint: &str, failover: &FailoverSettings) {
+    let mut health = ENDPOINT_HEALTH.lock().unwrap();
+    let h = health.entry(endpoint.to_string()).or_default();
+    h.consecutive_failures += 1;
+
+    if h.consecutive_failures >= failover.failure_threshold {
+        let cooldown = Duration::from_secs(failover.cooldown_secs);
+        h.open_until = Some(Instant::now() + cooldown);
+        warn!("上游端点 {} 连续失败 {} 次，熔断 {} 秒", endpoint, h.consecutive_failures, failover.cooldown_secs);
+    }
+}
+
+// 计算第 attempt 次重试前的等待时间（指数退避，可选抖动）
+fn backoff_delay(attempt: u32, retry: &RetrySettings) -> Duration {
+    let exponent = attempt.saturating_sub(1).min(16);
+    let delay = retry.initial_backoff_ms
+        .saturating_mul(1 << exponent)
+        .min(retry.max_backoff_ms);
+
+    if !retry.jitter || delay == 0 {
+        return Duration::from_millis(delay);
+    }
+
+    // 等幅抖动：保留一半等待时间，另一半随机
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    let half = delay / 2;
+    Duration::from_millis(half + u64::from(nanos) % (delay - half + 1))
+}
+
+// 解析 Retry-After 头（秒数形式）
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response.headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+// 连接错误、超时等传输层错误可以重试
+fn is_retryable_error(error: &reqwest::Error) -> bool {
+    error.is_connect() || error.is_timeout() || error.is_request() || error.is_body()
+}
+
+// 发送上游请求，对 GET/HEAD 请求按重试策略进行重试
+pub async fn send_with_retry(
+    request_builder: reqwest::RequestBuilder,
+    retry: &RetrySettings,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let (client, request) = request_builder.build_split();
+    let request = request?;
+
+    // 只有幂等请求才允许重试
+    let idempotent = request.method() == reqwest::Method::GET || request.method() == reqwest::Method::HEAD;
+    let max_attempts = if idempotent { retry.max_attempts.max(1) } else { 1 };
+
+    let mut attempt = 1;
+    loop {
+        let current = match request.try_clone() {
+            Some(current) if attempt < max_attempts => current,
+            _ => return client.execute(request).await,
+        };
+
+        let delay = match client.execute(current).await {
+            Ok(resp) if retry.retryable_status_codes.contains(&resp.status().as_u16()) => {
+                let delay = backoff_delay(attempt, retry)
+                    .max(retry_after(&resp).unwrap_or_default())
+                    .min(Duration::from_millis(retry.max_backoff_ms));
+                warn!("{} {} 返回 {}，{} 毫秒后重试 ({}/{})",
+                    request.method(), request.url(), resp.status().as_u16(), delay.as_millis(), attempt, max_attempts);
+                delay
+            },
+            Ok(resp) => return Ok(resp),
+            Err(e) if is_retryable_error(&e) => {
+                let delay = backoff_delay(attempt, retry);
+                warn!("{} {} 失败: {}，{} 毫秒后重试 ({}/{})",
+                    request.method(), request.url(), e, delay.as_millis(), attempt, max_attempts);
+                delay
+            },
+            Err(e) => return Err(e),
+        };
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+// 可续传响应流的内部状态
+struct ResumableState {
+    inner: BoxStream<'static, Result<Bytes, reqwest::Error>>,
+    request: Option<reqwest::RequestBuilder>,
+    retry: RetrySettings,
+    start: u64,          // 本次响应内容在 blob 中的起始位置
+    end: Option<u64>,    // 客户端请求的结束位置（包含）
+    delivered: u64,
+    resumes: u32,
+    finished: bool,
+}
+
+// 解析 Content-Range 头，例如 "bytes 100-199/1000"
+fn parse_content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let range = value.strip_prefix("bytes ")?.split('/').next()?;
+    let (start, end) = range.split_once('-')?;
+    Some((start.trim().parse().ok()?, Some(end.trim().parse().ok()?)))
+}
+
+// 将上游响应转换为字节流，传输中断时使用 Range 请求从已发送位置续传
+// request 为 None 时不进行续传
+pub fn resumable_stream(
+    response: reqwest::Response,
+    request: Option<reqwest::RequestBuilder>,
+    retry: RetrySettings,
+) -> impl Stream<Item = Result<Bytes, reqwest::Error>> {
+    // 上游明确声明不支持 Range 时不进行续传
+    let supports_ranges = response.headers()
+        .get(reqwest::header::ACCEPT_RANGES)
+        .and_then(|value| value.to_str().ok())
+        .is_none_or(|value| !value.eq_ignore_ascii_case("none"));
+
+    // 206 响应从 Content-Range 的起始位置续传，无法解析时（如多段响应）不进行续传
+    let range = if response.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        response.headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_content_range)
+    } else {
+        Some((0, None))
+    };
+    let (start, end) = range.unwrap_or((0, None));
+
+    let state = ResumableState {
+        inner: response.bytes_stream().boxed(),
+        request: request.filter(|_| supports_ranges && range.is_some() && retry.resume_interrupted_blobs),
+        retry,
+        start,
+        end,
+        delivered: 0,
+        resumes: 0,
+        finished: false,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        if state.finished {
+            return None;
+        }
+
+        loop {
+            match state.inner.next().await {
+                Some(Ok(chunk)) => {
+                    state.delivered += chunk.len() as u64;
+                    return Some((Ok(chunk), state));
+                },
+                Some(Err(e)) => {
+                    if let Some(inner) = resume(&mut state, &e).await {
+                        state.inner = inner;
+                        continue;
+                    }
+                    state.finished = true;
+                    return Some((Err(e), state));
+                },
+                None => return None,
+            }
+        }
+    })
+}
+
+// 尝试从已发送的位置重新请求剩余内容
+async fn resume(
+    state: &mut ResumableState,
+    error: &reqwest::Error,
+) -> Option<BoxStream<'static, Result<Bytes, reqwest::Error>>> {
+    let request = state.request.as_ref()?;
+
+    while state.resumes + 1 < state.retry.max_attempts {
+        state.resumes += 1;
+        let delay = backoff_delay(state.resumes, &state.retry);
+        let offset = state.start + state.delivered;
+        warn!("上游流传输中断: {}，{} 毫秒后从第 {} 字节续传 ({}/{})",
+            error, delay.as_millis(), offset, state.resumes, state.retry.max_attempts - 1);
+        tokio::time::sleep(delay).await;
+
+        let end = state.end.map(|end| end.to_string()).unwrap_or_default();
+        let range_request = request.try_clone()?
+            .header(reqwest::header::RANGE, format!("bytes={offset}-{end}"));
+
+        match range_request.send().await {
+            Ok(resp) if resp.status() == reqwest::StatusCode::PARTIAL_CONTENT => {
+                let expected = format!("bytes {offset}-");
+                let matches = resp.headers()
+                    .get(reqwest::header::CONTENT_RANGE)
+                    .and_then(|value| value.to_str().ok())
+                    .is_some_and(|value| value.starts_with(&expected));
+
+                if matches {
+                    debug!("续传成功，从第 {} 字节继续传输", offset);
+                    return Some(resp.bytes_stream().boxed());
+                }
+                warn!("上游返回的 Content-Range 与续传位置不一致，放弃续传");
+                return None;
+            },
+            Ok(resp) => {
+                warn!("上游不支持 Range 续传 (状态码 {})，放弃续传", resp.status().as_u16());
+                return None;
+            },
+            Err(e) => warn!("续传请求失败: {}", e),
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn registry_settings(mirrors: &[&str]) -> RegistrySettings {
+        serde_json::from_value(json!({
+            "upstream_registry": "https://registry-1.docker.io",
+            "registries": {
+                "ghcr.io": {"url": "https://ghcr.io", "api_version": "v2", "mirrors": mirrors},
+            },
+        })).unwrap()
+    }
+
+    #[test]
+    fn mirrors_come_before_the_origin() {
+        let settings = registry_settings(&["https://mirror-a.example.com", "https://mirror-b.example.com/"]);
+        assert_eq!(registry_endpoints(&settings, "ghcr.io", "https://ghcr.io"), vec![
+            "https://mirror-a.example.com",
+            "https://mirror-b.example.com",
+            "https://ghcr.io",
+        ]);
+        assert_eq!(registry_endpoints(&settings, "docker.io", "https://registry-1.docker.io/"), vec!["https://registry-1.docker.io"]);
+    }
+
+    #[test]
+    fn endpoints_are_deduplicated_after_trimming() {
+        let settings = registry_settings(&["https://ghcr.io/", "https://mirror.example.com", "https://mirror.example.com/"]);
+        assert_eq!(registry_endpoints(&settings, "ghcr.io", "https://ghcr.io"), vec![
+            "https://ghcr.io",
+            "https://mirror.example.com",
+        ]);
+    }
+
+    #[test]
+    fn circuit_opens_after_threshold_and_closes_on_success() {
+        let failover = FailoverSettings { failure_threshold: 2, cooldown_secs: 60 };
+        let endpoints = vec!["https://breaker-a.test".to_string(), "https://breaker-b.test".to_string()];
+
+        record_failure("https://breaker-a.test", &failover);
+        assert_eq!(available_endpoints(&endpoints, &failover), endpoints);
+        record_failure("https://breaker-a.test", &failover);
+        assert_eq!(available_endpoints(&endpoints, &failover), vec!["https://breaker-b.test"]);
+
+        // 全部熔断时仍按原顺序尝试全部端点
+        record_failure("https://breaker-b.test", &failover);
+        record_failure("https://breaker-b.test", &failover);
+        assert_eq!(available_endpoints(&endpoints, &failover), endpoints);
+
+        record_success("https://breaker-a.test");
+        assert_eq!(available_endpoints(&endpoints, &failover), vec!["https://breaker-a.test"]);
+    }
+
+    #[test]
+    fn only_server_errors_fail_over() {
+        assert!(is_failover_status(reqwest::StatusCode::BAD_GATEWAY));
+        assert!(!is_failover_status(reqwest::StatusCode::NOT_FOUND));
+        assert!(!is_failover_status(reqwest::StatusCode::UNAUTHORIZED));
+    }
+
+    fn retry(jitter: bool) -> RetrySettings {
+        RetrySettings { initial_backoff_ms: 100, max_backoff_ms: 1000, jitter, ..Default::default() }
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_the_limit() {
+        let retry = retry(false);
+        let delays: Vec<u64> = (1..=6).map(|attempt| backoff_delay(attempt, &retry).as_millis() as u64).collect();
+        assert_eq!(delays, vec![100, 200, 400, 800, 1000, 1000]);
+        // 很大的重试次数不会溢出
+        assert_eq!(backoff_delay(u32::MAX, &retry), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn jitter_keeps_at_least_half_the_delay() {
+        let retry = retry(true);
+        for attempt in 1..=5 {
+            let full = backoff_delay(attempt, &RetrySettings { jitter: false, ..retry.clone() });
+            let delay = backoff_delay(attempt, &retry);
+            assert!(delay >= full / 2 && delay <= full, "{attempt}: {delay:?}");
+        }
+        let zero = RetrySettings { initial_backoff_ms: 0, ..retry };
+        assert_eq!(backoff_delay(3, &zero), Duration::ZERO);
+    }
+
+    #[test]
+    fn content_range_start_and_end() {
+        assert_eq!(parse_content_range("bytes 100-199/1000"), Some((100, Some(199))));
+        assert_eq!(parse_content_range("bytes 0-0/*"), Some((0, Some(0))));
+        assert_eq!(parse_content_range("bytes 5 - 9/10"), Some((5, Some(9))));
+    }
+
+    #[test]
+    fn unsatisfied_or_malformed_content_range() {
+        // 416 响应的 bytes */N 没有可续传的起始位置
+        assert_eq!(parse_content_range("bytes */1000"), None);
+        assert_eq!(parse_content_range("items 0-9/10"), None);
+        assert_eq!(parse_content_range("bytes 0-/10"), None);
+        assert_eq!(parse_content_range(""), None);
+    }
+}
@@ -0,0 +1,445 @@
+use std::fs::File;
+use serde_json::Value;
+
+use crate::config::{schema_sample, AcmeChallenge, HttpClientSettings, Settings};
+use crate::forwarded;
+use crate::listeners;
+use crate::routing::DOCKER_HUB_KEY;
+
+// 加载并严格校验配置，返回所有问题，每个问题以配置项路径开头
+pub fn load(config_files: &[String]) -> Result<Settings, Vec<String>> {
+    let source = Settings::config_source(config_files).map_err(|e| vec![e.to_string()])?;
+    let raw: Value = source.clone().try_deserialize().map_err(|e| vec![e.to_string()])?;
+
+    // 反序列化错误不一定包含配置项路径（如无效的枚举值），且只报告第一个错误
+    // 此时逐项定位无效的配置项，替换为有效值后继续检查其余的问题
+    let (settings, invalid) = match source.try_deserialize::<Settings>() {
+        Ok(settings) => (settings, Vec::new()),
+        Err(e) => invalid_values(&raw).ok_or_else(|| vec![e.to_string()])?,
+    };
+    let mut problems = invalid.clone();
+
+    // 反序列化时会忽略未知的配置项，与解析结果重新序列化后的内容比较找出这些配置项
+    match serde_json::to_value(&settings) {
+        Ok(known) => unknown_keys(&raw, &known, "", &mut problems),
+        Err(e) => problems.push(format!("无法检查未知配置项: {e}")),
+    }
+    let mut checked = Vec::new();
+    check_settings(&settings, &mut checked);
+    // 替换后的值引起的问题不再重复报告
+    checked.retain(|problem| !invalid.iter().any(|bad| within(problem, problem_path(bad))));
+    problems.extend(checked);
+
+    if problems.is_empty() {
+        Ok(settings)
+    } else {
+        Err(problems)
+    }
+}
+
+fn invalid_values(raw: &Value) -> Option<(Settings, Vec<String>)> {
+    let mut checker = ValueChecker { sample: schema_sample(), problems: Vec::new() };
+    let repaired = checker.check(raw, &mut Vec::new(), "");
+    if checker.problems.is_empty() {
+        return None;
+    }
+    deserialize(&repaired).ok().map(|settings| (settings, checker.problems))
+}
+
+// 与加载配置时一样宽松地反序列化，如字符串形式的数字
+fn deserialize(value: &Value) -> Result<Settings, ::config::ConfigError> {
+    config_value(value).try_deserialize()
+}
+
+fn config_value(value: &Value) -> ::config::Value {
+    let kind = match value {
+        Value::Null => ::config::ValueKind::Nil,
+        Value::Bool(value) => ::config::ValueKind::Boolean(*value),
+        Value::Number(number) => match number.as_i64() {
+            Some(value) => ::config::ValueKind::I64(value),
+            None => ::config::ValueKind::Float(number.as_f64().unwrap_or_default()),
+        },
+        Value::String(value) => ::config::ValueKind::String(value.clone()),
+        Value::Array(items) => ::config::ValueKind::Array(items.iter().map(config_value).collect()),
+        Value::Object(map) => ::config::ValueKind::Table(
+            map.iter().map(|(key, value)| (key.clone(), config_value(value))).collect(),
+        ),
+    };
+    ::config::Value::new(None, kind)
+}
+
+// 去掉错误中的 key 和来源，由调用方在前面加上实际的配置项路径
+fn describe(error: ::config::ConfigError) -> String {
+    match error {
+        ::config::ConfigError::Type { unexpected, expected, .. } => format!("无效的类型 {unexpected}，应为 {expected}"),
+        other => other.to_string(),
+    }
+}
+
+fn problem_path(problem: &str) -> &str {
+    problem.split_once(": ").map_or(problem, |(path, _)| path)
+}
+
+// problem 是否属于 path 或其下的配置项
+fn within(problem: &str, path: &str) -> bool {
+    problem.strip_prefix(path).is_some_and(|rest| rest.starts_with([':', '.', '[']))
+}
+
+// 把原始配置中的每个值单独放入有效的配置样例中反序列化，失败时即可确定出错的配置项
+struct ValueChecker {
+    sample: Value,
+    problems: Vec<String>,
+}
+
+impl ValueChecker {
+    // 返回把无效值替换为样例值后的配置；pointer 是样例中对应位置的 JSON Pointer 片段
+    fn check(&mut self, raw: &Value, pointer: &mut Vec<String>, path: &str) -> Value {
+        let schema = self.sample.pointer(&json_pointer(pointer)).cloned().unwrap_or(Value::Null);
+        match (raw, &schema) {
+            (Value::Object(raw_map), Value::Object(schema_map)) => {
+                // 映射表的样例只有一个 * 键，所有条目按它检查
+                let is_map = schema_map.contains_key("*");
+                let mut repaired = serde_json::Map::new();
+                for (key, value) in raw_map {
+                    let schema_key = if is_map { "*" } else { key.as_str() };
+                    if !schema_map.contains_key(schema_key) {
+                        continue; // 未知的配置项由 unknown_keys 报告
+                    }
+                    pointer.push(schema_key.to_string());
+                    let value = self.check(value, pointer, &key_path(path, key));
+                    pointer.pop();
+                    repaired.insert(key.clone(), value);
+                }
+                if !is_map {
+                    for (key, value) in schema_map {
+                        if !raw_map.contains_key(key) && self.is_required(pointer, key) {
+                            self.problems.push(format!("{}: 缺少必需的配置项", key_path(path, key)));
+                            repaired.insert(key.clone(), value.clone());
+                        }
+                    }
+                }
+                Value::Object(repaired)
+            }
+            (Value::Array(items), Value::Array(schema_items)) => {
+                let mut repaired = Vec::new();
+                for (index, item) in items.iter().enumerate() {
+                    let item_path = format!("{path}[{index}]");
+                    if schema_items.first().is_some_and(Value::is_object) {
+                        // 结构体列表按样例元素逐个字段检查
+                        pointer.push("0".to_string());
+                        repaired.push(self.check(item, pointer, &item_path));
+                        pointer.pop();
+                    } else if let Err(e) = self.probe(pointer, Value::Array(vec![item.clone()])) {
+                        self.problems.push(format!("{item_path}: {e}"));
+                    } else {
+                        repaired.push(item.clone());
+                    }
+                }
+                Value::Array(repaired)
+            }
+            _ => match self.probe(pointer, raw.clone()) {
+                Ok(()) => raw.clone(),
+                Err(e) => {
+                    self.problems.push(format!("{path}: {e}"));
+                    schema
+                }
+            },
+        }
+    }
+
+    // 在样例中替换 pointer 处的值后反序列化
+    fn probe(&self, pointer: &[String], value: Value) -> Result<(), String> {
+        let mut candidate = self.sample.clone();
+        if let Some(slot) = candidate.pointer_mut(&json_pointer(pointer)) {
+            *slot = value;
+        }
+        deserialize(&candidate).map(|_| ()).map_err(describe)
+    }
+
+    // 从样例中删除该配置项后无法反序列化，说明它是必需的
+    fn is_required(&self, pointer: &[String], key: &str) -> bool {
+        let mut candidate = self.sample.clone();
+        if let Some(Value::Object(parent)) = candidate.pointer_mut(&json_pointer(pointer)) {
+            parent.remove(key);
+        }
+        deserialize(&candidate).is_err()
+    }
+}
+
+fn json_pointer(segments: &[String]) -> String {
+    segments.iter().map(|segment| format!("/{}", segment.replace('~', "~0").replace('/', "~1"))).collect()
+}
+
+// 拼接配置项路径，包含 . 的键加引号，如 registry.registries."ghcr.io"
+fn key_path(parent: &str, key: &str) -> String {
+    let key = if key.contains('.') { format!("\"{key}\"") } else { key.to_string() };
+    if parent.is_empty() { key } else { format!("{parent}.{key}") }
+}
+
+fn unknown_keys(raw: &Value, known: &Value, path: &str, problems: &mut Vec<String>) {
+    match (raw, known) {
+        (Value::Object(raw), Value::Object(known)) => {
+            for (key, value) in raw {
+                let child = key_path(path, key);
+                match known.get(key) {
+                    Some(known_value) => unknown_keys(value, known_value, &child, problems),
+                    None => problems.push(format!("{child}: 未知的配置项")),
+                }
+            }
+        }
+        (Value::Array(raw), Value::Array(known)) => {
+            for (index, (value, known_value)) in raw.iter().zip(known).enumerate() {
+                unknown_keys(value, known_value, &format!("{path}[{index}]"), problems);
+            }
+        }
+        _ => {}
+    }
+}
+
+// 检查 URL 是否是有效的 http/https 地址
+fn check_url(path: &str, url: &str, problems: &mut Vec<String>) {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if !matches!(parsed.scheme(), "http" | "https") => {
+            problems.push(format!("{path}: 不支持的协议 {} ({url})", parsed.scheme()));
+        }
+        Ok(parsed) if parsed.host_str().is_none() => {
+            problems.push(format!("{path}: 缺少主机名 ({url})"));
+        }
+        Ok(_) => {}
+        Err(e) => problems.push(format!("{path}: 无效的 URL {url}: {e}")),
+    }
+}
+
+fn check_readable(path: &str, file: &str, problems: &mut Vec<String>) {
+    if file.is_empty() {
+        problems.push(format!("{path}: 未配置文件路径"));
+    } else if let Err(e) = File::open(file) {
+        problems.push(format!("{path}: 无法读取 {file}: {e}"));
+    }
+}
+
+fn check_client(path: &str, client: &HttpClientSettings, problems: &mut Vec<String>) {
+    if let Some(proxy) = &client.proxy
+        && let Err(e) = reqwest::Proxy::all(proxy) {
+        problems.push(format!("{}: 无效的出站代理 {proxy}: {e}", key_path(path, "proxy")));
+    }
+    if let Some(ca_bundle) = &client.ca_bundle {
+        check_readable(&key_path(path, "ca_bundle"), ca_bundle, problems);
+    }
+}
+
+fn check_settings(settings: &Settings, problems: &mut Vec<String>) {
+    let server = &settings.server;
+    if !server.http_enabled && !server.https_enabled && server.unix_sockets.is_empty() {
+        problems.push("server: http_enabled 和 https_enabled 都为 false 时必须配置 unix_sockets".to_string());
+    }
+    if let Some(public_url) = &server.public_url {
+        check_url("server.public_url", public_url, problems);
+        if let Ok(parsed) = reqwest::Url::parse(public_url)
+            && (parsed.query().is_some() || parsed.fragment().is_some()) {
+            problems.push(format!("server.public_url: 不能包含查询参数或片段 ({public_url})"));
+        }
+    }
+    for (index, cidr) in server.trusted_proxies.iter().enumerate() {
+        if let Err(e) = forwarded::parse_cidr(cidr) {
+            problems.push(format!("server.trusted_proxies[{index}]: {e}"));
+        }
+    }
+    for (index, rule) in settings.policy.rules.iter().enumerate() {
+        for (client_index, cidr) in rule.clients.iter().enumerate() {
+            if let Err(e) = forwarded::parse_cidr(cidr) {
+                problems.push(format!("policy.rules[{index}].clients[{client_index}]: {e}"));
+            }
+        }
+    }
+    for (index, address) in server.bind_addresses.iter().enumerate() {
+        if let Err(e) = listeners::socket_addr(address, 0) {
+            problems.push(format!("server.bind_addresses[{index}]: {e}"));
+        }
+    }
+
+    // 配置了 certificates 或 ACME 时默认证书可以省略，未匹配 SNI 的连接使用第一个证书
+    let tls = &settings.tls;
+    let default_optional = !tls.certificates.is_empty() || settings.acme.enabled;
+    if server.https_enabled && (!default_optional || !tls.cert_path.is_empty() || !tls.key_path.is_empty()) {
+        check_readable("tls.cert_path", &tls.cert_path, problems);
+        check_readable("tls.key_path", &tls.key_path, problems);
+    }
+    for (index, certificate) in tls.certificates.iter().enumerate() {
+        if server.https_enabled {
+            check_readable(&format!("tls.certificates[{index}].cert_path"), &certificate.cert_path, problems);
+            check_readable(&format!("tls.certificates[{index}].key_path"), &certificate.key_path, problems);
+        }
+        for host in &certificate.hosts {
+            let name = host.strip_prefix("*.").unwrap_or(host);
+            if name.is_empty() || name.contains('*') || name.contains(':') {
+                problems.push(format!("tls.certificates[{index}].hosts: 无效的主机名 {host}"));
+            }
+        }
+    }
+
+    let acme = &settings.acme;
+    if acme.enabled {
+        check_url("acme.directory_url", &acme.directory_url, problems);
+        if acme.domains.is_empty() {
+            problems.push("acme.domains: 启用 ACME 时至少需要一个域名".to_string());
+        }
+        for (index, domain) in acme.domains.iter().enumerate() {
+            if domain.is_empty() || domain.contains(['*', ':', '/']) {
+                problems.push(format!("acme.domains[{index}]: 无效的域名 {domain}（HTTP-01 和 TLS-ALPN-01 不支持通配符）"));
+            }
+        }
+        if !server.https_enabled {
+            problems.push("acme: 启用 ACME 时必须启用 https_enabled".to_string());
+        }
+        if acme.challenge == AcmeChallenge::Http01 && !server.http_enabled {
+            problems.push("acme.challenge: http-01 验证需要启用 http_enabled".to_string());
+        }
+        if let Some(ca_bundle) = &acme.ca_bundle {
+            check_readable("acme.ca_bundle", ca_bundle, problems);
+        }
+    }
+
+    let registry = &settings.registry;
+    check_url("registry.upstream_registry", &registry.upstream_registry, problems);
+    check_client("registry.client", &registry.client, problems);
+
+    for code in &registry.retry.retryable_status_codes {
+        if !(100..=599).contains(code) {
+            problems.push(format!("registry.retry.retryable_status_codes: 无效的状态码 {code}"));
+        }
+    }
+
+    let mut keys: Vec<&String> = registry.registries.keys().collect();
+    keys.sort();
+    for key in keys {
+        let config = &registry.registries[key];
+        let path = key_path("registry.registries", key);
+
+        // 通配符注册表的 url 可以使用 {host} 占位符
+        check_url(&key_path(&path, "url"), &config.url.replace("{host}", "example.com"), problems);
+        if let Some(auth_url) = &config.auth_url {
+            check_url(&key_path(&path, "auth_url"), auth_url, problems);
+        }
+        for (index, mirror) in config.mirrors.iter().enumerate() {
+            check_url(&format!("{}[{index}]", key_path(&path, "mirrors")), mirror, problems);
+        }
+        if let Some(client) = &config.client {
+            check_client(&key_path(&path, "client"), client, problems);
+        }
+    }
+
+    // 不带注册表前缀的镜像使用 upstream_registry，带 docker.io 前缀的镜像使用 docker.io 的配置
+    if let Some(docker_hub) = registry.registries.get(DOCKER_HUB_KEY)
+        && docker_hub.url.trim_end_matches('/') != registry.upstream_registry.trim_end_matches('/') {
+        problems.push(format!(
+            "registry.upstream_registry: {} 与 {} ({}) 不一致",
+            registry.upstream_registry,
+            key_path(&key_path("registry.registries", DOCKER_HUB_KEY), "url"),
+            docker_hub.url,
+        ));
+    }
+
+    // 凭据和带宽限制按注册表键查找，必须引用已配置的注册表
+    let known_registry = |key: &str| key == DOCKER_HUB_KEY || registry.registries.contains_key(key);
+
+    let mut usernames: Vec<&String> = settings.auth.users.keys().collect();
+    usernames.sort();
+    for username in usernames {
+        let path = key_path(&key_path("auth.users", username), "registry_credentials");
+        let mut registries: Vec<&String> = settings.auth.users[username].registry_credentials.keys().collect();
+        registries.sort();
+        for registry_key in registries {
+            if !known_registry(registry_key) {
+                problems.push(format!("{}: 引用了未配置的注册表", key_path(&path, registry_key)));
+            }
+        }
+    }
+
+    let mut limited: Vec<&String> = settings.bandwidth.registries.keys().collect();
+    limited.sort();
+    for registry_key in limited {
+        if !known_registry(registry_key) {
+            problems.push(format!("{}: 引用了未配置的注册表", key_path("bandwidth.registries", registry_key)));
+        }
+    }
+
+    for (index, platform) in settings.manifest.platforms.iter().enumerate() {
+        let parts: Vec<&str> = platform.split('/').collect();
+        if !(2..=3).contains(&parts.len()) || parts.iter().any(|part| part.is_empty()) {
+            problems.push(format!("manifest.platforms[{index}]: 平台格式应为 os/arch 或 os/arch/variant ({platform})"));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(raw: Value) -> (Value, Vec<String>) {
+        let mut checker = ValueChecker { sample: schema_sample(), problems: Vec::new() };
+        let repaired = checker.check(&raw, &mut Vec::new(), "");
+        (repaired, checker.problems)
+    }
+
+    fn minimal() -> Value {
+        serde_json::json!({
+            "server": {"http_port": 80, "https_port": 443, "http_enabled": true, "https_enabled": false, "behind_proxy": false},
+            "registry": {"upstream_registry": "https://registry-1.docker.io"},
+            "tls": {},
+            "auth": {},
+        })
+    }
+
+    #[test]
+    fn valid_values_are_kept() {
+        let mut raw = minimal();
+        raw["server"]["http_port"] = "8080".into();
+        raw["registry"]["registries"] = serde_json::json!({"ghcr.io": {"url": "https://ghcr.io", "api_version": "v2", "auth_url": null}});
+        let (repaired, problems) = check(raw.clone());
+        assert!(problems.is_empty(), "{problems:?}");
+        assert_eq!(repaired, raw);
+        assert_eq!(deserialize(&repaired).unwrap().server.http_port, 8080);
+    }
+
+    #[test]
+    fn invalid_values_are_reported_by_path() {
+        let mut raw = minimal();
+        raw["server"]["http_port"] = "abc".into();
+        raw["registry"]["registries"] = serde_json::json!({"ghcr.io": {"url": "https://ghcr.io", "api_version": "v9", "auth_url": null}});
+        raw["registry"]["retry"] = serde_json::json!({"retryable_status_codes": [503, "x"]});
+        raw["policy"] = serde_json::json!({"rules": [{"action": "bogus"}, {"repositories": ["b/*"]}]});
+
+        let (repaired, problems) = check(raw);
+        let paths: Vec<&str> = problems.iter().map(|problem| problem_path(problem)).collect();
+        assert_eq!(paths, [
+            "policy.rules[0].action",
+            "policy.rules[1].action",
+            "registry.registries.\"ghcr.io\".api_version",
+            "registry.retry.retryable_status_codes[1]",
+            "server.http_port",
+        ]);
+        assert!(problems[1].ends_with("缺少必需的配置项"));
+
+        // 无效值替换为样例值后可以继续检查其余的配置
+        let settings = deserialize(&repaired).unwrap();
+        assert_eq!(settings.registry.retry.retryable_status_codes, [503]);
+        assert_eq!(settings.policy.rules[1].repositories, ["b/*"]);
+    }
+
+    #[test]
+    fn missing_sections_are_reported() {
+        let mut raw = minimal();
+        raw.as_object_mut().unwrap().remove("tls");
+        raw["server"].as_object_mut().unwrap().remove("https_port");
+        let (_, problems) = check(raw);
+        assert_eq!(problems, ["server.https_port: 缺少必需的配置项", "tls: 缺少必需的配置项"]);
+    }
+
+    #[test]
+    fn problems_of_replaced_values_are_not_repeated() {
+        assert!(within("registry.upstream_registry: 无效的 URL", "registry.upstream_registry"));
+        assert!(within("policy.rules[0].clients[1]: 无效的地址", "policy.rules[0]"));
+        assert!(!within("registry.upstream_registry_x: 无效", "registry.upstream_registry"));
+    }
+}